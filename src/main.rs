@@ -3,31 +3,127 @@
 //! alerts for when any of the rules used are absent.
 use std::{
     cmp::max,
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     fs,
+    io::Read,
     path::{self, Path},
+    time::{Duration, Instant},
     vec,
 };
 
-use anyhow::{ensure, Result};
+use anyhow::{anyhow, ensure, Context, Result};
 use itertools::Itertools;
 use path::PathBuf;
+use rayon::prelude::*;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_yaml::Value;
 
 const USAGE: &str = "
-prometheus-absent-data-alert-rule-generator [OPTIONS] <PATH>
+prometheus-absent-data-alert-rule-generator [OPTIONS] [PATH...]
 
 ARGS:
-    PATH            Path to the directory containing the Prometheus rules files.
+    PATH            Path to one or more directories containing the Prometheus rules files, and/or rules files or globs (quoted so the shell doesn't expand them) to read. Optional if --files-from is given. A single - reads one rules file from stdin and writes the generated rules to stdout.
 
 OPTIONS:
     -h, --help      Print this help information.
     --dry-run       Dry run. Don't output the generated rules files.
-    --output-file   File to write the absent rules to. Defaults to absent.rules.yml in <PATH>.
-    --ignore-file   Path to the file with a list of metrics to ignore. Defaults to ignore_metrics.txt in cargo path.
+    --output-file   File to write the absent rules to, or - for stdout. Defaults to absent.rules.yml in the first directory <PATH>. Required if no directory <PATH> is given.
+    --files-from    Path to a manifest file listing rules files or globs to scan, one per line. Relative paths are relative to the manifest. Lines starting with # are comments.
+    --ignore-file   Path to the file with a list of metrics to ignore, - for stdin, or an http(s) URL to fetch it from. Defaults to ignore_metrics.txt in cargo path.
     --playbook-link Link to the playbook to associate with all generated alerts. If not provided no playbook is associated.
+    --prometheus-url URL of a running Prometheus to check selectors against. Selectors whose metric has no data right now are skipped.
+    --annotate-count Add a referenced_by_count annotation with the number of rules that use each selector.
+    --allow-duplicate-names Don't report each alert name shared by more than one generated rule, just log how many there are. Names shared by rules for different selectors don't get a hash added to tell them apart either.
+    --high-cardinality-file Path to a file listing metric names, one per line, that should only get a single absent rule for the bare metric rather than one per selector.
+    --lint-only     Only check that every matched rules file can be parsed, reporting any that can't. No rules are generated.
+    --for-from-range For range-vector selectors whose origin rules don't have a 'for', use the selector's range as the 'for', still at least the --min-for floor, 1h by default.
+    --group-limit   Limit on the number of alerts the generated rule group can fire at once. Must be a positive integer.
+    --validate-fields Warn about rules in the input files that have fields Prometheus doesn't know about, e.g. a misspelt 'for'.
+    --combine-variants Generate one rule per metric that covers all the selectors for it by or-ing their absent expressions together.
+    --disabled-annotation Annotation key that marks a rule as disabled. Rules with this annotation set to true are skipped entirely.
+    --label-order   Comma separated list of label keys to put first, in that order, in the generated rules. Other labels follow alphabetically.
+    --dump-ast      Print the parsed AST of the given expression and the selectors found in it, then exit. Useful for debugging.
+    --guard-metrics Comma separated list of metric names that are only used as guards (e.g. in 'unless' clauses) and never get absent rules.
+    --tag-currently-absent When used with --prometheus-url, keep rules for metrics with no data right now but add a `currently_absent` label to them instead of skipping them.
+    --prometheus-timeout Seconds to wait for each query to --prometheus-url. Defaults to 10.
+    --migrate       Rewrite an existing generated output file in the current format using the selectors in it, without reading any rules files.
+    --expr-label    Label key to put the absent selector's expression in. Long expressions are truncated, see README.
+    --extra-metrics Path to a file listing extra selectors or metric names, one per line, to generate absent rules for even though they're not used in any rules.
+    --candidate     Generate candidate rules that shouldn't page: they get the --candidate-severity and a candidate label.
+    --candidate-severity Severity for candidate rules. Defaults to info.
+    --dedup-report  Path to write a JSON report to, mapping each generated alert name to the selectors that were merged into it.
+    --warn-for-divergence Warn when the rules a selector comes from disagree on 'for' by more than this factor, e.g. 10 for 30s vs 6h.
+    --strict        Turn the warnings from --warn-for-divergence into an error.
+    --metadata-file Path to a YAML file mapping metric names to their HELP text, which is added to the description of the metric's absent rules.
+    --compact       Write the output in a more compact form, with scalar-only mappings like labels and annotations on one line.
+    --doctor        Check the setup for common problems, like a rules directory that doesn't exist or matches no files, print what was found, then exit.
+    --ignore-range  Treat selectors that only differ by their range, e.g. foo[1m] and foo[5m], as the same selector and generate one rule for them.
+    --range-collapse-strategy Which range to use in the absent_over_time of selectors merged by --ignore-range: longest (the default) or shortest.
+    --rename-metrics Path to a YAML file mapping old metric names to new ones. References to the old names are treated as references to the new ones.
+    --no-trailing-newline Don't end the output file with a newline. By default it ends with exactly one.
+    --group-by-label Label key to split the generated rules into groups by. Rules get a group per value of the label in their selectors, named absent_<key>_<value>. Rules without it stay in the default group.
+    --severity-for-floors Use a built-in minimum 'for' per generated severity instead of always 1h, e.g. 15m for pages. See README.
+    --for-by-severity Comma separated list of severity=duration pairs overriding the --severity-for-floors minimums, e.g. page=5m,info=4h. Implies --severity-for-floors.
+    --dedup-scope   Where identical selectors are merged into one rule: global (the default) merges them across all rules files, group only within the same rule group, which can mean several rules for the same selector.
+    --errors-file   Path to write a JSON array of every failure to parse a rules file or get the selectors out of a rule to, for tooling.
+    --rich-description List the rules (and the files they're in) that use each selector in the description of its absent rule.
+    --emit-tests    Path to write a skeleton promtool test file to, with a test per generated alert checking it fires when there's no data.
+    --ignore-file-timeout Seconds to wait when fetching an --ignore-file URL. Defaults to 10.
+    --ignore-file-cache Path to keep a copy of the last --ignore-file fetched from a URL in. It's used if fetching fails.
+    --expr-wrapper  Template to wrap each generated absent expression in, with {absent} where the expression goes, e.g. count({absent}) > 0.
+    --skip-vector-fallbacks Skip the selectors in rules whose whole expression falls back to vector(...) with or, e.g. foo or vector(0), as they're meant to always have data.
+    --profile       Log how long each stage of processing took, at the info level, at the end.
+    --records-output-file File to write the absent rules for recordings to, instead of --output-file. Other rules still go to --output-file unless --exprs-output-file is given.
+    --exprs-output-file File to write the absent rules for selectors used in expressions, rather than recordings, to instead of --output-file.
+    --expand-by     Label to split each absent rule by. The label's values for each selector are looked up in --prometheus-url, which is required, and a rule is generated per value. As with any --prometheus-url, rules for metrics with no data right now are skipped unless --tag-currently-absent is passed.
+    --normalize-exprs Sort the label matchers in generated expressions by label name so they don't depend on how the rules happened to order them.
+    --heuristic-for Guess whether each metric is a counter or a gauge from its name and use --counter-for or --gauge-for as the minimum 'for' instead of 1h. See README.
+    --counter-for   Minimum 'for' for counters with --heuristic-for. Defaults to 2h. Implies --heuristic-for.
+    --gauge-for     Minimum 'for' for gauges with --heuristic-for. Defaults to 30m. Implies --heuristic-for.
+    --hash-label    Add a rule_hash label to each generated rule, a short hash of its selector, expression, and 'for', so changes to a rule's definition can be spotted between deploys.
+    --ignore-comment-prefix Lines in the --ignore-file starting with this are comments. Defaults to #.
+    --sustained-window Only consider instant selectors absent once they've had no data for this long, e.g. 10m, using absent_over_time. The 'for' is raised to at least the window too. See README.
+    --validate-only Generate the rules into a temporary file and check them with promtool check rules instead of writing any output. Needs promtool on the PATH.
+    --severity-label-key Key of the label the severity of generated rules goes in, e.g. priority or urgency. Defaults to severity.
+    --base-labels-file Path to a YAML file mapping label keys to values to add to every generated rule, e.g. environment or cluster. Labels the rules set themselves take precedence.
+    --prefer-range  When a selector is used both as an instant vector and a range vector, e.g. foo and foo[5m], only generate the absent_over_time rule for the range. By default both are generated.
+    --prefer-instant When a selector is used both as an instant vector and a range vector, only generate the absent rule for the instant vector.
+    --group-meta    A key=value field to add to the generated rule groups, e.g. partial_response_strategy=warn for Thanos. Can be given more than once.
+    --write-baseline Path to write the generated rules to as the baseline for --check, instead of writing --output-file.
+    --check         Don't write anything, just check the generated rules are the same as the output files, or the --baseline file, failing if they aren't.
+    --baseline      File to compare the generated rules to with --check. Defaults to --output-file.
+    --numeric-for-unit Unit, e.g. s, to give a 'for' in the rules that's just a number, like 300. By default they're reported as invalid.
+    --query-offset  Duration to delay the evaluation of the generated rule group by, e.g. 5m, so data that arrives late isn't reported as absent. Needs Prometheus 2.53 or newer.
+    --split-by-label Label key to split the generated rules into a file per value of, e.g. team. The value is inherited from the labels of the rules, or their groups, that use each selector. Needs --output-dir.
+    --output-dir    Directory to write a <value>.rules.yml per --split-by-label value, or <subdirectory>.rules.yml per --split-by-dir subdirectory, to. Rules without a consistent value, or from files that aren't in a subdirectory, go in unowned.rules.yml.
+    --selector-delta Don't write anything, just print the selectors that would be added to (+) and removed from (-) the existing generated output.
+    --no-sort       Order the generated rules by where their selectors are first used in the rules files, rather than alphabetically by selector. Identical selectors are still merged.
+    --embed-params  Add a comment to the top of the output summarising the main options used and how many rules were generated.
+    --require-metric Fail if any selector has no metric name and no __name__ matcher, instead of generating a rule for it.
+    --transform-script Command to pipe the generated rules through, as YAML on stdin, before they're written. It has to write the rules, still as valid YAML, to stdout.
+    --color         When to use color in logs and --selector-delta output: auto (the default) only when writing to a terminal, always, or never.
+    --merge-label-values Generate one rule per metric and, where its selectors only differ by the value of one label, e.g. env=a and env=b, match them all with a regex, e.g. env=~a|b. Other selectors for the metric are or-ed together like --combine-variants.
+    --plan-json     Path to write a JSON description of what the run would do to, for tooling: the files scanned, selectors found and ignored, the rules that would be created in and removed from each output file, and every generated rule. Works with --dry-run. Can't be used with --ignore-file -.
+    --severity      Severity of generated rules, e.g. warning or page. Defaults to low_urgency_page.
+    --no-default-labels Don't give generated rules the default low_urgency_page severity, so they only have the labels you ask for, e.g. with --severity or --base-labels-file.
+    --label         A key=value label to add to every generated rule, e.g. team=payments. Overrides --base-labels-file. Can be given more than once.
+    --min-for       Minimum 'for' of generated rules, when nothing else sets one. Defaults to 1h.
+    --stdout        Print the generated rules to stdout instead of writing them to the output file, even with --dry-run. The output file is still never read as a rules file.
+    --format        Format to write the generated rules in: yaml (the default) or json. JSON has no comments so it doesn't get the DO NOT MODIFY header.
+    --group-name    Name of the generated rule group. Defaults to absent_label_alerts.
+    --summary-template Template for the summary annotation of generated rules. {{selector}} is replaced with the selector and {{tool}} with the name of this tool.
+    --description-template Template for the description annotation of generated rules, with the same placeholders as --summary-template.
+    --inherit-label Label key to copy from the rules that use a selector, or their groups, to the rule generated for it, e.g. team. Can be given more than once.
+    --glob          Glob, relative to each rules directory, matching the rules files in it. Defaults to **/*.rules.yml.
+    --strict-ignore Fail if any line of the ignore file doesn't match a selector, instead of just warning about it, so stale entries are caught.
+    --for-overrides Path to a YAML file mapping metric name regexes to the 'for' to use for their rules, instead of working it out. The first matching regex wins.
+    --annotate-source Add a source_file annotation listing the rules files each selector is used in.
+    --instant-absent-over-time Wrap instant selectors in absent_over_time with this range, e.g. 5m, instead of absent, to ride out brief scrape gaps. Unlike --sustained-window the 'for' is left alone.
+    --diff          Don't write anything, just print a unified diff of each output file against what would be generated, failing if they differ.
+    --split-by-dir  Split the generated rules into a file per top-level subdirectory of the rules directories, named after it, with only the selectors used under it. Needs --output-dir.
+    --max-name-length Longest generated alert name, e.g. 128. Longer names are cut short and end with a hash of the full name so they stay unique. Must be at least 10.
+    --name-prefix   Prefix of generated alert names, instead of absent, e.g. NoData. It must start with a letter or underscore and only have letters, digits, and underscores.
 ";
 
 /// A little helper for making [BTreeMap]'s nicer to write. This lets you use
@@ -68,12 +164,30 @@ struct PrometheusRuleGroup {
     name: String,
     /// Rules contained within the group.
     rules: Vec<PrometheusRule>,
+    /// Limit on the number of alerts the group can produce.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limit: Option<u64>,
+    /// Labels for the group. Rule authors can also set `absent_min_for` and
+    /// `absent_severity` here to control the absent rules generated for the
+    /// group's selectors.
+    /// YAML is happy to turn label values into numbers and booleans, e.g.
+    /// `priority: 1`, so they're kept as they are, see [yaml_label_values].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    labels: BTreeMap<String, serde_yaml::Value>,
+    /// How far back in time the group's rules are evaluated, to allow for
+    /// data that arrives late. Needs Prometheus 2.53 or newer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    query_offset: Option<String>,
+    /// Any other fields in the group, e.g. `interval` or Thanos'
+    /// `partial_response_strategy`, so they survive being read and written.
+    #[serde(flatten)]
+    extra_fields: BTreeMap<String, serde_yaml::Value>,
 }
 
 /// A Prometheus rule. Every rule _most_ have the `expr` field but some of the
 /// others change depending on the rule type (e.g. alert vs record) so we
 /// they're stored in an unstructured way in `untyped_fields`.
-#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
 struct PrometheusRule {
     /// The rule expression.
     expr: String,
@@ -81,6 +195,56 @@ struct PrometheusRule {
     /// ordering, unlike [HashMap].
     #[serde(flatten)]
     untyped_fields: BTreeMap<String, serde_yaml::Value>,
+    /// For generated rules, [SelectorOrigin::Record] if any of the selectors
+    /// the rule is for came from a `record` name. It's only used to split the
+    /// output so it's never read or written.
+    #[serde(skip)]
+    origin: SelectorOrigin,
+}
+
+/// Fields that Prometheus accepts on a rule, other than `expr` which
+/// [PrometheusRule] stores separately.
+/// https://prometheus.io/docs/prometheus/latest/configuration/recording_rules/#rule
+const KNOWN_RULE_FIELDS: &[&str] = &[
+    "record",
+    "alert",
+    "for",
+    "keep_firing_for",
+    "labels",
+    "annotations",
+];
+
+impl PrometheusRule {
+    /// The name of the rule, i.e. its `alert` or `record` field.
+    fn name(&self) -> Option<&str> {
+        self.untyped_fields
+            .get("alert")
+            .or_else(|| self.untyped_fields.get("record"))
+            .and_then(|name| name.as_str())
+    }
+
+    /// Whether the rule has been marked as disabled by setting the annotation
+    /// `key` to true.
+    fn is_disabled(&self, key: &str) -> bool {
+        match self
+            .untyped_fields
+            .get("annotations")
+            .and_then(|annotations| annotations.get(key))
+        {
+            Some(Value::Bool(disabled)) => *disabled,
+            Some(Value::String(disabled)) => disabled.eq_ignore_ascii_case("true"),
+            _ => false,
+        }
+    }
+
+    /// Any fields on the rule that Prometheus doesn't know about.
+    fn unknown_fields(&self) -> Vec<&str> {
+        self.untyped_fields
+            .keys()
+            .map(|key| key.as_str())
+            .filter(|key| !KNOWN_RULE_FIELDS.contains(key))
+            .collect()
+    }
 }
 
 /// Representation of an alert rule for an absent selector.
@@ -93,6 +257,17 @@ struct PrometheusAbsentSelectorAlertRule {
     selector_expr: String,
     r#for: prometheus_parser::PromDuration,
     labels: BTreeMap<String, String>,
+    /// Annotations to add on top of the generated summary and description.
+    extra_annotations: BTreeMap<String, String>,
+    /// HELP text of the selector's metric, if we know it.
+    metric_help: Option<String>,
+    /// Descriptions of the rules that use the selector for
+    /// `--rich-description`.
+    origin_rules: Vec<String>,
+    /// `--summary-template` and `--description-template`, used instead of
+    /// the built-in annotations.
+    summary_template: Option<String>,
+    description_template: Option<String>,
 }
 
 impl Into<PrometheusRule> for PrometheusAbsentSelectorAlertRule {
@@ -102,34 +277,86 @@ impl Into<PrometheusRule> for PrometheusAbsentSelectorAlertRule {
         // Explain exactly what this is alerting for and that is was generated,
         // not written by someone with extensive Java experience.
         let tool_name = env!("CARGO_PKG_NAME");
-        let annotations: BTreeMap<String, String> = btree_map! {
-            "summary" => format!("No data for '{}'", self.selector_expr),
-            "description" => format!("No data for '{}'. This alert rule was generated by {}.", self.selector_expr, tool_name)
+        let selector_expr = &self.selector_expr;
+        let fill_template = |template: &str| {
+            template
+                .replace("{{selector}}", selector_expr)
+                .replace("{{tool}}", tool_name)
+        };
+        let described_selector = match self.metric_help {
+            Some(help) => format!("'{}' ({})", self.selector_expr, help.trim_end_matches('.')),
+            None => format!("'{}'", self.selector_expr),
+        };
+        let mut description = match &self.description_template {
+            Some(template) => fill_template(template),
+            None => format!(
+                "No data for {}. This alert rule was generated by {}.",
+                described_selector, tool_name
+            ),
+        };
+        if !self.origin_rules.is_empty() {
+            description += &format!(" It's used by {}.", self.origin_rules.join(", "));
+        }
+        let mut annotations: BTreeMap<String, String> = btree_map! {
+            "summary" => match &self.summary_template {
+                Some(template) => fill_template(template),
+                None => format!("No data for '{}'", self.selector_expr),
+            },
+            "description" => description
         };
+        annotations.extend(self.extra_annotations);
 
         let annotations_mapping: serde_yaml::Mapping = btree_to_yaml_mapping(annotations);
-        let labels_mapping = btree_to_yaml_mapping(self.labels);
+
+        let mut untyped_fields: BTreeMap<String, Value> = btree_map! {
+            "alert" => self.name,
+            // Don't alert the instant a time series is missing, give a bit of
+            // leeway.
+            "for" => self.r#for.to_string(),
+            "annotations" => annotations_mapping
+        };
+        // Some linters complain about `labels: {}` so leave it out entirely.
+        if !self.labels.is_empty() {
+            untyped_fields.insert("labels".into(), btree_to_yaml_mapping(self.labels).into());
+        }
 
         PrometheusRule {
             expr: self.expr,
-            untyped_fields: btree_map! {
-                "alert" => self.name,
-                // Don't alert the instant a time series is missing, give a bit of
-                // leeway.
-                "for" => self.r#for.to_string(),
-                "annotations" => annotations_mapping,
-                "labels" => labels_mapping
-            },
+            untyped_fields,
+            origin: SelectorOrigin::Expr,
         }
     }
 }
 
 /// Representation of a Prometheus selector that contains the [PrometheusRule]
 /// that it came from and the [prometheus_parser::Selector].
-#[derive(Clone)]
+#[derive(Clone, Default)]
 struct SelectorWithOriginRule {
     selector: prometheus_parser::Selector,
     rule: PrometheusRule,
+    /// Labels of the group the origin rule is in.
+    group_labels: BTreeMap<String, String>,
+    /// Name of the group the origin rule is in.
+    group_name: String,
+    /// File the origin rule is in.
+    source_file: PathBuf,
+    /// Which part of the origin rule the selector came from.
+    origin: SelectorOrigin,
+}
+
+/// Where in its origin rule a selector came from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SelectorOrigin {
+    /// The rule's `expr`.
+    Expr,
+    /// The rule's `record` name.
+    Record,
+}
+
+impl Default for SelectorOrigin {
+    fn default() -> Self {
+        SelectorOrigin::Expr
+    }
 }
 
 impl SelectorWithOriginRule {
@@ -139,8 +366,19 @@ impl SelectorWithOriginRule {
     /// [prometheus_parser::Selector] as it is something that we want to
     /// eventually be unique and already implements ord.
     fn sort_key(&self) -> String {
-        // Don't care about the `span` field
-        // as that will be different for everything.
+        // Don't care about the `span` field as that will be different for
+        // everything, or the order of the label matchers as
+        // `foo{a="1",b="2"}` and `foo{b="2",a="1"}` select the same thing.
+        prometheus_parser::Selector {
+            span: None,
+            ..sort_selector_labels(&self.selector)
+        }
+        .to_string()
+    }
+
+    /// The selector as it was written, with its label matchers in their
+    /// original order.
+    fn written_key(&self) -> String {
         prometheus_parser::Selector {
             span: None,
             ..self.selector.clone()
@@ -149,739 +387,7831 @@ impl SelectorWithOriginRule {
     }
 }
 
+/// Which range to keep when `--ignore-range` merges selectors with different
+/// ranges.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RangeCollapseStrategy {
+    /// The most tolerant of gaps in the data.
+    Longest,
+    Shortest,
+}
+
+impl Default for RangeCollapseStrategy {
+    fn default() -> Self {
+        RangeCollapseStrategy::Longest
+    }
+}
+
+impl std::str::FromStr for RangeCollapseStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "longest" => Ok(RangeCollapseStrategy::Longest),
+            "shortest" => Ok(RangeCollapseStrategy::Shortest),
+            _ => Err(anyhow!(
+                "Unknown range collapse strategy '{}', expected longest or shortest",
+                s
+            )),
+        }
+    }
+}
+
+/// What to do with selectors used both as an instant vector and a range
+/// vector, e.g. `foo` and `foo[5m]`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MixedVectorPreference {
+    /// Generate a rule for each.
+    Both,
+    /// Only generate the `absent_over_time` rule for the range.
+    Range,
+    /// Only generate the `absent` rule for the instant vector.
+    Instant,
+}
+
+impl Default for MixedVectorPreference {
+    fn default() -> Self {
+        MixedVectorPreference::Both
+    }
+}
+
+/// What format the generated rules are written in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OutputFormat {
+    Yaml,
+    /// Prometheus reads rules files as YAML, which JSON is a subset of.
+    Json,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Yaml
+    }
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "yaml" => Ok(OutputFormat::Yaml),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(anyhow!(
+                "Unknown output format '{}', expected yaml or json",
+                s
+            )),
+        }
+    }
+}
+
+/// When to use ANSI colors in what we output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ColorChoice {
+    /// Only when writing to a terminal.
+    Auto,
+    Always,
+    /// Plain text, for CI logs and scraping.
+    Never,
+}
+
+impl Default for ColorChoice {
+    fn default() -> Self {
+        ColorChoice::Auto
+    }
+}
+
+impl std::str::FromStr for ColorChoice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(anyhow!(
+                "Unknown color choice '{}', expected auto, always, or never",
+                s
+            )),
+        }
+    }
+}
+
+impl ColorChoice {
+    /// Whether to use color in what's printed to stdout.
+    fn stdout_enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => atty::is(atty::Stream::Stdout),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+
+    /// The equivalent for [env_logger], which logs to stderr.
+    fn write_style(self) -> env_logger::WriteStyle {
+        match self {
+            ColorChoice::Auto => env_logger::WriteStyle::Auto,
+            ColorChoice::Always => env_logger::WriteStyle::Always,
+            ColorChoice::Never => env_logger::WriteStyle::Never,
+        }
+    }
+}
+
+/// How widely identical selectors are merged into a single rule.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DedupScope {
+    /// Across every rules file.
+    Global,
+    /// Only within the same rule group, so the generated rules mirror how the
+    /// original rules are organised.
+    Group,
+}
+
+impl Default for DedupScope {
+    fn default() -> Self {
+        DedupScope::Global
+    }
+}
+
+impl std::str::FromStr for DedupScope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "global" => Ok(DedupScope::Global),
+            "group" => Ok(DedupScope::Group),
+            _ => Err(anyhow!(
+                "Unknown dedup scope '{}', expected global or group",
+                s
+            )),
+        }
+    }
+}
+
 /// Available command line options. See [parse_options] where [pico_args] is used
 /// to parse the provided command line options into this struct.
+#[derive(Default)]
 struct Opts {
-    rules_dir: PathBuf,
+    rules_dirs: Vec<PathBuf>,
+    rule_paths: Vec<String>,
+    files_from: Option<PathBuf>,
     output_file: PathBuf,
     dry_run: bool,
     ignore_file: PathBuf,
     playbook_link: Option<String>,
+    prometheus_url: Option<String>,
+    tag_currently_absent: bool,
+    prometheus_timeout: Option<u64>,
+    annotate_count: bool,
+    allow_duplicate_names: bool,
+    high_cardinality_file: Option<PathBuf>,
+    lint_only: bool,
+    for_from_range: bool,
+    group_limit: Option<u64>,
+    validate_fields: bool,
+    combine_variants: bool,
+    disabled_annotation: Option<String>,
+    label_order: Vec<String>,
+    guard_metrics: Vec<String>,
+    migrate: bool,
+    expr_label: Option<String>,
+    extra_metrics: Option<PathBuf>,
+    candidate: bool,
+    candidate_severity: Option<String>,
+    dedup_report: Option<PathBuf>,
+    warn_for_divergence: Option<f64>,
+    strict: bool,
+    metadata_file: Option<PathBuf>,
+    compact: bool,
+    doctor: bool,
+    ignore_range: bool,
+    range_collapse_strategy: RangeCollapseStrategy,
+    rename_metrics: BTreeMap<String, String>,
+    no_trailing_newline: bool,
+    group_by_label: Option<String>,
+    severity_for_floors: bool,
+    for_by_severity: BTreeMap<String, prometheus_parser::PromDuration>,
+    dedup_scope: DedupScope,
+    errors_file: Option<PathBuf>,
+    rich_description: bool,
+    emit_tests: Option<PathBuf>,
+    ignore_file_timeout: Option<u64>,
+    ignore_file_cache: Option<PathBuf>,
+    expr_wrapper: Option<String>,
+    skip_vector_fallbacks: bool,
+    profile: bool,
+    records_output_file: Option<PathBuf>,
+    exprs_output_file: Option<PathBuf>,
+    expand_by: Option<String>,
+    normalize_exprs: bool,
+    heuristic_for: bool,
+    counter_for: Option<prometheus_parser::PromDuration>,
+    gauge_for: Option<prometheus_parser::PromDuration>,
+    hash_label: bool,
+    ignore_comment_prefix: Option<String>,
+    sustained_window: Option<prometheus_parser::PromDuration>,
+    validate_only: bool,
+    severity_label_key: Option<String>,
+    base_labels: BTreeMap<String, String>,
+    mixed_vector_preference: MixedVectorPreference,
+    group_meta: BTreeMap<String, String>,
+    write_baseline: Option<PathBuf>,
+    check: bool,
+    baseline: Option<PathBuf>,
+    numeric_for_unit: Option<String>,
+    query_offset: Option<prometheus_parser::PromDuration>,
+    split_by_label: Option<String>,
+    output_dir: Option<PathBuf>,
+    selector_delta: bool,
+    no_sort: bool,
+    embed_params: bool,
+    require_metric: bool,
+    transform_script: Option<PathBuf>,
+    color: ColorChoice,
+    merge_label_values: bool,
+    plan_json: Option<PathBuf>,
+    severity: Option<String>,
+    no_default_labels: bool,
+    labels: BTreeMap<String, String>,
+    min_for: Option<prometheus_parser::PromDuration>,
+    stdout: bool,
+    format: OutputFormat,
+    group_name: Option<String>,
+    summary_template: Option<String>,
+    description_template: Option<String>,
+    inherit_labels: Vec<String>,
+    glob: Option<String>,
+    strict_ignore: bool,
+    for_overrides: Vec<(Regex, prometheus_parser::PromDuration)>,
+    annotate_source: bool,
+    instant_absent_over_time: Option<prometheus_parser::PromDuration>,
+    diff: bool,
+    split_by_dir: bool,
+    max_name_length: Option<usize>,
+    name_prefix: Option<String>,
+}
+
+/// The parts of a Prometheus label values response
+/// (https://prometheus.io/docs/prometheus/latest/querying/api/#querying-label-values)
+/// that we care about.
+#[derive(Deserialize, Debug)]
+struct PrometheusLabelValuesResponse {
+    status: String,
+    data: Option<Vec<String>>,
+    error: Option<String>,
+}
+
+/// The parts of a Prometheus instant query response
+/// (https://prometheus.io/docs/prometheus/latest/querying/api/#instant-queries)
+/// that we care about.
+#[derive(Deserialize, Debug)]
+struct PrometheusQueryResponse {
+    status: String,
+    data: Option<PrometheusQueryData>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PrometheusQueryData {
+    result: Vec<serde_json::Value>,
+}
+
+/// A `promtool test rules` file. See
+/// https://prometheus.io/docs/prometheus/latest/configuration/unit_testing_rules/.
+#[derive(Serialize, Debug)]
+struct PromtoolTestFile {
+    rule_files: Vec<PathBuf>,
+    evaluation_interval: String,
+    tests: Vec<PromtoolTest>,
+}
+
+#[derive(Serialize, Debug)]
+struct PromtoolTest {
+    interval: String,
+    input_series: Vec<Value>,
+    alert_rule_test: Vec<PromtoolAlertRuleTest>,
+}
+
+#[derive(Serialize, Debug)]
+struct PromtoolAlertRuleTest {
+    eval_time: String,
+    alertname: String,
+    exp_alerts: Vec<PromtoolExpectedAlert>,
+}
+
+#[derive(Serialize, Debug)]
+struct PromtoolExpectedAlert {
+    exp_labels: BTreeMap<String, String>,
+    exp_annotations: BTreeMap<String, String>,
 }
 
 fn main() -> Result<()> {
-    env_logger::init();
+    // The logger needs setting up before the options are parsed so anything
+    // logged while parsing them isn't lost, so pick `--color` out on its own.
+    let color: ColorChoice = pico_args::Arguments::from_env()
+        .opt_value_from_str("--color")?
+        .unwrap_or_default();
+    env_logger::Builder::from_default_env()
+        .write_style(color.write_style())
+        .init();
     let opts = parse_options()?;
-    process_rules_dir(
-        opts.rules_dir,
-        opts.output_file,
-        opts.ignore_file,
-        opts.playbook_link,
-        opts.dry_run,
-    )?;
+    process_rules_dir(&opts)?;
     Ok(())
 }
 
-/// Process the given rules directory, outputting the absent rules file to
-/// `output_file`.
+/// Process the rules directory given in `opts`, outputting the absent rules
+/// file to `opts.output_file`.
 ///
 /// This just wraps things up so we can easily call them in a unit test, [main]
 /// just passes through the command line options.
-fn process_rules_dir<P: AsRef<Path>>(
-    rules_dir: P,
-    output_file: P,
-    ignore_file: P,
-    playbook_link: Option<String>,
-    dry_run: bool,
+fn process_rules_dir(opts: &Opts) -> Result<()> {
+    // How long each stage took, for `--profile`. There are plenty of ways for
+    // a run to finish, e.g. `--check` or an error, so they're logged here
+    // rather than on every one of them.
+    let mut stage_timings: Vec<(&str, Duration)> = vec![];
+    let result = process_rules_dir_stages(opts, &mut stage_timings);
+    if opts.profile {
+        for line in format_stage_timings(&stage_timings).lines() {
+            log::info!("{}", line);
+        }
+    }
+    result
+}
+
+/// [process_rules_dir] without the `--profile` logging. Each stage that
+/// finishes adds how long it took to `stage_timings`.
+fn process_rules_dir_stages(
+    opts: &Opts,
+    stage_timings: &mut Vec<(&'static str, Duration)>,
 ) -> Result<()> {
+    let output_file = &opts.output_file;
     log::debug!(
-        "Reading rules from {}, outputting rules to {}",
-        rules_dir.as_ref().display(),
-        output_file.as_ref().display(),
+        "Reading rules from {:?}, {:?}, and files listed in {:?}, outputting rules to {}",
+        opts.rules_dirs,
+        opts.rule_paths,
+        opts.files_from,
+        output_file.display(),
     );
-    if dry_run {
+    if opts.dry_run {
         log::info!("This is a dry run, no files will be generated");
     }
-    let rules_file_matcher = format!("{}/**/*.rules.yml", rules_dir.as_ref().display());
-    let metrics_to_ignore: Vec<String> = fs::read_to_string(ignore_file)
-        .map(|contents| contents.lines().map(|l| l.to_string()).collect())
-        .unwrap_or_default();
-    log::debug!("Ignoring these metrics {:?}", metrics_to_ignore);
+    if opts.validate_only {
+        log::info!("Only validating the generated rules, no files will be generated");
+    }
+    if opts.migrate {
+        log::info!("Migrating {} to the current format", output_file.display());
+        let selectors = get_selectors_in_generated_file(output_file)?;
+        let config = generate_config(selectors, opts)?;
+        let migrated_file = if opts.stdout {
+            Path::new("-")
+        } else {
+            output_file.as_path()
+        };
+        write_generated_config_to_file(migrated_file, &config, opts)?;
+        return Ok(());
+    }
 
     // We only want to write the file out if all is well but it's useful to run
     // through the whole thing so we can pick up as many issues as possible in a
     // single run. `failure` is used as a flag to tell us if there has been a
     // failure or not but doesn't interrupt the processing of other rules.
     let mut failure = false;
-    let rule_files: Vec<PathBuf> = glob::glob(&rules_file_matcher)?
-        .filter_map(|path| match path {
-            Ok(p) => Some(p),
+    let stage_start = Instant::now();
+    let mut rule_file_matchers: Vec<String> = vec![];
+    for rules_dir in &opts.rules_dirs {
+        rule_file_matchers.push(format!("{}/{}", rules_dir.display(), rules_dir_glob(opts)));
+    }
+    // Concrete paths are just globs that match themselves.
+    rule_file_matchers.extend(opts.rule_paths.iter().cloned());
+    if let Some(files_from) = &opts.files_from {
+        match load_files_from_manifest(files_from) {
+            Ok(matchers) => rule_file_matchers.extend(matchers),
             Err(e) => {
-                log::error!("Failed to read path: {}", e);
+                log::error!("Failed to load manifest: {:#}", e);
                 failure = true;
-                None
             }
-        })
+        }
+    }
+    let mut rule_files: Vec<PathBuf> = vec![];
+    for matcher in &rule_file_matchers {
+        // `-` is stdin, which isn't something to glob for.
+        if is_stdio(Path::new(matcher)) {
+            rule_files.push(PathBuf::from(matcher));
+            continue;
+        }
+        let mut matched_any = false;
+        for path in glob::glob(matcher)? {
+            match path {
+                Ok(p) => {
+                    matched_any = true;
+                    rule_files.push(p);
+                }
+                Err(e) => {
+                    log::error!("Failed to read path: {}", e);
+                    failure = true;
+                }
+            }
+        }
+        if !matched_any {
+            log::warn!("'{}' didn't match any files", matcher);
+        }
+    }
+    // A file can be matched by more than one glob but we only want to read it
+    // once.
+    let rule_files: Vec<PathBuf> = rule_files
+        .into_iter()
         .sorted_by(|left, right| left.cmp(right))
+        .dedup()
         .collect();
+    for rules_dir in &opts.rules_dirs {
+        if !is_stdio(output_file) {
+            warn_if_output_file_is_unmatched_in_rules_dir(rules_dir, output_file, opts);
+        }
+    }
+    stage_timings.push(("finding rules files", stage_start.elapsed()));
+    if opts.doctor {
+        let problems = diagnose_setup(opts, &rule_files);
+        ensure!(
+            !failure && problems == 0,
+            "Found {} problems with the setup. See above for more details.",
+            problems
+        );
+        return Ok(());
+    }
+    if opts.lint_only {
+        let malformed = find_malformed_rule_files(&rule_files);
+        ensure!(
+            !failure && malformed == 0,
+            "{} of {} rules files are malformed. See logs above for more details.",
+            malformed,
+            rule_files.len()
+        );
+        log::info!("All {} rules files are well-formed", rule_files.len());
+        return Ok(());
+    }
 
-    // Get a list of _all_ the selectors we use.
-    let selectors: Vec<SelectorWithOriginRule> = rule_files
-        .iter()
-        .flat_map(|path| {
-            // If the output file is already there ignore it. We're going to
-            // overwrite it at the end. Use `canoncialize` to handle all the
-            // edge cases around expanding paths and such. It's pretty far
-            // fetched that it'll actually fail but it's easy enough to handle.
-            // Things will probably fail down the line if cannicalization did
-            // fail so log the failure here but still have a go at getting the
-            // selectors. If `output_file` doesn't exist then `canonicalize`
-            // will fail so we need to check it does exists before check if it
-            // is the same as the path we're currently looking at. We don't need
-            // to check `path` because it was given to us by [glob::glob] so it
-            // must exist.
-            let path_is_output_file = output_file.as_ref().exists()
-                && match (fs::canonicalize(path), fs::canonicalize(&output_file)) {
-                    (Ok(canonical_path), Ok(canonical_output_file)) => {
-                        canonical_path == canonical_output_file
-                    }
-                    (Ok(_), Err(e)) => {
-                        log::error!("Failed to canonicalize output file path: {}", e);
-                        failure = true;
-                        false
-                    }
-                    (Err(e), Ok(_)) => {
-                        log::error!("Failed to canonicalize path: {}", e);
-                        failure = true;
-                        false
+    let stage_start = Instant::now();
+    // Get a list of _all_ the selectors we use, along with everything that
+    // went wrong getting them for `--errors-file`. There can be thousands of
+    // files so they're read in parallel, but collecting keeps them in the same
+    // order as `rule_files` so the output doesn't change from run to run.
+    let results: Vec<_> = rule_files
+        .par_iter()
+        .map(|path| {
+            let (path_is_generated, failure) = is_generated_output_path(path, opts);
+            if path_is_generated {
+                (vec![], vec![], failure)
+            } else {
+                match get_selectors_and_errors_in_file(&path, opts) {
+                    Ok((selectors, errors)) if errors.is_empty() => {
+                        (selectors, vec![], failure)
                     }
-                    (Err(path_e), Err(output_file_e)) => {
-                        log::error!("Failed to canonicalize output file path: {}", path_e);
-                        log::error!("Failed to canonicalize path: {}", output_file_e);
-                        failure = true;
-                        false
+                    Ok((_, errors)) => {
+                        log::error!(
+                            "Failed to get selectors from file: There was a failure getting selectors from {}, see logs for details.",
+                            path.display()
+                        );
+                        (vec![], errors, true)
                     }
-                };
-            if path_is_output_file {
-                vec![]
-            } else {
-                match get_selectors_in_file(&path) {
-                    Ok(selectors) => selectors,
                     Err(e) => {
                         log::error!("Failed to get selectors from file: {}", e);
-                        failure = true;
-                        vec![]
+                        let error = SelectorExtractionError {
+                            file: path.clone(),
+                            group: None,
+                            rule_name: None,
+                            expr: None,
+                            error: format!("{:#}", e),
+                        };
+                        (vec![], vec![error], true)
                     }
                 }
             }
         })
         .collect();
-    let grouped_selectors: Vec<(String, Vec<SelectorWithOriginRule>)> = selectors
-        .iter()
-        .sorted_by_key(|selector| selector.sort_key())
-        .group_by(|selector| selector.sort_key())
-        .into_iter()
-        .filter_map(|(selector, group)| {
-            if metrics_to_ignore.contains(&selector) {
-                None
-            } else {
-                Some((selector, group.cloned().collect()))
-            }
-        })
-        .collect();
+    let mut selectors: Vec<SelectorWithOriginRule> = vec![];
+    let mut extraction_errors: Vec<SelectorExtractionError> = vec![];
+    for (mut file_selectors, mut file_errors, file_failure) in results {
+        selectors.append(&mut file_selectors);
+        extraction_errors.append(&mut file_errors);
+        failure |= file_failure;
+    }
+    // We can't tell which files in `--output-dir` we wrote last time until we
+    // know which values there are, so they're read like any other and what
+    // came from them is dropped here.
+    if let Some(output_dir) = &opts.output_dir {
+        let split_output_files = find_split_output_files(output_dir, &selectors, opts);
+        selectors.retain(|selector| !split_output_files.contains(&selector.source_file));
+    }
+    // Write this out even if it's empty so tools can tell there were no errors
+    // rather than the run not happening.
+    if let Some(errors_file) = &opts.errors_file {
+        fs::write(
+            errors_file,
+            serde_json::to_string_pretty(&extraction_errors)?,
+        )
+        .with_context(|| format!("failed to write {}", errors_file.display()))?;
+    }
     log::info!(
-        "Found {} unique selectors in {} files",
-        grouped_selectors.len(),
+        "Found {} selectors in {} files",
+        selectors.len(),
         rule_files.len()
     );
-    let absent_alert_rules = grouped_selectors
-        .iter()
-        .map(|(_selector, selectors)| merge_selectors_into_rule(selectors, playbook_link.clone()))
-        .collect();
-    let config = PrometheusRulesConfig {
-        groups: vec![PrometheusRuleGroup {
-            name: "absent_label_alerts".into(),
-            rules: absent_alert_rules,
-        }],
+    // Metrics from third parties might never appear in our rules but we
+    // still want to know if they go missing.
+    if let Some(extra_metrics) = &opts.extra_metrics {
+        match get_extra_selectors(extra_metrics) {
+            Ok(extra_selectors) => selectors.extend(extra_selectors),
+            Err(e) => {
+                log::error!("Failed to get extra metrics: {:#}", e);
+                failure = true;
+            }
+        }
+    }
+    if opts.require_metric {
+        for selector in find_nameless_selectors(&selectors) {
+            log::error!(
+                "Selector '{}' in '{}' in {} has no metric name",
+                selector.selector,
+                selector.rule.name().unwrap_or(&selector.rule.expr),
+                selector.source_file.display()
+            );
+            failure = true;
+        }
+    }
+    stage_timings.push(("parsing rules", stage_start.elapsed()));
+    let stage_start = Instant::now();
+    // `generate_config` does the actual ignoring, this is just so the plan
+    // can say what was ignored.
+    let selectors_found = selectors.len();
+    let ignored_selectors: Vec<String> = if opts.plan_json.is_some() {
+        let metrics_to_ignore = parse_ignore_entries(&load_ignore_file(opts)?)?;
+        selectors
+            .iter()
+            .filter(|selector| {
+                metrics_to_ignore
+                    .iter()
+                    .any(|entry| entry.matches(selector))
+            })
+            .map(|selector| selector.sort_key())
+            .sorted()
+            .dedup()
+            .collect()
+    } else {
+        vec![]
     };
+    let outputs: Vec<(PathBuf, PrometheusRulesConfig)> = if let Some(output_dir) = &opts.output_dir
+    {
+        generate_configs_by_label(selectors, opts)?
+            .into_iter()
+            .map(|(value, config)| {
+                (
+                    output_dir.join(split_output_file_name(value.as_deref())),
+                    config,
+                )
+            })
+            .collect()
+    } else if opts.records_output_file.is_some() || opts.exprs_output_file.is_some() {
+        let config = generate_config(selectors, opts)?;
+        let (records_config, exprs_config) = split_config_by_origin(config);
+        vec![
+            (
+                opts.records_output_file
+                    .clone()
+                    .unwrap_or_else(|| output_file.clone()),
+                records_config,
+            ),
+            (
+                opts.exprs_output_file
+                    .clone()
+                    .unwrap_or_else(|| output_file.clone()),
+                exprs_config,
+            ),
+        ]
+    } else {
+        vec![(output_file.clone(), generate_config(selectors, opts)?)]
+    };
+    let outputs = match &opts.transform_script {
+        Some(transform_script) => outputs
+            .into_iter()
+            .map(|(output_file, config)| {
+                let config = transform_config(&config, transform_script).with_context(|| {
+                    format!(
+                        "failed to transform the rules for {}",
+                        output_file.display()
+                    )
+                })?;
+                Ok((output_file, config))
+            })
+            .collect::<Result<Vec<_>>>()?,
+        None => outputs,
+    };
+    stage_timings.push(("generating rules", stage_start.elapsed()));
     log::debug!(
         "Writing generated absent selector rules config to {}",
-        output_file.as_ref().display()
+        output_file.display()
     );
     ensure!(!failure, "Failure at some point during the generation process. See logs above for more details. Config file not being written out.");
-    write_generated_config_to_file(output_file, &config)?;
+    if let Some(plan_json) = &opts.plan_json {
+        let plan = Plan {
+            files_scanned: rule_files.clone(),
+            selectors_found,
+            ignored_selectors,
+            outputs: outputs
+                .iter()
+                .map(|(output_file, config)| plan_output(output_file, config))
+                .collect::<Result<_>>()?,
+        };
+        fs::write(plan_json, serde_json::to_string_pretty(&plan)?)
+            .with_context(|| format!("failed to write {}", plan_json.display()))?;
+    }
+    if opts.validate_only {
+        let stage_start = Instant::now();
+        let result = validate_with_promtool(&outputs, opts);
+        stage_timings.push(("validating output", stage_start.elapsed()));
+        return result;
+    }
+    if opts.selector_delta {
+        for (output_file, config) in &outputs {
+            let existing_selectors = if output_file.exists() {
+                get_selectors_in_generated_file(output_file)?
+            } else {
+                vec![]
+            };
+            let (added, removed) =
+                selector_delta(&existing_selectors, &get_selectors_in_config(config)?);
+            log::info!(
+                "{} selectors would be added to and {} removed from {}",
+                added.len(),
+                removed.len(),
+                output_file.display()
+            );
+            let color = opts.color.stdout_enabled();
+            for selector in added {
+                println!("{}", format_delta_line('+', &selector, color));
+            }
+            for selector in removed {
+                println!("{}", format_delta_line('-', &selector, color));
+            }
+        }
+        return Ok(());
+    }
+    if opts.diff {
+        let mut stale_files = vec![];
+        for (output_file, config) in &outputs {
+            let existing = if output_file.exists() {
+                fs::read_to_string(output_file)
+                    .with_context(|| format!("failed to read {}", output_file.display()))?
+            } else {
+                String::new()
+            };
+            let generated = render_generated_config(config, opts)?;
+            if existing != generated {
+                print!("{}", unified_diff(output_file, &existing, &generated));
+                stale_files.push(output_file.display().to_string());
+            }
+        }
+        ensure!(
+            stale_files.is_empty(),
+            "The generated rules differ from {}, regenerate to update them",
+            stale_files.join(", ")
+        );
+        log::info!("The generated rules are up to date");
+        return Ok(());
+    }
+    if let Some(baseline) = &opts.write_baseline {
+        ensure!(
+            outputs.len() == 1,
+            "--write-baseline can't be used with --records-output-file, --exprs-output-file, or --split-by-label"
+        );
+        log::info!(
+            "Writing the generated rules to {} as the baseline",
+            baseline.display()
+        );
+        return write_generated_config_to_file(baseline, &outputs[0].1, opts);
+    }
+    if opts.check {
+        // Without a --baseline every output file is checked, so a stale
+        // records or per-label file is caught too.
+        let baselines: Vec<(&Path, &PrometheusRulesConfig)> = match &opts.baseline {
+            Some(baseline) => {
+                ensure!(
+                    outputs.len() == 1,
+                    "--baseline can't be used with --records-output-file, --exprs-output-file, or --split-by-label"
+                );
+                vec![(baseline.as_path(), &outputs[0].1)]
+            }
+            None => outputs
+                .iter()
+                .map(|(output_file, config)| (output_file.as_path(), config))
+                .collect(),
+        };
+        let mut stale_files = vec![];
+        for (baseline, config) in baselines {
+            // Rendered exactly as write_generated_config_to_file would write
+            // it, header and all, so this is a byte for byte comparison.
+            let expected = fs::read_to_string(baseline)
+                .with_context(|| format!("failed to read baseline {}", baseline.display()))?;
+            if render_generated_config(config, opts)? != expected {
+                stale_files.push(baseline.display().to_string());
+            }
+        }
+        ensure!(
+            stale_files.is_empty(),
+            "The generated rules differ from {}, regenerate to update them",
+            stale_files.join(", ")
+        );
+        log::info!("The generated rules are up to date");
+        return Ok(());
+    }
+    if opts.stdout {
+        // Printing isn't writing a file so it happens even on a dry run.
+        for (_, config) in &outputs {
+            write_generated_config_to_file(Path::new("-"), config, opts)?;
+        }
+        return Ok(());
+    }
+    if opts.dry_run {
+        return Ok(());
+    }
+    let stage_start = Instant::now();
+    for (output_file, config) in &outputs {
+        write_generated_config_to_file(output_file, config, opts)?;
+    }
+    if let Some(tests_file) = &opts.emit_tests {
+        write_promtool_tests_to_file(tests_file, &outputs)?;
+    }
+    stage_timings.push(("writing output", stage_start.elapsed()));
     Ok(())
 }
 
-/// Merge the given [Selector]s into a [PrometheusRule].
-///
-/// This is where the logic for adopting certain attributes from the selector
-/// origin rules is contained. Currently we do this for the "for" field, where
-/// we take the smallest "for" then use it or 1h, whichever is larger.
-fn merge_selectors_into_rule(
+/// Whether the rules file `path` is one of the files we're about to generate,
+/// `--output-file`, `--records-output-file` or `--exprs-output-file`, so
+/// shouldn't be read. Also returns whether there was a failure working that
+/// out, which is logged.
+fn is_generated_output_path(path: &Path, opts: &Opts) -> (bool, bool) {
+    // stdin can't be a file we're writing to, and can't be canonicalized.
+    if is_stdio(path) {
+        return (false, false);
+    }
+    let mut failure = false;
+    // If an output file is already there ignore it. We're going to overwrite
+    // it at the end. Use `canoncialize` to handle all the edge cases around
+    // expanding paths and such. It's pretty far fetched that it'll actually
+    // fail but it's easy enough to handle. Things will probably fail down the
+    // line if cannicalization did fail so log the failure here but still have
+    // a go at getting the selectors. If `output_file` doesn't exist then
+    // `canonicalize` will fail so we need to check it does exists before check
+    // if it is the same as the path we're currently looking at. Other than
+    // stdin, which was handled above, `path` was given to us by [glob::glob]
+    // so it must exist.
+    let path_is_output_file = std::iter::once(&opts.output_file)
+        .chain(&opts.records_output_file)
+        .chain(&opts.exprs_output_file)
+        .filter(|output_file| output_file.exists())
+        .any(
+            |output_file| match (fs::canonicalize(path), fs::canonicalize(output_file)) {
+                (Ok(canonical_path), Ok(canonical_output_file)) => {
+                    canonical_path == canonical_output_file
+                }
+                (Ok(_), Err(e)) => {
+                    log::error!("Failed to canonicalize output file path: {}", e);
+                    failure = true;
+                    false
+                }
+                (Err(e), Ok(_)) => {
+                    log::error!("Failed to canonicalize path: {}", e);
+                    failure = true;
+                    false
+                }
+                (Err(path_e), Err(output_file_e)) => {
+                    log::error!("Failed to canonicalize output file path: {}", path_e);
+                    log::error!("Failed to canonicalize path: {}", output_file_e);
+                    failure = true;
+                    false
+                }
+            },
+        );
+    (path_is_output_file, failure)
+}
+
+/// The files `selectors` come from that are in `output_dir` and have the name
+/// of one of the files we're going to write there, i.e. the output of an
+/// earlier run. Anything else in `output_dir` is rules like any other.
+fn find_split_output_files(
+    output_dir: &Path,
     selectors: &[SelectorWithOriginRule],
-    playbook_link: Option<String>,
-) -> PrometheusRule {
-    let name = build_absent_selector_alert_name(&selectors.first().unwrap().selector);
-    let function = wrap_selector_in_absent(&selectors.first().unwrap().selector);
-    let shortest_for = selectors
+    opts: &Opts,
+) -> BTreeSet<PathBuf> {
+    let canonical_output_dir = match fs::canonicalize(output_dir) {
+        Ok(dir) => dir,
+        // It hasn't been written to yet so there's nothing of ours in it.
+        Err(_) => return BTreeSet::new(),
+    };
+    let output_file_names: BTreeSet<String> = selectors
         .iter()
-        .flat_map(|s| {
-            s.rule
-                .untyped_fields
-                .get("for")
-                .and_then(|val| val.as_str())
-                .and_then(|duration| {
-                    if duration.len() < 2 {
-                        log::error!(
-                            "Malformed duration, expected at least two characters, found '{}'",
-                            duration
-                        );
-                        return None;
-                    }
-                    let unit = duration[duration.len() - 1..].into();
-                    match duration[0..duration.len() - 1].parse() {
-                        Ok(value) => {
-                            match prometheus_parser::PromDuration::from_pair(unit, value) {
-                                Ok(duration) => Some(duration),
-                                Err(e) => {
-                                    log::error!("Invalid duration {}{}: {}", value, unit, e);
-                                    None
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            log::error!("Invalid 'for' field '{}': {}", duration, e);
-                            None
-                        }
-                    }
-                })
+        .map(|selector| split_output_file_name(split_value(selector, opts).as_deref()))
+        .chain(std::iter::once(split_output_file_name(None)))
+        .collect();
+    selectors
+        .iter()
+        .map(|selector| &selector.source_file)
+        .unique()
+        .filter(|path| {
+            let has_output_file_name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map_or(false, |name| output_file_names.contains(name));
+            has_output_file_name
+                && fs::canonicalize(path)
+                    .map(|path| path.parent() == Some(canonical_output_dir.as_path()))
+                    .unwrap_or(false)
         })
-        .min();
-    let chosen_for = shortest_for
-        .map(|duration| max(duration, prometheus_parser::PromDuration::Hours(1)))
-        .unwrap_or(prometheus_parser::PromDuration::Hours(1));
-    let mut labels: BTreeMap<String, String> = btree_map! {
-            "severity" => "low_urgency_page"
-    };
-    if let Some(playbook_link) = playbook_link {
-        labels.insert("playbook".to_string(), playbook_link);
-    }
+        .cloned()
+        .collect()
+}
 
-    PrometheusAbsentSelectorAlertRule {
-        name,
-        expr: function.to_string(),
-        selector_expr: selectors.first().unwrap().selector.to_string(),
-        r#for: chosen_for,
-        labels,
+/// Check what would be written to each of `outputs` with `promtool check
+/// rules`, for `--validate-only`. Each config is written to a temporary file
+/// which is removed afterwards so the real output files are never touched.
+fn validate_with_promtool(outputs: &[(PathBuf, PrometheusRulesConfig)], opts: &Opts) -> Result<()> {
+    let mut invalid = 0;
+    for (i, (output_file, config)) in outputs.iter().enumerate() {
+        let temp_file = std::env::temp_dir().join(format!(
+            "absent-rules-validate-{}-{}.rules.yml",
+            std::process::id(),
+            i
+        ));
+        write_generated_config_to_file(&temp_file, config, opts)?;
+        let output = std::process::Command::new("promtool")
+            .arg("check")
+            .arg("rules")
+            .arg(&temp_file)
+            .output();
+        if let Err(e) = fs::remove_file(&temp_file) {
+            log::warn!("Failed to remove {}: {}", temp_file.display(), e);
+        }
+        let output = output.context("failed to run promtool, is it installed?")?;
+        if output.status.success() {
+            log::info!("Rules for {} are valid", output_file.display());
+        } else {
+            log::error!(
+                "Rules for {} are invalid:\n{}{}",
+                output_file.display(),
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            );
+            invalid += 1;
+        }
     }
-    .into()
+    ensure!(
+        invalid == 0,
+        "{} of {} generated rules files failed promtool check rules. See logs above for more details.",
+        invalid,
+        outputs.len()
+    );
+    Ok(())
 }
 
-/// Build the alert name for a selector.
-///
-/// This takes the metric name, labels, range, and offset, and smashes them
-/// together separated by underscores and puts "absent_" in front. For complex
-/// selectors the results will _not_ be pretty but at least it'll be somewhat
-/// clear what it's for (not some random id) and will only contain allowed
-/// characters ([a-zA-Z_][a-zA-Z0-9_]*).
-fn build_absent_selector_alert_name(selector: &prometheus_parser::Selector) -> String {
-    let metric = if let Some(metric) = &selector.metric {
-        format!("_{}", metric)
-    } else {
-        // This should never happen and I think it's a problem with
-        // prometheus_parser's data model. Just log it and make the first char
-        // something that is allowed.
-        log::error!("Found selector with no metric: '{}'", selector);
-        "_".into()
-    };
-    let mut labels = selector
-        .labels
+/// Pipe `config` through the `--transform-script` and read the transformed
+/// rules back from what it outputs.
+fn transform_config(
+    config: &PrometheusRulesConfig,
+    transform_script: &Path,
+) -> Result<PrometheusRulesConfig> {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let input = serde_yaml::to_string(config)?;
+    let mut child = Command::new(transform_script)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to run {}", transform_script.display()))?;
+    // Write from another thread so a script that writes a lot before it's
+    // read all of its input doesn't deadlock with us.
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("failed to open stdin"))?;
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output()?;
+    // The script is allowed to not read all of its input, so a broken pipe
+    // doesn't matter as long as it succeeds.
+    if let Ok(Err(e)) = writer.join() {
+        log::debug!(
+            "Failed to write all the rules to the transform script: {}",
+            e
+        );
+    }
+    ensure!(
+        output.status.success(),
+        "{} failed with {}",
+        transform_script.display(),
+        output.status
+    );
+    serde_yaml::from_slice(&output.stdout)
+        .with_context(|| format!("{} didn't output valid rules", transform_script.display()))
+}
+
+/// A little table of how long each stage took for `--profile`.
+fn format_stage_timings(stage_timings: &[(&str, Duration)]) -> String {
+    let total: Duration = stage_timings.iter().map(|(_, duration)| *duration).sum();
+    stage_timings
         .iter()
-        .map(|label| {
-            // LabelOp's string repr is the symbol which isn't compatible with
-            // the allowed characters for alert names. Lets convert it to
-            // something that still has meaning but is allowed.
-            let op = match label.op {
-                prometheus_parser::LabelOp::Equal => "equal",
-                prometheus_parser::LabelOp::NotEqual => "notequal",
-                prometheus_parser::LabelOp::RegexEqual => "regexequal",
-                prometheus_parser::LabelOp::RegexNotEqual => "regexnotequal",
-            };
-            // This regex is constant so panicing on it being incorrect is okay
-            // as it would be a developer error.
-            let not_allowed_chars_re = Regex::new("[^a-zA-Z0-9_:]").expect("invalid regex");
-            let value = not_allowed_chars_re.replace_all(&label.value, "_");
-            format!("{}_{}_{}", label.key, op, value)
+        .chain(std::iter::once(&("total", total)))
+        .map(|(stage, duration)| {
+            format!("{:<20} {:>10.3}ms", stage, duration.as_secs_f64() * 1000.0)
         })
-        .join("_");
-    if !labels.is_empty() {
-        labels = "_".to_string() + &labels
+        .join("\n")
+}
+
+/// Split `config` in two: the rules for selectors from `record` names, i.e.
+/// metrics we record ourselves, and the rest. Each keeps the groups it has
+/// rules in.
+fn split_config_by_origin(
+    config: PrometheusRulesConfig,
+) -> (PrometheusRulesConfig, PrometheusRulesConfig) {
+    let mut records_config = PrometheusRulesConfig { groups: vec![] };
+    let mut exprs_config = PrometheusRulesConfig { groups: vec![] };
+    for group in config.groups {
+        let (records_rules, exprs_rules): (Vec<PrometheusRule>, Vec<PrometheusRule>) = group
+            .rules
+            .into_iter()
+            .partition(|rule| rule.origin == SelectorOrigin::Record);
+        for (split_config, rules) in vec![
+            (&mut records_config, records_rules),
+            (&mut exprs_config, exprs_rules),
+        ] {
+            split_config.groups.push(PrometheusRuleGroup {
+                name: group.name.clone(),
+                rules,
+                limit: group.limit,
+                labels: group.labels.clone(),
+                query_offset: group.query_offset.clone(),
+                extra_fields: group.extra_fields.clone(),
+            });
+        }
     }
-    let range = if let Some(range) = selector.range {
-        format!("_{}", range)
-    } else {
-        "".into()
-    };
-    let offset = if let Some(offset_duration) = selector.offset {
-        format!("_offset_{}", offset_duration)
-    } else {
-        "".into()
-    };
-    format!("absent{}{}{}{}", metric, labels, range, offset)
+    (records_config, exprs_config)
 }
 
-/// Write out the serializable config to the provided file with a comment header
-/// to say this generated.
-fn write_generated_config_to_file<P: AsRef<Path>, C: Serialize>(path: P, config: &C) -> Result<()> {
-    let serialized = serde_yaml::to_string(config)?;
-    let contents = format!(
-        "
-# DO NOT MODIFY THIS FILE BY HAND. It was generated by {} in operations/tools/prometheus-absent-data-alert-rule-generator.
-{}",
-        env!("CARGO_PKG_NAME"), serialized
-    );
-    Ok(fs::write(path, contents)?)
+/// Generate the absent rules config for all the given selectors.
+///
+/// See [generate_configs_by_label], this puts all the configs' groups into one.
+fn generate_config(
+    selectors: Vec<SelectorWithOriginRule>,
+    opts: &Opts,
+) -> Result<PrometheusRulesConfig> {
+    let groups = generate_configs_by_label(selectors, opts)?
+        .into_iter()
+        .flat_map(|(_, config)| config.groups)
+        .collect();
+    Ok(PrometheusRulesConfig { groups })
 }
 
-fn get_selectors_in_file<P: AsRef<Path>>(rules_path: P) -> Result<Vec<SelectorWithOriginRule>> {
-    let config = load_rules_from_file(&rules_path)?;
-    let mut selectors: Vec<SelectorWithOriginRule> = vec![];
-    let mut failed = false;
-    for group in config.groups {
-        for rule in group.rules {
-            let expr_selectors = match prometheus_parser::parse_expr(&rule.expr) {
-                Ok(expr) => get_selectors_from_expression(&expr),
-                Err(e) => {
-                    log::error!("Failed to parse expression '{}': {}", rule.expr, e);
-                    failed = true;
-                    continue;
-                }
-            };
-            let mut rule_selectors: Vec<SelectorWithOriginRule> = expr_selectors
-                .into_iter()
-                .map(|selector| SelectorWithOriginRule {
-                    selector,
-                    rule: rule.clone(),
-                })
-                .collect();
-            selectors.append(&mut rule_selectors);
-            // Also explicitly get the recordings we've defined. Even if
-            // they're not used in other Prometheus rules they may be used
-            // in places like Grafana. We've defined them for a reason so we
-            // should alert if they're missing.
-            if let Some(record_name_value) = rule.untyped_fields.get("record") {
-                let maybe_record_name = record_name_value.as_str();
-                if let Some(record_name) = maybe_record_name {
-                    match prometheus_parser::parse_expr(record_name) {
-                        Ok(prometheus_parser::Expression::Selector(selector)) => {
-                            selectors.push(SelectorWithOriginRule {
-                                selector,
-                                rule: rule.clone(),
-                            });
-                        }
-                        Ok(_) => {
-                            log::error!("Expected record name '{}' to be a selector", record_name);
-                            failed = true;
-                        }
-                        Err(e) => {
-                            log::error!("Failed to parse selector name '{}': {}", record_name, e);
-                            failed = true;
-                        }
-                    }
+/// Generate the absent rules configs for all the given selectors, one per
+/// `--split-by-label` value or `--split-by-dir` subdirectory. Rules without
+/// one, which is all of them if neither is given, are under [None].
+///
+/// This is where selectors are filtered, grouped, and merged into rules.
+fn generate_configs_by_label(
+    selectors: Vec<SelectorWithOriginRule>,
+    opts: &Opts,
+) -> Result<BTreeMap<Option<String>, PrometheusRulesConfig>> {
+    let ignore_lines = load_ignore_file(opts)?;
+    let metrics_to_ignore = parse_ignore_entries(&ignore_lines)?;
+    log::debug!("Ignoring these metrics {:?}", metrics_to_ignore);
+    let metadata = match &opts.metadata_file {
+        Some(path) => read_map_file(path)?,
+        None => BTreeMap::new(),
+    };
+    let high_cardinality_metrics = match &opts.high_cardinality_file {
+        Some(path) => read_list_file(path)?,
+        None => vec![],
+    };
+    // High cardinality metrics get one rule for the bare metric instead of one
+    // for every combination of labels they're selected with.
+    let selectors: Vec<SelectorWithOriginRule> = selectors
+        .into_iter()
+        .map(|selector| match &selector.selector.metric {
+            Some(metric) if high_cardinality_metrics.contains(metric) => SelectorWithOriginRule {
+                selector: strip_selector_to_metric(&selector.selector),
+                ..selector
+            },
+            _ => selector,
+        })
+        .collect();
+    let without_range = |selector: &SelectorWithOriginRule| {
+        prometheus_parser::Selector {
+            span: None,
+            range: None,
+            ..sort_selector_labels(&selector.selector)
+        }
+        .to_string()
+    };
+    // Selectors used both with and without a range, which are only merged
+    // with `--prefer-range` or `--prefer-instant`.
+    let mut vector_types: BTreeMap<String, (bool, bool)> = BTreeMap::new();
+    if opts.mixed_vector_preference != MixedVectorPreference::Both {
+        for selector in &selectors {
+            let (instant, range) = vector_types.entry(without_range(selector)).or_default();
+            if selector.selector.range.is_some() {
+                *range = true;
+            } else {
+                *instant = true;
+            }
+        }
+    }
+    let is_mixed = |key: &String| vector_types.get(key) == Some(&(true, true));
+    // With `--combine-variants` all the selectors for a metric end up in the
+    // same group, otherwise only identical selectors do.
+    let selector_key = |selector: &SelectorWithOriginRule| match &selector.selector.metric {
+        Some(metric) if opts.combine_variants || opts.merge_label_values => metric.clone(),
+        _ if opts.ignore_range || is_mixed(&without_range(selector)) => without_range(selector),
+        _ => selector.sort_key(),
+    };
+    let group_key = |selector: &SelectorWithOriginRule| {
+        let key = match opts.dedup_scope {
+            DedupScope::Global => selector_key(selector),
+            DedupScope::Group => format!("{} in {}", selector_key(selector), selector.group_name),
+        };
+        // Each `--split-by-dir` file only has the selectors used under its
+        // subdirectory, so selectors used in several get a rule in each.
+        if opts.split_by_dir {
+            match rules_subdir(&selector.source_file, &opts.rules_dirs) {
+                Some(subdir) => format!("{} under {}", key, subdir),
+                None => key,
+            }
+        } else {
+            key
+        }
+    };
+    // Nothing that was ignored made it into a generated file so every entry
+    // would look unused when migrating one.
+    if !opts.migrate {
+        let unused_lines: Vec<&String> = ignore_lines
+            .iter()
+            .zip(&metrics_to_ignore)
+            .filter(|(_, entry)| !selectors.iter().any(|selector| entry.matches(selector)))
+            .map(|(line, _)| line)
+            .collect();
+        for line in &unused_lines {
+            log::warn!("'{}' in the ignore file didn't match any selectors", line);
+        }
+        ensure!(
+            !opts.strict_ignore || unused_lines.is_empty(),
+            "{} lines in the ignore file didn't match any selectors. See logs above for more details.",
+            unused_lines.len()
+        );
+    }
+    let mut grouped_selectors: Vec<(String, Vec<SelectorWithOriginRule>)> = selectors
+        .iter()
+        .filter(|selector| {
+            !metrics_to_ignore
+                .iter()
+                .any(|entry| entry.matches(selector))
+        })
+        .sorted_by_key(|selector| group_key(selector))
+        .group_by(|selector| group_key(selector))
+        .into_iter()
+        .map(|(selector, group)| (selector, group.cloned().collect()))
+        .collect();
+    if opts.no_sort {
+        // Going backwards means the first position of each key is the one
+        // that's kept.
+        let first_seen: BTreeMap<String, usize> = selectors
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, selector)| (group_key(selector), i))
+            .collect();
+        grouped_selectors.sort_by_key(|(key, _)| first_seen.get(key).copied());
+    }
+    log::info!("Found {} unique selectors", grouped_selectors.len());
+    if let Some(factor) = opts.warn_for_divergence {
+        let divergent_count = grouped_selectors
+            .iter()
+            .filter(|(selector, selectors)| match for_divergence(selectors) {
+                Some(ratio) if ratio > factor => {
+                    log::warn!(
+                        "The rules using '{}' disagree on 'for', the longest is {:.1} times the shortest",
+                        selector,
+                        ratio
+                    );
+                    true
                 }
+                _ => false,
+            })
+            .count();
+        ensure!(
+            !opts.strict || divergent_count == 0,
+            "{} selectors have diverging 'for' durations",
+            divergent_count
+        );
+    }
+    let grouped_selectors = match (&opts.expand_by, &opts.prometheus_url) {
+        (Some(label), Some(prometheus_url)) => {
+            expand_selectors_by_label(grouped_selectors, prometheus_url, label, opts)
+        }
+        _ => grouped_selectors,
+    };
+    let currently_absent_metrics = match &opts.prometheus_url {
+        Some(prometheus_url) => find_currently_absent_metrics(&grouped_selectors, |metric| {
+            metric_has_data(prometheus_url, metric, opts)
+        }),
+        None => vec![],
+    };
+    // Which selectors ended up in each rule, for `--dedup-report`, in the same
+    // order as the rules.
+    let mut rule_selectors: Vec<Vec<String>> = vec![];
+    // The selectors of each rule with their labels sorted, to tell apart
+    // rules whose names collide.
+    let mut rule_selector_keys: Vec<String> = vec![];
+    // The `--group-by-label` value of each rule, in the same order as the
+    // rules.
+    let mut rule_group_label_values: Vec<Option<String>> = vec![];
+    // Likewise for the `--split-by-label` value of each rule.
+    let mut rule_split_label_values: Vec<Option<String>> = vec![];
+    let mut absent_alert_rules: Vec<PrometheusRule> = grouped_selectors
+        .iter()
+        .filter_map(|(selector, selectors)| {
+            let currently_absent = selectors
+                .first()
+                .and_then(|s| s.selector.metric.as_ref())
+                .map(|metric| currently_absent_metrics.contains(metric))
+                .unwrap_or(false);
+            if currently_absent && !opts.tag_currently_absent {
+                log::info!("Skipping '{}' because it has no data right now", selector);
+                return None;
             }
+            let metric_help = selectors
+                .first()
+                .and_then(|s| s.selector.metric.as_ref())
+                .and_then(|metric| metadata.get(metric))
+                .map(String::as_str);
+            let mut rule = merge_selectors_into_rule(selectors, opts, metric_help);
+            if currently_absent {
+                log::info!("Tagging '{}' because it has no data right now", selector);
+                tag_currently_absent(&mut rule);
+            }
+            rule_selectors.push(
+                selectors
+                    .iter()
+                    .map(|s| s.selector.to_string())
+                    .unique()
+                    .collect(),
+            );
+            rule_selector_keys.push(
+                selectors
+                    .iter()
+                    .map(|s| s.sort_key())
+                    .unique()
+                    .sorted()
+                    .join(" or "),
+            );
+            rule_group_label_values.push(
+                opts.group_by_label
+                    .as_ref()
+                    .and_then(|key| agreed_selector_label(selectors, key)),
+            );
+            rule_split_label_values.push(if opts.split_by_dir {
+                selectors.first().and_then(|s| split_value(s, opts))
+            } else {
+                opts.split_by_label
+                    .as_ref()
+                    .and_then(|key| agreed_inherited_label(selectors, key))
+            });
+            Some(rule)
+        })
+        .collect();
+    // `--allow-duplicate-names` keeps the names as they are so they don't
+    // change when another rule happens to collide with them.
+    if !opts.allow_duplicate_names {
+        disambiguate_alert_names(&mut absent_alert_rules, &rule_selector_keys);
+    }
+    if let Some(max_name_length) = opts.max_name_length {
+        truncate_alert_names(&mut absent_alert_rules, max_name_length)?;
+    }
+    let mut dedup_report: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (rule, selectors) in absent_alert_rules.iter().zip(rule_selectors) {
+        if let Some(name) = rule.name() {
+            dedup_report
+                .entry(name.to_string())
+                .or_insert_with(Vec::new)
+                .extend(selectors);
         }
     }
-    if failed {
-        anyhow::bail!(
-            "There was a failure getting selectors from {}, see logs for details.",
-            rules_path.as_ref().display()
+    if let Some(dedup_report_file) = &opts.dedup_report {
+        log::debug!("Writing dedup report to {}", dedup_report_file.display());
+        fs::write(
+            dedup_report_file,
+            serde_json::to_string_pretty(&dedup_report)?,
         )
+        .with_context(|| format!("failed to write {}", dedup_report_file.display()))?;
     }
-    Ok(selectors)
-}
-
-/// Get all the selectors in an expression.
-///
-/// Recursively traverse the AST and return all the selectors it finds.
-fn get_selectors_from_expression(
-    expr: &prometheus_parser::Expression,
-) -> Vec<prometheus_parser::Selector> {
-    match expr {
-        prometheus_parser::Expression::Float(_) => vec![],
-        prometheus_parser::Expression::String(_) => vec![],
-        prometheus_parser::Expression::Selector(selector) => vec![selector.to_owned()],
-        prometheus_parser::Expression::Group(prometheus_parser::Group { expression, .. }) => {
-            get_selectors_from_expression(&expression)
+    if !opts.label_order.is_empty() {
+        for rule in &mut absent_alert_rules {
+            if let Some(Value::Mapping(labels)) = rule.untyped_fields.get_mut("labels") {
+                *labels = order_yaml_mapping(labels, &opts.label_order);
+            }
         }
-        prometheus_parser::Expression::Function(function) => function
-            .args
-            .iter()
-            .flat_map(|arg| get_selectors_from_expression(&arg))
-            .collect(),
-        prometheus_parser::Expression::Operator(operator) => {
-            let mut selectors = get_selectors_from_expression(&operator.lhs);
-            selectors.extend(get_selectors_from_expression(&operator.rhs));
-            selectors
+    }
+    // Sanitising selectors into alert names is lossy so different selectors
+    // can end up with the same name.
+    let duplicate_names = find_duplicate_alert_names(&absent_alert_rules);
+    if opts.allow_duplicate_names {
+        if !duplicate_names.is_empty() {
+            log::warn!(
+                "{} alert names are shared by more than one rule",
+                duplicate_names.len()
+            );
         }
-        prometheus_parser::Expression::BoolOperator(bool_operator) => {
-            let mut selectors = get_selectors_from_expression(&bool_operator.lhs);
-            selectors.extend(get_selectors_from_expression(&bool_operator.rhs));
-            selectors
+    } else {
+        for (name, count) in &duplicate_names {
+            log::warn!("Alert name '{}' is shared by {} rules", name, count);
         }
     }
+    // Rules are split into a config per `--split-by-label` value, or
+    // `--split-by-dir` subdirectory, and then a group per `--group-by-label`
+    // value. Rules without a value, which is all of them if the options aren't
+    // given, go in the default config or group. They sort first.
+    let mut rules_by_label_values: BTreeMap<
+        Option<String>,
+        BTreeMap<Option<String>, Vec<PrometheusRule>>,
+    > = BTreeMap::new();
+    for ((rule, group_value), split_value) in absent_alert_rules
+        .into_iter()
+        .zip(rule_group_label_values)
+        .zip(rule_split_label_values)
+    {
+        rules_by_label_values
+            .entry(split_value)
+            .or_insert_with(BTreeMap::new)
+            .entry(group_value)
+            .or_insert_with(Vec::new)
+            .push(rule);
+    }
+    // Still write out the default group if there are no rules at all.
+    if rules_by_label_values.is_empty() {
+        let mut rules_by_group_label_value = BTreeMap::new();
+        rules_by_group_label_value.insert(None, vec![]);
+        rules_by_label_values.insert(None, rules_by_group_label_value);
+    }
+    Ok(rules_by_label_values
+        .into_iter()
+        .map(|(split_value, rules_by_group_label_value)| {
+            let groups = rules_by_group_label_value
+                .into_iter()
+                .map(|(value, rules)| PrometheusRuleGroup {
+                    name: match (&opts.group_by_label, value) {
+                        (Some(key), Some(value)) => format!("absent_{}_{}", key, value),
+                        _ => opts
+                            .group_name
+                            .clone()
+                            .unwrap_or_else(|| "absent_label_alerts".into()),
+                    },
+                    rules,
+                    limit: opts.group_limit,
+                    labels: BTreeMap::new(),
+                    query_offset: opts.query_offset.map(|offset| offset.to_string()),
+                    extra_fields: opts
+                        .group_meta
+                        .iter()
+                        .map(|(key, value)| (key.clone(), Value::String(value.clone())))
+                        .collect(),
+                })
+                .collect();
+            (split_value, PrometheusRulesConfig { groups })
+        })
+        .collect())
 }
 
-fn load_rules_from_file<P: AsRef<Path>>(rules_path: P) -> Result<PrometheusRulesConfig> {
-    let content = fs::read_to_string(rules_path)?;
-    let config = serde_yaml::from_str(&content)?;
-    Ok(config)
+/// The value of the label `key` that all the rules `selectors` come from
+/// have, either set on the rule itself or inherited from its group, if they
+/// all have the same one.
+fn agreed_inherited_label(selectors: &[SelectorWithOriginRule], key: &str) -> Option<String> {
+    let values: Vec<Option<String>> = selectors
+        .iter()
+        .map(|s| {
+            s.rule
+                .untyped_fields
+                .get("labels")
+                .and_then(|labels| labels.get(key))
+                .and_then(|value| value.as_str())
+                .or_else(|| s.group_labels.get(key).map(String::as_str))
+                .map(str::to_string)
+        })
+        .unique()
+        .collect();
+    match values.as_slice() {
+        [Some(value)] => Some(value.clone()),
+        _ => None,
+    }
 }
 
-/// Parse the provided command line options into [Opts].
-fn parse_options() -> Result<Opts> {
-    let mut args = pico_args::Arguments::from_env();
-    if args.contains(["-h", "--help"]) {
-        println!("{}", USAGE);
-        std::process::exit(1);
+/// The `--split-by-label` value, or `--split-by-dir` subdirectory, of the rule
+/// `selector` would get on its own.
+fn split_value(selector: &SelectorWithOriginRule, opts: &Opts) -> Option<String> {
+    if opts.split_by_dir {
+        rules_subdir(&selector.source_file, &opts.rules_dirs)
+    } else {
+        opts.split_by_label
+            .as_ref()
+            .and_then(|key| agreed_inherited_label(std::slice::from_ref(selector), key))
     }
-    let dry_run = args.contains("--dry-run");
-    let maybe_output_file: Option<PathBuf> = args.opt_value_from_str("--output-file")?;
-    let ignore_file: PathBuf = args
-        .opt_value_from_str("--ignore-file")?
-        .unwrap_or_else(|| {
-            let mut path = PathBuf::new();
-            path = path.join(env!("CARGO_MANIFEST_DIR"));
-            path.join("ignore_metrics.txt")
-        });
-    let playbook_link = args.opt_value_from_str("--playbook-link")?;
-    let rules_dir: PathBuf = args.free_from_str()?;
-    let opts = Opts {
-        dry_run,
-        output_file: maybe_output_file.unwrap_or_else(|| rules_dir.join("absent.rules.yml")),
-        rules_dir,
-        ignore_file,
-        playbook_link,
-    };
-    let remaining = args.finish();
-    if !remaining.is_empty() {
-        log::warn!("Ignoring junk: {:?}", remaining);
+}
+
+/// The value of the label `key` to copy to the rule for `selectors` with
+/// `--inherit-label`, from the rules they come from or their groups. If they
+/// don't agree the first value alphabetically is used so it's the same from
+/// run to run.
+fn inherited_label(selectors: &[SelectorWithOriginRule], key: &str) -> Option<String> {
+    let values: Vec<&str> = selectors
+        .iter()
+        .filter_map(|s| {
+            s.rule
+                .untyped_fields
+                .get("labels")
+                .and_then(|labels| labels.get(key))
+                .and_then(|value| value.as_str())
+                .or_else(|| s.group_labels.get(key).map(String::as_str))
+        })
+        .sorted()
+        .dedup()
+        .collect();
+    if values.len() > 1 {
+        log::warn!(
+            "The rules using '{}' have different values for the '{}' label, {:?}, using '{}'",
+            selectors[0].selector,
+            key,
+            values,
+            values[0]
+        );
     }
-    Ok(opts)
+    values.first().map(|value| value.to_string())
 }
 
-/// Wrap the given [prometheus_parser::Expression] in the applicable absent
-/// function.
-///
-/// Prometheus has two functions in the absent family, `absent` and
-/// `absent_over_time`
-/// (https://prometheus.io/docs/prometheus/latest/querying/functions/#absent).
-/// `absent` expects an instant-vector selector and `absent_over_time` expects a
-/// range-vector selector. We can easily differentiate between the two in
-/// [prometheus_parser]'s AST because the [prometheus_parser::Selector] struct
-/// will have a `range` if it is a range-vector selector and otherwise it's an
-/// instant-vector.
-fn wrap_selector_in_absent(selector: &prometheus_parser::Selector) -> prometheus_parser::Function {
-    let function_name = if selector.range.is_some() {
-        "absent_over_time"
-    } else {
-        "absent"
+/// The top-level subdirectory of whichever of `rules_dirs` `source_file` is
+/// in, for `--split-by-dir`. Files directly in a rules directory, or not in
+/// any of them, don't have one.
+fn rules_subdir(source_file: &Path, rules_dirs: &[PathBuf]) -> Option<String> {
+    rules_dirs.iter().find_map(|rules_dir| {
+        let mut components = source_file.strip_prefix(rules_dir).ok()?.components();
+        let subdir = components.next()?;
+        // The file itself is the last component, so there has to be another
+        // after the subdirectory.
+        components.next()?;
+        Some(subdir.as_os_str().to_string_lossy().into_owned())
+    })
+}
+
+/// The name of the `--output-dir` file for the rules whose `--split-by-label`
+/// value, or `--split-by-dir` subdirectory, is `value`. Anything that isn't
+/// safe in a file name is replaced with an underscore.
+fn split_output_file_name(value: Option<&str>) -> String {
+    let stem: String = match value {
+        Some(value) => value
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect(),
+        None => "unowned".to_string(),
     };
-    prometheus_parser::Function::new(function_name).arg(selector.clone().wrap())
+    format!("{}.rules.yml", stem)
 }
 
-/// Converting a BTreeMap to a serde_yaml::Value turns out to be a massive pain.
-/// The best I could find is converting it to an intermediate Mapping here. You
-/// can't convert a BTreeMap directly to a mapping, instead you need an Iterator
-/// with an Item type of (Value, Value). Hence the shenanigans below.
-fn btree_to_yaml_mapping<K: Into<Value> + Clone, V: Into<Value> + Clone>(
-    btree: BTreeMap<K, V>,
-) -> serde_yaml::Mapping {
-    btree
-        .into_iter()
-        .map(|(key, value)| -> (Value, Value) { (key.into(), value.into()) })
-        .collect()
+/// The value of the `key` equality matcher that every one of `selectors` has,
+/// if they all have the same one.
+fn agreed_selector_label(selectors: &[SelectorWithOriginRule], key: &str) -> Option<String> {
+    let values: Vec<Option<&String>> = selectors
+        .iter()
+        .map(|s| {
+            s.selector
+                .labels
+                .iter()
+                .find(|label| label.key == key && label.op == prometheus_parser::LabelOp::Equal)
+                .map(|label| &label.value)
+        })
+        .unique()
+        .collect();
+    match values.as_slice() {
+        [Some(value)] => Some(value.to_string()),
+        _ => None,
+    }
 }
 
-#[cfg(test)]
-mod test {
+/// Load the list of selectors to ignore from `--ignore-file`, which can be a
+/// local file or an http(s) URL.
+///
+/// A missing local file just means nothing is ignored but failing to fetch a
+/// URL is an error, unless there's a copy in `--ignore-file-cache` to fall
+/// back on.
+fn load_ignore_file(opts: &Opts) -> Result<Vec<String>> {
+    let contents = match ignore_file_url(opts) {
+        Some(url) => match fetch_ignore_file(url, opts) {
+            Ok(contents) => {
+                if let Some(cache) = &opts.ignore_file_cache {
+                    if let Err(e) = fs::write(cache, &contents) {
+                        log::warn!("Failed to cache ignore file in {}: {}", cache.display(), e);
+                    }
+                }
+                contents
+            }
+            Err(e) => match &opts.ignore_file_cache {
+                Some(cache) if cache.exists() => {
+                    log::warn!(
+                        "Failed to fetch ignore file from {}, using the copy in {}: {:#}",
+                        url,
+                        cache.display(),
+                        e
+                    );
+                    fs::read_to_string(cache)
+                        .with_context(|| format!("failed to read {}", cache.display()))?
+                }
+                _ => return Err(e.context(format!("failed to fetch ignore file from {}", url))),
+            },
+        },
+        None if is_stdio(&opts.ignore_file) => {
+            let mut contents = String::new();
+            std::io::stdin()
+                .read_to_string(&mut contents)
+                .context("failed to read ignore file from stdin")?;
+            contents
+        }
+        None => fs::read_to_string(&opts.ignore_file).unwrap_or_default(),
+    };
+    Ok(parse_ignore_list(&contents, opts))
+}
+
+/// The metrics listed in the contents of an ignore file, skipping comments
+/// and blank lines.
+fn parse_ignore_list(contents: &str, opts: &Opts) -> Vec<String> {
+    let comment_prefix = opts.ignore_comment_prefix.as_deref().unwrap_or("#");
+    contents
+        .lines()
+        .map(str::trim)
+        // Blank lines and stray whitespace would otherwise ignore a metric
+        // with an empty name or one that doesn't exist.
+        .filter(|line| !line.is_empty() && !line.starts_with(comment_prefix))
+        .map(str::to_string)
+        .collect()
+}
+
+/// `--ignore-file` if it's an http(s) URL rather than a path.
+fn ignore_file_url(opts: &Opts) -> Option<&str> {
+    opts.ignore_file
+        .to_str()
+        .filter(|path| path.starts_with("http://") || path.starts_with("https://"))
+}
+
+/// A line of the ignore file.
+#[derive(Debug)]
+enum IgnoreEntry {
+    /// The whole selector, e.g. `foo{job="x"}`.
+    Exact(String),
+    /// A `regex:` line, which has to match the whole selector.
+    Regex(Regex),
+    /// A `metric:` line, which matches every selector for the metric.
+    Metric(String),
+}
+
+impl IgnoreEntry {
+    /// Whether `selector` is ignored by this entry.
+    fn matches(&self, selector: &SelectorWithOriginRule) -> bool {
+        match self {
+            // Ignore files written before label order stopped mattering list
+            // selectors as they were written.
+            IgnoreEntry::Exact(ignored) => {
+                *ignored == selector.sort_key() || *ignored == selector.written_key()
+            }
+            IgnoreEntry::Regex(regex) => regex.is_match(&selector.sort_key()),
+            IgnoreEntry::Metric(metric) => selector.selector.metric.as_ref() == Some(metric),
+        }
+    }
+}
+
+/// Work out what each of the `lines` from the ignore file ignores.
+fn parse_ignore_entries(lines: &[String]) -> Result<Vec<IgnoreEntry>> {
+    lines
+        .iter()
+        .map(|line| {
+            if let Some(pattern) = line.strip_prefix("regex:") {
+                Regex::new(&format!("^(?:{})$", pattern.trim()))
+                    .map(IgnoreEntry::Regex)
+                    .with_context(|| format!("Invalid regex in ignore file line '{}'", line))
+            } else if let Some(metric) = line.strip_prefix("metric:") {
+                Ok(IgnoreEntry::Metric(metric.trim().to_string()))
+            } else {
+                Ok(IgnoreEntry::Exact(line.clone()))
+            }
+        })
+        .collect()
+}
+
+fn fetch_ignore_file(url: &str, opts: &Opts) -> Result<String> {
+    let timeout = std::time::Duration::from_secs(opts.ignore_file_timeout.unwrap_or(10));
+    Ok(ureq::get(url).timeout(timeout).call()?.into_string()?)
+}
+
+/// Whether `path` is `-`, which means stdin or stdout, like most Unix tools.
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// The glob matching the rules files in each rules directory, relative to the
+/// directory.
+fn rules_dir_glob(opts: &Opts) -> &str {
+    opts.glob.as_deref().unwrap_or("**/*.rules.yml")
+}
+
+/// Warn if `output_file` is somewhere under `rules_dir` but won't be matched by
+/// the `--glob`, `**/*.rules.yml` by default.
+///
+/// Such a file isn't re-read on the next run, which is fine, but it's probably
+/// not loaded by Prometheus either if that uses the same naming convention, and
+/// if the name changes between runs the old file is left lying around.
+fn warn_if_output_file_is_unmatched_in_rules_dir(
+    rules_dir: &Path,
+    output_file: &Path,
+    opts: &Opts,
+) {
+    if is_output_file_unmatched_in_rules_dir(rules_dir, output_file, opts) {
+        log::warn!(
+            "Output file {} is inside the rules directory {} but isn't matched by {}. \
+            Prometheus may not load it and it won't be cleaned up if the name changes. \
+            Consider naming it something like absent.rules.yml.",
+            output_file.display(),
+            rules_dir.display(),
+            rules_dir_glob(opts)
+        );
+    }
+}
+
+/// Whether `output_file` is somewhere under `rules_dir` but isn't matched by
+/// the `--glob`, see [warn_if_output_file_is_unmatched_in_rules_dir].
+fn is_output_file_unmatched_in_rules_dir(
+    rules_dir: &Path,
+    output_file: &Path,
+    opts: &Opts,
+) -> bool {
+    // The output file might not exist yet so canonicalize the directory it's
+    // going in instead.
+    let canonical_output_dir = match output_file.parent() {
+        Some(parent) if parent.as_os_str().is_empty() => fs::canonicalize("."),
+        Some(parent) => fs::canonicalize(parent),
+        None => return false,
+    };
+    let (canonical_output_dir, canonical_rules_dir) =
+        match (canonical_output_dir, fs::canonicalize(rules_dir)) {
+            (Ok(output_dir), Ok(rules_dir)) => (output_dir, rules_dir),
+            // Something else will complain if these don't exist.
+            _ => return false,
+        };
+    let relative_output_file = match (
+        canonical_output_dir.strip_prefix(&canonical_rules_dir),
+        output_file.file_name(),
+    ) {
+        (Ok(relative_output_dir), Some(name)) => relative_output_dir.join(name),
+        _ => return false,
+    };
+    let matched_by_glob = glob::Pattern::new(rules_dir_glob(opts))
+        .map(|pattern| pattern.matches_path(&relative_output_file))
+        .unwrap_or(false);
+    !matched_by_glob
+}
+
+/// Sanitising selectors into alert names is lossy, e.g. `slo=~"L1|L2"` and
+/// `slo=~"L1_L2"` both end up as `slo_regexequal_L1_L2`. Add a short hash of
+/// the `selector_keys`, one per rule, to the names of the rules whose name is
+/// shared with a rule for different selectors so they can be told apart.
+/// Names that aren't shared, which is almost all of them, are left as they
+/// are, as are names that are only shared by rules for the same selectors,
+/// e.g. with `--dedup-scope group`.
+fn disambiguate_alert_names(rules: &mut [PrometheusRule], selector_keys: &[String]) {
+    let mut selector_keys_by_name: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for (rule, selector_key) in rules.iter().zip(selector_keys) {
+        if let Some(name) = rule.untyped_fields.get("alert").and_then(Value::as_str) {
+            selector_keys_by_name
+                .entry(name.to_string())
+                .or_insert_with(Vec::new)
+                .push(selector_key);
+        }
+    }
+    for (rule, selector_key) in rules.iter_mut().zip(selector_keys) {
+        let name = match rule.untyped_fields.get("alert").and_then(Value::as_str) {
+            Some(name) if selector_keys_by_name[name].iter().unique().count() > 1 => {
+                name.to_string()
+            }
+            _ => continue,
+        };
+        let disambiguated_name = format!("{}_{}", name, short_hash(selector_key));
+        log::debug!(
+            "Renaming '{}' for '{}' to '{}' as its name collides with another rule's",
+            name,
+            selector_key,
+            disambiguated_name
+        );
+        rule.untyped_fields
+            .insert("alert".to_string(), Value::String(disambiguated_name));
+    }
+}
+
+/// The shortest `--max-name-length`, which leaves room for the first character
+/// of the name, an underscore, and the hash.
+const MIN_MAX_NAME_LENGTH: usize = 10;
+
+/// Cut the names of `rules` longer than `max_length` characters short, see
+/// [truncate_alert_name]. It's an error if that gives rules with different
+/// names the same name.
+fn truncate_alert_names(rules: &mut [PrometheusRule], max_length: usize) -> Result<()> {
+    let mut names_by_truncated_name: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for rule in rules.iter_mut() {
+        let name = match rule.untyped_fields.get("alert").and_then(Value::as_str) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let truncated_name = truncate_alert_name(&name, max_length);
+        rule.untyped_fields
+            .insert("alert".to_string(), Value::String(truncated_name.clone()));
+        names_by_truncated_name
+            .entry(truncated_name)
+            .or_insert_with(Vec::new)
+            .push(name);
+    }
+    for (truncated_name, names) in names_by_truncated_name {
+        let names: Vec<String> = names.into_iter().unique().collect();
+        ensure!(
+            names.len() == 1,
+            "The alert names {} are all truncated to '{}', try a longer --max-name-length",
+            names.join(", "),
+            truncated_name
+        );
+    }
+    Ok(())
+}
+
+/// Cut `name` short to `max_length` characters, which must be at least
+/// [MIN_MAX_NAME_LENGTH], if it's longer. The end of it is replaced with a
+/// short hash of the whole name so names that start the same still end up
+/// different. The start of the name is always kept so it still starts with a
+/// character that's allowed at the start.
+fn truncate_alert_name(name: &str, max_length: usize) -> String {
+    if name.chars().count() <= max_length {
+        return name.to_string();
+    }
+    let hash = short_hash(name);
+    let kept: String = name.chars().take(max_length - hash.len() - 1).collect();
+    // Avoid a double underscore before the hash, unless that's all there is.
+    let kept = match kept.trim_end_matches('_') {
+        "" => kept.as_str(),
+        trimmed => trimmed,
+    };
+    format!("{}_{}", kept, hash)
+}
+
+/// Find the alert names that are used by more than one of `rules`, along with
+/// how many rules use them.
+fn find_duplicate_alert_names(rules: &[PrometheusRule]) -> BTreeMap<String, usize> {
+    rules
+        .iter()
+        .filter_map(|rule| rule.untyped_fields.get("alert")?.as_str())
+        .counts()
+        .into_iter()
+        .filter(|(_name, count)| *count > 1)
+        .map(|(name, count)| (name.to_string(), count))
+        .collect()
+}
+
+/// Ask `has_data`, usually [metric_has_data], about each unique metric in
+/// `grouped_selectors` and return the metrics that have no data right now.
+///
+/// Generating an absent rule for something that is already absent would page
+/// straight away so these are the ones we want to leave out (or flag). If a
+/// query fails we can't say either way so the metric is treated as having data,
+/// i.e. we fall back to emitting the rule as normal.
+fn find_currently_absent_metrics<F: Fn(&str) -> Result<bool>>(
+    grouped_selectors: &[(String, Vec<SelectorWithOriginRule>)],
+    has_data: F,
+) -> Vec<String> {
+    grouped_selectors
+        .iter()
+        .filter_map(|(_selector, selectors)| selectors.first()?.selector.metric.clone())
+        .unique()
+        .filter(|metric| match has_data(metric) {
+            Ok(has_data) => !has_data,
+            Err(e) => {
+                log::warn!(
+                    "Failed to query Prometheus for '{}', assuming it has data: {}",
+                    metric,
+                    e
+                );
+                false
+            }
+        })
+        .collect()
+}
+
+/// Label `rule` as being for a metric that has no data right now, for
+/// `--tag-currently-absent`.
+fn tag_currently_absent(rule: &mut PrometheusRule) {
+    if let Value::Mapping(labels) = rule
+        .untyped_fields
+        .entry("labels".into())
+        .or_insert_with(|| Value::Mapping(serde_yaml::Mapping::new()))
+    {
+        labels.insert("currently_absent".into(), "true".into());
+    }
+}
+
+/// How long to wait for each query to `--prometheus-url`.
+fn prometheus_timeout(opts: &Opts) -> Duration {
+    Duration::from_secs(opts.prometheus_timeout.unwrap_or(10))
+}
+
+/// Split each group of selectors into one group per value `label` has for them
+/// in Prometheus right now, adding a `label="value"` matcher to each selector.
+///
+/// A single absent rule won't notice one instance of a metric disappearing but
+/// one per instance will. Groups whose selectors already match on `label`, or
+/// whose values we can't get, are left as they are.
+fn expand_selectors_by_label(
+    grouped_selectors: Vec<(String, Vec<SelectorWithOriginRule>)>,
+    prometheus_url: &str,
+    label: &str,
+    opts: &Opts,
+) -> Vec<(String, Vec<SelectorWithOriginRule>)> {
+    grouped_selectors
+        .into_iter()
+        .flat_map(|(key, selectors)| {
+            let first_selector = &selectors.first().unwrap().selector;
+            if first_selector.labels.iter().any(|l| l.key == label) {
+                return vec![(key, selectors)];
+            }
+            let values = match fetch_label_values(prometheus_url, label, first_selector, opts) {
+                Ok(values) if !values.is_empty() => values,
+                Ok(_) => {
+                    log::warn!("No values of '{}' for '{}', not expanding it", label, key);
+                    return vec![(key, selectors)];
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Failed to get the values of '{}' for '{}', not expanding it: {:#}",
+                        label,
+                        key,
+                        e
+                    );
+                    return vec![(key, selectors)];
+                }
+            };
+            values
+                .into_iter()
+                .map(|value| {
+                    let expanded: Vec<SelectorWithOriginRule> = selectors
+                        .iter()
+                        .map(|s| SelectorWithOriginRule {
+                            selector: with_equal_matcher(&s.selector, label, &value),
+                            ..s.clone()
+                        })
+                        .collect();
+                    (format!("{}{{{}={:?}}}", key, label, value), expanded)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// `selector` with an extra `key="value"` matcher.
+fn with_equal_matcher(
+    selector: &prometheus_parser::Selector,
+    key: &str,
+    value: &str,
+) -> prometheus_parser::Selector {
+    let mut selector = selector.clone();
+    selector.labels.push(prometheus_parser::Label {
+        op: prometheus_parser::LabelOp::Equal,
+        key: key.to_string(),
+        value: value.to_string(),
+        span: None,
+    });
+    selector
+}
+
+/// Ask Prometheus for the values `label` has in the series matching
+/// `selector`.
+fn fetch_label_values(
+    prometheus_url: &str,
+    label: &str,
+    selector: &prometheus_parser::Selector,
+    opts: &Opts,
+) -> Result<Vec<String>> {
+    let url = format!(
+        "{}/api/v1/label/{}/values",
+        prometheus_url.trim_end_matches('/'),
+        label
+    );
+    // The series endpoints only take instant vector selectors.
+    let series_selector = prometheus_parser::Selector {
+        range: None,
+        offset: None,
+        subquery: None,
+        span: None,
+        ..selector.clone()
+    };
+    let body = ureq::get(&url)
+        .timeout(prometheus_timeout(opts))
+        .query("match[]", &series_selector.to_string())
+        .call()?
+        .into_string()?;
+    let response: PrometheusLabelValuesResponse = serde_json::from_str(&body)?;
+    ensure!(
+        response.status == "success",
+        "Query returned status '{}': {}",
+        response.status,
+        response.error.unwrap_or_default()
+    );
+    Ok(response.data.unwrap_or_default())
+}
+
+/// Run an instant query against the Prometheus HTTP API to see if there is any
+/// data for `metric` right now.
+fn metric_has_data(prometheus_url: &str, metric: &str, opts: &Opts) -> Result<bool> {
+    let url = format!("{}/api/v1/query", prometheus_url.trim_end_matches('/'));
+    let body = ureq::get(&url)
+        .timeout(prometheus_timeout(opts))
+        .query("query", &format!("count({})", metric))
+        .call()?
+        .into_string()?;
+    let response: PrometheusQueryResponse = serde_json::from_str(&body)?;
+    ensure!(
+        response.status == "success",
+        "Query returned status '{}': {}",
+        response.status,
+        response.error.unwrap_or_default()
+    );
+    Ok(response
+        .data
+        .map(|data| !data.result.is_empty())
+        .unwrap_or(false))
+}
+
+/// Merge the given [Selector]s into a [PrometheusRule].
+///
+/// This is where the logic for adopting certain attributes from the selector
+/// origin rules is contained. Currently we do this for the "for" field, where
+/// we take the smallest "for" then use it or the floor, whichever is larger.
+/// The floor is `--min-for`, 1h by default, unless the groups, severity or
+/// `--heuristic-for` set one. If none of the origin rules have a "for" and
+/// `--for-from-range` is set then the range of a range-vector selector is used
+/// instead, again with the same floor.
+///
+/// With `--combine-variants` the selectors can differ (but share a metric) and
+/// the rule's expression is the absent expressions for each of them joined
+/// with `or`.
+fn merge_selectors_into_rule(
+    selectors: &[SelectorWithOriginRule],
+    opts: &Opts,
+    metric_help: Option<&str>,
+) -> PrometheusRule {
+    let first_selector = &representative_selector(selectors, opts);
+    // With --sustained-window or --instant-absent-over-time instant selectors
+    // only count as absent once they've been absent for the whole window. The
+    // name and selector_expr stay as they were so that turning it on doesn't
+    // rename every rule.
+    let sustained = |selector: &prometheus_parser::Selector| {
+        let mut selector = selector.clone();
+        if selector.range.is_none() {
+            selector.range = opts.sustained_window.or(opts.instant_absent_over_time);
+        }
+        selector
+    };
+    let name_prefix = opts.name_prefix.as_deref().unwrap_or("absent");
+    let (name, expr, selector_expr) = if opts.combine_variants || opts.merge_label_values {
+        let mut variants: Vec<prometheus_parser::Selector> = selectors
+            .iter()
+            .unique_by(|s| s.sort_key())
+            .sorted_by_key(|s| s.sort_key())
+            .map(|s| {
+                if opts.normalize_exprs || label_orders_differ(selectors) {
+                    sort_selector_labels(&s.selector)
+                } else {
+                    s.selector.clone()
+                }
+            })
+            // Variants that only differed by label order are now the same.
+            .unique_by(|selector| selector.to_string())
+            .collect();
+        if opts.merge_label_values {
+            if let Some(merged) = merge_label_values(&variants) {
+                variants = vec![merged];
+            }
+        }
+        let expr = variants
+            .iter()
+            .map(|selector| wrap_selector_in_absent(&sustained(selector)).to_string())
+            .join(" or ");
+        if let Err(e) = prometheus_parser::parse_expr(&expr) {
+            log::error!("Combined expression '{}' is invalid: {}", expr, e);
+        }
+        (
+            build_absent_selector_alert_name(
+                &strip_selector_to_metric(first_selector),
+                name_prefix,
+            ),
+            expr,
+            variants.iter().join(" or "),
+        )
+    } else {
+        (
+            build_absent_selector_alert_name(first_selector, name_prefix),
+            wrap_selector_in_absent(&sustained(first_selector)).to_string(),
+            first_selector.to_string(),
+        )
+    };
+    let expr = match &opts.expr_wrapper {
+        Some(expr_wrapper) => {
+            let wrapped = expr_wrapper.replace("{absent}", &expr);
+            if let Err(e) = prometheus_parser::parse_expr(&wrapped) {
+                log::error!("Wrapped expression '{}' is invalid: {}", wrapped, e);
+            }
+            wrapped
+        }
+        None => expr,
+    };
+    let shortest_for = selectors
+        .iter()
+        .flat_map(|s| {
+            s.rule
+                .untyped_fields
+                .get("for")
+                .and_then(|val| match parse_rule_for(val, opts) {
+                    Ok(duration) => Some(duration),
+                    Err(e) => {
+                        log::error!("Invalid 'for' field: {:#}", e);
+                        None
+                    }
+                })
+        })
+        .min();
+    let severity_label_key = opts.severity_label_key.as_deref().unwrap_or("severity");
+    let mut labels: BTreeMap<String, String> = default_severity(opts)
+        .map(|severity| btree_map! { severity_label_key => severity })
+        .unwrap_or_default();
+    // Base labels can override the defaults but anything after this, which is
+    // specific to the rule, overrides them.
+    labels.extend(opts.base_labels.clone());
+    labels.extend(opts.labels.clone());
+    for key in &opts.inherit_labels {
+        if let Some(value) = inherited_label(selectors, key) {
+            labels.insert(key.clone(), value);
+        }
+    }
+    if let Some(severity) = agreed_group_label(selectors, "absent_severity") {
+        labels.insert(severity_label_key.to_string(), severity.to_string());
+    }
+    if opts.candidate {
+        let severity = opts.candidate_severity.as_deref().unwrap_or("info");
+        labels.insert(severity_label_key.to_string(), severity.to_string());
+        labels.insert("candidate".to_string(), "true".to_string());
+    }
+    if let Some(expr_label) = &opts.expr_label {
+        labels.insert(expr_label.clone(), sanitize_label_value(&selector_expr));
+    }
+    if let Some(playbook_link) = &opts.playbook_link {
+        labels.insert("playbook".to_string(), playbook_link.clone());
+    }
+    // Rule authors can raise or lower the floor for their group's selectors
+    // but only if every group the selector is used in agrees.
+    let min_for = agreed_group_label(selectors, "absent_min_for")
+        .and_then(|duration| match parse_duration(duration) {
+            Ok(duration) => Some(duration),
+            Err(e) => {
+                log::error!("Invalid 'absent_min_for' group label: {:#}", e);
+                None
+            }
+        })
+        .or_else(|| {
+            if !opts.severity_for_floors {
+                return None;
+            }
+            let severity = labels.get(severity_label_key)?;
+            opts.for_by_severity
+                .get(severity)
+                .copied()
+                .or_else(|| default_for_floor(severity))
+        })
+        .or_else(|| {
+            if !opts.heuristic_for {
+                return None;
+            }
+            let floor = if is_probably_counter(first_selector.metric.as_ref()?) {
+                opts.counter_for
+                    .unwrap_or(prometheus_parser::PromDuration::Hours(2))
+            } else {
+                opts.gauge_for
+                    .unwrap_or(prometheus_parser::PromDuration::Minutes(30))
+            };
+            Some(floor)
+        })
+        .or(opts.min_for)
+        .unwrap_or(prometheus_parser::PromDuration::Hours(1));
+    let range = first_selector.range;
+    let chosen_for = match (shortest_for, range) {
+        (Some(duration), _) => max(duration, min_for),
+        (None, Some(range)) if opts.for_from_range => max(range, min_for),
+        (None, _) => min_for,
+    };
+    // The `for` of rules for instant selectors is at least the sustained
+    // window, so a rule can't fire any sooner than the window.
+    let chosen_for = match (opts.sustained_window, range) {
+        (Some(window), None) if duration_seconds(&window) > duration_seconds(&chosen_for) => window,
+        _ => chosen_for,
+    };
+    // Overrides are explicit so they beat everything else.
+    let chosen_for = first_selector
+        .metric
+        .as_ref()
+        .and_then(|metric| {
+            opts.for_overrides
+                .iter()
+                .find(|(pattern, _)| pattern.is_match(metric))
+        })
+        .map(|(_, duration)| *duration)
+        .unwrap_or(chosen_for);
+    if opts.hash_label {
+        labels.insert(
+            "rule_hash".to_string(),
+            rule_definition_hash(&selector_expr, &expr, &chosen_for),
+        );
+    }
+    let mut extra_annotations = BTreeMap::new();
+    if opts.annotate_count {
+        // Annotation values have to be strings.
+        extra_annotations.insert(
+            "referenced_by_count".to_string(),
+            selectors.len().to_string(),
+        );
+    }
+    if opts.annotate_source {
+        // Selectors from --extra-metrics don't come from a rules file.
+        let source_files = selectors
+            .iter()
+            .filter(|s| !s.source_file.as_os_str().is_empty())
+            .map(|s| s.source_file.display().to_string())
+            .sorted()
+            .dedup()
+            .join(", ");
+        if !source_files.is_empty() {
+            extra_annotations.insert("source_file".to_string(), source_files);
+        }
+    }
+
+    let mut rule: PrometheusRule = PrometheusAbsentSelectorAlertRule {
+        name,
+        expr,
+        selector_expr,
+        r#for: chosen_for,
+        labels,
+        extra_annotations,
+        metric_help: metric_help.map(str::to_string),
+        summary_template: opts.summary_template.clone(),
+        description_template: opts.description_template.clone(),
+        origin_rules: if opts.rich_description {
+            describe_origin_rules(selectors)
+        } else {
+            vec![]
+        },
+    }
+    .into();
+    // Metrics we record get their rule in `--records-output-file` even if
+    // they're used in expressions too.
+    if selectors.iter().any(|s| s.origin == SelectorOrigin::Record) {
+        rule.origin = SelectorOrigin::Record;
+    }
+    rule
+}
+
+/// The most origin rules we'll list in a description. Some selectors are used by
+/// hundreds of rules and nobody wants to scroll through that in a notification.
+const MAX_DESCRIBED_ORIGIN_RULES: usize = 10;
+
+/// Describe the distinct rules `selectors` come from, and the files they're
+/// in, for `--rich-description`. Only the first [MAX_DESCRIBED_ORIGIN_RULES]
+/// are listed, followed by how many more there are.
+fn describe_origin_rules(selectors: &[SelectorWithOriginRule]) -> Vec<String> {
+    let origin_rules: Vec<String> = selectors
+        .iter()
+        .map(|s| {
+            let name = s.rule.name().unwrap_or(&s.rule.expr);
+            if s.source_file.as_os_str().is_empty() {
+                format!("'{}'", name)
+            } else {
+                format!("'{}' in {}", name, s.source_file.display())
+            }
+        })
+        .unique()
+        .collect();
+    if origin_rules.len() <= MAX_DESCRIBED_ORIGIN_RULES {
+        return origin_rules;
+    }
+    let remaining = origin_rules.len() - MAX_DESCRIBED_ORIGIN_RULES;
+    origin_rules
+        .into_iter()
+        .take(MAX_DESCRIBED_ORIGIN_RULES)
+        .chain(std::iter::once(format!("{} more", remaining)))
+        .collect()
+}
+
+/// Built-in minimum `for` of rules with `severity` for `--severity-for-floors`.
+/// Anything that pages someone straight away gets a shorter floor so it's
+/// noticed sooner, everything else keeps the usual 1h.
+fn default_for_floor(severity: &str) -> Option<prometheus_parser::PromDuration> {
+    match severity {
+        "page" | "high_urgency_page" | "critical" => {
+            Some(prometheus_parser::PromDuration::Minutes(15))
+        }
+        "low_urgency_page" | "business_hours" | "warning" | "info" => {
+            Some(prometheus_parser::PromDuration::Hours(1))
+        }
+        _ => None,
+    }
+}
+
+/// Guess whether `metric` is a counter from the naming conventions in
+/// https://prometheus.io/docs/practices/naming/. Counters, and the counters
+/// that make up summaries and histograms, end in `_total`, `_count`, `_sum`,
+/// or `_bucket`. Anything else is assumed to be a gauge.
+fn is_probably_counter(metric: &str) -> bool {
+    ["_total", "_count", "_sum", "_bucket"]
+        .iter()
+        .any(|suffix| metric.ends_with(suffix))
+}
+
+/// Parse `--for-by-severity`'s comma separated `severity=duration` pairs.
+fn parse_for_by_severity(
+    mapping: &str,
+) -> Result<BTreeMap<String, prometheus_parser::PromDuration>> {
+    mapping
+        .split(',')
+        .map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(severity), Some(duration)) if !severity.trim().is_empty() => Ok((
+                    severity.trim().to_string(),
+                    parse_duration(duration.trim())?,
+                )),
+                _ => Err(anyhow!(
+                    "Expected severity=duration in --for-by-severity, found '{}'",
+                    pair
+                )),
+            }
+        })
+        .collect()
+}
+
+/// Parse a `--group-meta` key=value pair. The fields we set ourselves can't be
+/// overridden this way.
+fn parse_group_meta(pair: &str) -> Result<(String, String)> {
+    let mut parts = pair.splitn(2, '=');
+    match (parts.next().map(str::trim), parts.next()) {
+        (Some(key), Some(value)) if !key.is_empty() => {
+            ensure!(
+                !["name", "rules", "limit", "labels", "query_offset"].contains(&key),
+                "--group-meta can't set the group's '{}', it's set by the generator",
+                key
+            );
+            Ok((key.to_string(), value.trim().to_string()))
+        }
+        _ => Err(anyhow!(
+            "Expected key=value in --group-meta, found '{}'",
+            pair
+        )),
+    }
+}
+
+/// Parse a `--label` key=value pair.
+fn parse_label(pair: &str) -> Result<(String, String)> {
+    let mut parts = pair.splitn(2, '=');
+    match (parts.next().map(str::trim), parts.next()) {
+        (Some(key), Some(value)) if !key.is_empty() => {
+            ensure!(
+                Regex::new("^[a-zA-Z_][a-zA-Z0-9_]*$")
+                    .expect("invalid regex")
+                    .is_match(key),
+                "'{}' in --label isn't a valid label name",
+                key
+            );
+            Ok((key.to_string(), value.trim().to_string()))
+        }
+        _ => Err(anyhow!("Expected key=value in --label, found '{}'", pair)),
+    }
+}
+
+/// The value of the group label `key` if the origin groups of all the
+/// `selectors` have it set to the same thing.
+fn agreed_group_label<'a>(selectors: &'a [SelectorWithOriginRule], key: &str) -> Option<&'a str> {
+    let values: Vec<Option<&String>> = selectors
+        .iter()
+        .map(|s| s.group_labels.get(key))
+        .unique()
+        .collect();
+    match values.as_slice() {
+        [Some(value)] => Some(value.as_str()),
+        _ => None,
+    }
+}
+
+/// The selector to build the rule for `selectors` from. They're normally all the
+/// same but with `--ignore-range` or `--prefer-range` they can have different
+/// ranges, in which case the range is picked with `--range-collapse-strategy`.
+/// With `--prefer-instant` any instant vector wins.
+fn representative_selector(
+    selectors: &[SelectorWithOriginRule],
+    opts: &Opts,
+) -> prometheus_parser::Selector {
+    let mut selector = selectors.first().unwrap().selector.clone();
+    if opts.normalize_exprs || label_orders_differ(selectors) {
+        selector = sort_selector_labels(&selector);
+    }
+    let has_instant = selectors.iter().any(|s| s.selector.range.is_none());
+    if opts.mixed_vector_preference == MixedVectorPreference::Instant && has_instant {
+        selector.range = None;
+    } else if opts.ignore_range || opts.mixed_vector_preference == MixedVectorPreference::Range {
+        let ranges = selectors.iter().filter_map(|s| s.selector.range);
+        selector.range = match opts.range_collapse_strategy {
+            RangeCollapseStrategy::Longest => ranges.max_by_key(duration_seconds),
+            RangeCollapseStrategy::Shortest => ranges.min_by_key(duration_seconds),
+        };
+    }
+    selector
+}
+
+/// For `--merge-label-values`, if `variants` only differ by the value of one
+/// equality matcher, e.g. `foo{env="a"}` and `foo{env="b"}`, a single selector
+/// that matches all of them with a regex, e.g. `foo{env=~"a|b"}`.
+fn merge_label_values(
+    variants: &[prometheus_parser::Selector],
+) -> Option<prometheus_parser::Selector> {
+    let first = variants.first()?;
+    if variants.len() < 2 {
+        return None;
+    }
+    let sorted_labels = |selector: &prometheus_parser::Selector| {
+        selector
+            .labels
+            .iter()
+            .sorted_by(|left, right| left.key.cmp(&right.key))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    let first_labels = sorted_labels(first);
+    let mut differing_key: Option<&str> = None;
+    for variant in &variants[1..] {
+        // Everything apart from the label values has to be the same.
+        let without_labels = |selector: &prometheus_parser::Selector| prometheus_parser::Selector {
+            labels: vec![],
+            span: None,
+            ..selector.clone()
+        };
+        if without_labels(variant) != without_labels(first) {
+            return None;
+        }
+        let labels = sorted_labels(variant);
+        if labels.len() != first_labels.len() {
+            return None;
+        }
+        for (label, first_label) in labels.iter().zip(&first_labels) {
+            if label.key != first_label.key || label.op != first_label.op {
+                return None;
+            }
+            if label.value != first_label.value {
+                if label.op != prometheus_parser::LabelOp::Equal {
+                    return None;
+                }
+                match differing_key {
+                    Some(key) if key != label.key => return None,
+                    _ => differing_key = Some(&first_label.key),
+                }
+            }
+        }
+    }
+    let key = differing_key?;
+    // Values are as they're written in the rules, so any backslashes are
+    // string escapes that would have to be undone before escaping the value
+    // for the regex. Those are rare enough to leave alone.
+    if variants
+        .iter()
+        .flat_map(|variant| &variant.labels)
+        .any(|label| label.key == key && label.value.contains('\\'))
+    {
+        return None;
+    }
+    let values = variants
+        .iter()
+        .flat_map(|variant| &variant.labels)
+        .filter(|label| label.key == key)
+        // Label values are written out as they are, inside double quotes, so
+        // the backslashes escaping regex metacharacters have to be escaped
+        // themselves, otherwise `b\.prod` would be an invalid string escape.
+        .map(|label| regex::escape(&label.value).replace('\\', "\\\\"))
+        .unique()
+        .sorted()
+        .join("|");
+    let mut merged = first.clone();
+    for label in merged.labels.iter_mut().filter(|label| label.key == key) {
+        label.op = prometheus_parser::LabelOp::RegexEqual;
+        label.value = values.clone();
+    }
+    Some(merged)
+}
+
+/// Whether any of `selectors` are the same apart from the order of their label
+/// matchers. If so they're all written with sorted matchers so the generated
+/// rule doesn't depend on which order was seen first.
+fn label_orders_differ(selectors: &[SelectorWithOriginRule]) -> bool {
+    selectors
+        .iter()
+        .map(|s| (s.sort_key(), s.written_key()))
+        .unique()
+        .map(|(sort_key, _)| sort_key)
+        .duplicates()
+        .next()
+        .is_some()
+}
+
+/// `selector` with its label matchers sorted by label name, then operator and
+/// value, for `--normalize-exprs` and selectors used with their matchers in
+/// different orders.
+fn sort_selector_labels(selector: &prometheus_parser::Selector) -> prometheus_parser::Selector {
+    let mut selector = selector.clone();
+    selector.labels.sort_by(|left, right| {
+        (&left.key, left.op.to_string(), &left.value).cmp(&(
+            &right.key,
+            right.op.to_string(),
+            &right.value,
+        ))
+    });
+    selector
+}
+
+/// Ratio of the longest to the shortest `for` of the rules `selectors` come
+/// from. [None] if fewer than two of them have a valid `for`.
+fn for_divergence(selectors: &[SelectorWithOriginRule]) -> Option<f64> {
+    let seconds: Vec<u64> = selectors
+        .iter()
+        .filter_map(|s| s.rule.untyped_fields.get("for"))
+        .filter_map(|val| val.as_str())
+        .filter_map(|duration| parse_duration(duration).ok())
+        .map(|duration| duration_seconds(&duration))
+        .collect();
+    if seconds.len() < 2 {
+        return None;
+    }
+    let shortest = *seconds.iter().min()?;
+    let longest = *seconds.iter().max()?;
+    Some(longest as f64 / max(shortest, 1) as f64)
+}
+
+/// Length of `duration` in seconds, treating a year as 365 days like
+/// Prometheus does.
+fn duration_seconds(duration: &prometheus_parser::PromDuration) -> u64 {
+    use prometheus_parser::PromDuration;
+    match *duration {
+        PromDuration::Seconds(n) => n,
+        PromDuration::Minutes(n) => n * 60,
+        PromDuration::Hours(n) => n * 60 * 60,
+        PromDuration::Days(n) => n * 60 * 60 * 24,
+        PromDuration::Weeks(n) => n * 60 * 60 * 24 * 7,
+        PromDuration::Years(n) => n * 60 * 60 * 24 * 365,
+    }
+}
+
+/// Parse the `for` field of a rule. A plain number, e.g. `for: 300`, isn't a
+/// valid duration but it's an easy mistake to make, so it either gets the
+/// `--numeric-for-unit` or an error saying it needs a unit.
+fn parse_rule_for(value: &Value, opts: &Opts) -> Result<prometheus_parser::PromDuration> {
+    let number = match value {
+        Value::Number(number) => Some(number.to_string()),
+        Value::String(s) if !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) => {
+            Some(s.clone())
+        }
+        _ => None,
+    };
+    match (number, &opts.numeric_for_unit) {
+        (Some(number), Some(unit)) => {
+            log::warn!(
+                "'for' of {} has no unit, treating it as {}{}",
+                number,
+                number,
+                unit
+            );
+            parse_duration(&format!("{}{}", number, unit))
+        }
+        (Some(number), None) => Err(anyhow!(
+            "'for' of {} has no unit, e.g. {}s, use --numeric-for-unit to give it one",
+            number,
+            number
+        )),
+        (None, _) => parse_duration(
+            value
+                .as_str()
+                .ok_or_else(|| anyhow!("expected a duration, found {:?}", value))?,
+        ),
+    }
+}
+
+/// Parse a duration like `5m` into a [prometheus_parser::PromDuration]. Only
+/// single unit durations are supported.
+fn parse_duration(duration: &str) -> Result<prometheus_parser::PromDuration> {
+    let unit_start = duration.char_indices().last().map(|(i, _)| i).unwrap_or(0);
+    ensure!(
+        unit_start > 0,
+        "Malformed duration, expected at least two characters, found '{}'",
+        duration
+    );
+    let unit = &duration[unit_start..];
+    let value: u64 = duration[..unit_start]
+        .parse()
+        .with_context(|| format!("Invalid duration '{}'", duration))?;
+    prometheus_parser::PromDuration::from_pair(unit, value)
+        .map_err(|e| anyhow!("Invalid duration {}{}: {}", value, unit, e))
+}
+
+/// The longest value, in characters, that we'll put in a label we generate.
+/// Label values can technically be any length but they get copied around to
+/// every alert and notification so anything longer gets truncated.
+const MAX_LABEL_VALUE_LENGTH: usize = 200;
+
+/// Make `value` suitable for a label value by removing control characters
+/// (including the replacement character left behind by invalid UTF-8) and
+/// truncating it to [MAX_LABEL_VALUE_LENGTH], marking the truncation with
+/// "...".
+fn sanitize_label_value(value: &str) -> String {
+    let chars: Vec<char> = value
+        .chars()
+        .filter(|c| !c.is_control() && *c != std::char::REPLACEMENT_CHARACTER)
+        .collect();
+    if chars.len() <= MAX_LABEL_VALUE_LENGTH {
+        chars.into_iter().collect()
+    } else {
+        let truncated: String = chars[..MAX_LABEL_VALUE_LENGTH - 3].iter().collect();
+        truncated + "..."
+    }
+}
+
+/// A short hash of a generated rule's definition for `--hash-label`.
+fn rule_definition_hash(
+    selector_expr: &str,
+    expr: &str,
+    r#for: &prometheus_parser::PromDuration,
+) -> String {
+    short_hash(&format!("{}\n{}\n{}", selector_expr, expr, r#for))
+}
+
+/// A short hash of `text`, 8 hex characters.
+///
+/// This is a 64 bit FNV-1a hash rather than one of std's hashers because
+/// those don't promise to give the same result in different Rust versions and
+/// the whole point is getting the same hash from different runs.
+fn short_hash(text: &str) -> String {
+    let hash = text.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{:016x}", hash)[..8].to_string()
+}
+
+/// Build the alert name for a selector.
+///
+/// This takes the metric name, labels, range, and offset, and smashes them
+/// together separated by underscores and puts `prefix`, usually "absent", and
+/// an underscore in front. For complex selectors the results will _not_ be
+/// pretty but at least it'll be somewhat clear what it's for (not some random
+/// id) and will only contain allowed characters ([a-zA-Z_][a-zA-Z0-9_]*).
+fn build_absent_selector_alert_name(
+    selector: &prometheus_parser::Selector,
+    prefix: &str,
+) -> String {
+    let metric = if let Some(metric) = &selector.metric {
+        format!("_{}", metric)
+    } else {
+        // This should never happen and I think it's a problem with
+        // prometheus_parser's data model. Just log it and make the first char
+        // something that is allowed.
+        log::error!("Found selector with no metric: '{}'", selector);
+        "_".into()
+    };
+    let mut labels = selector
+        .labels
+        .iter()
+        .map(|label| {
+            // LabelOp's string repr is the symbol which isn't compatible with
+            // the allowed characters for alert names. Lets convert it to
+            // something that still has meaning but is allowed.
+            let op = match label.op {
+                prometheus_parser::LabelOp::Equal => "equal",
+                prometheus_parser::LabelOp::NotEqual => "notequal",
+                prometheus_parser::LabelOp::RegexEqual => "regexequal",
+                prometheus_parser::LabelOp::RegexNotEqual => "regexnotequal",
+            };
+            // This regex is constant so panicing on it being incorrect is okay
+            // as it would be a developer error.
+            let not_allowed_chars_re = Regex::new("[^a-zA-Z0-9_:]").expect("invalid regex");
+            let value = not_allowed_chars_re.replace_all(&label.value, "_");
+            format!("{}_{}_{}", label.key, op, value)
+        })
+        .join("_");
+    if !labels.is_empty() {
+        labels = "_".to_string() + &labels
+    }
+    let range = if let Some(range) = selector.range {
+        format!("_{}", range)
+    } else {
+        "".into()
+    };
+    let offset = if let Some(offset_duration) = selector.offset {
+        // Prometheus allows negative offsets to look into the future but `-`
+        // isn't allowed in alert names.
+        let offset_duration = offset_duration.to_string();
+        match offset_duration.strip_prefix('-') {
+            Some(duration) => format!("_offset_neg_{}", duration),
+            None => format!("_offset_{}", offset_duration),
+        }
+    } else {
+        "".into()
+    };
+    // The separator is added regardless so `NoData_` gives the same names as
+    // `NoData`.
+    let prefix = prefix.trim_end_matches('_');
+    format!("{}{}{}{}{}", prefix, metric, labels, range, offset)
+}
+
+/// The start of the comment at the top of every generated file. Also used to
+/// recognise files we generated.
+fn generated_file_marker() -> String {
+    format!(
+        "DO NOT MODIFY THIS FILE BY HAND. It was generated by {}",
+        env!("CARGO_PKG_NAME")
+    )
+}
+
+/// Write out the serializable config to the provided file with a comment header
+/// to say this generated.
+fn write_generated_config_to_file<P: AsRef<Path>, C: Serialize>(
+    path: P,
+    config: &C,
+    opts: &Opts,
+) -> Result<()> {
+    let contents = render_generated_config(config, opts)?;
+    if is_stdio(path.as_ref()) {
+        print!("{}", contents);
+        return Ok(());
+    }
+    Ok(fs::write(path, contents)?)
+}
+
+/// The contents of the generated rules file for `config`, exactly as
+/// [write_generated_config_to_file] would write it.
+fn render_generated_config<C: Serialize>(config: &C, opts: &Opts) -> Result<String> {
+    if opts.format == OutputFormat::Json {
+        let contents = serde_json::to_string_pretty(config)?;
+        return Ok(if opts.no_trailing_newline {
+            contents
+        } else {
+            format!("{}\n", contents)
+        });
+    }
+    let serialized = if opts.compact {
+        to_compact_yaml(&serde_yaml::to_value(config)?)?
+    } else {
+        serde_yaml::to_string(config)?
+    };
+    let params = if opts.embed_params {
+        describe_params(&serde_yaml::to_value(config)?, opts)
+    } else {
+        String::new()
+    };
+    let contents = format!(
+        "
+# {} in operations/tools/prometheus-absent-data-alert-rule-generator.
+{}{}",
+        generated_file_marker(),
+        params,
+        serialized
+    );
+    // Editors and formatters like files to end with exactly one newline, so
+    // give them that to avoid noisy diffs when they touch the file.
+    let contents = contents.trim_end_matches('\n');
+    Ok(if opts.no_trailing_newline {
+        contents.to_string()
+    } else {
+        format!("{}\n", contents)
+    })
+}
+
+/// The severity generated rules get unless something more specific, like
+/// `--base-labels-file` or an `absent_severity` group label, overrides it.
+fn default_severity(opts: &Opts) -> Option<&str> {
+    match &opts.severity {
+        Some(severity) => Some(severity.as_str()),
+        None if opts.no_default_labels => None,
+        None => Some("low_urgency_page"),
+    }
+}
+
+/// The severity every generated rule gets from the options alone, i.e.
+/// before any `absent_severity` group labels.
+fn options_severity(opts: &Opts) -> Option<&str> {
+    if opts.candidate {
+        return Some(opts.candidate_severity.as_deref().unwrap_or("info"));
+    }
+    let severity_label_key = opts.severity_label_key.as_deref().unwrap_or("severity");
+    opts.labels
+        .get(severity_label_key)
+        .or_else(|| opts.base_labels.get(severity_label_key))
+        .map(String::as_str)
+        .or_else(|| default_severity(opts))
+}
+
+/// A unified diff, like `diff -u`, from the `existing` contents of `path` to the
+/// `generated` contents.
+fn unified_diff(path: &Path, existing: &str, generated: &str) -> String {
+    let existing_header = format!("a/{}", path.display());
+    let generated_header = format!("b/{}", path.display());
+    similar::TextDiff::from_lines(existing, generated)
+        .unified_diff()
+        .header(&existing_header, &generated_header)
+        .to_string()
+}
+
+/// A comment summarising the main options used to generate `config` and how
+/// many groups and rules are in it, for `--embed-params`.
+fn describe_params(config: &Value, opts: &Opts) -> String {
+    let groups = config
+        .get("groups")
+        .and_then(|groups| groups.as_sequence())
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let rule_count: usize = groups
+        .iter()
+        .filter_map(|group| group.get("rules"))
+        .filter_map(|rules| rules.as_sequence())
+        .map(Vec::len)
+        .sum();
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "none".to_string());
+    let params = vec![
+        ("ignore file", opts.ignore_file.display().to_string()),
+        ("playbook link", optional(opts.playbook_link.clone())),
+        (
+            "severity label",
+            opts.severity_label_key
+                .clone()
+                .unwrap_or_else(|| "severity".to_string()),
+        ),
+        (
+            "severity",
+            optional(options_severity(opts).map(str::to_string)),
+        ),
+        (
+            "min for",
+            opts.min_for
+                .unwrap_or(prometheus_parser::PromDuration::Hours(1))
+                .to_string(),
+        ),
+        ("candidate", opts.candidate.to_string()),
+        ("severity for floors", opts.severity_for_floors.to_string()),
+        ("heuristic for", opts.heuristic_for.to_string()),
+        (
+            "sustained window",
+            optional(opts.sustained_window.map(|window| window.to_string())),
+        ),
+        (
+            "instant absent over time",
+            optional(opts.instant_absent_over_time.map(|range| range.to_string())),
+        ),
+        ("combine variants", opts.combine_variants.to_string()),
+        ("group by label", optional(opts.group_by_label.clone())),
+        ("groups", groups.len().to_string()),
+        ("rules", rule_count.to_string()),
+    ];
+    let mut description = "#\n# Generated with:\n".to_string();
+    for (name, value) in params {
+        // A newline would end the comment.
+        description.push_str(&format!("#   {}: {}\n", name, value.replace('\n', " ")));
+    }
+    description
+}
+
+/// Serialize `value` as YAML that's more compact than what [serde_yaml] writes.
+/// Mappings and sequences that only hold scalars, like labels and annotations,
+/// go on one line in flow style. Scalars are written as JSON, which is also
+/// valid YAML, so we don't have to worry about YAML's quoting rules.
+fn to_compact_yaml(value: &Value) -> Result<String> {
+    let mut out = String::new();
+    write_compact_yaml(value, 0, &mut out)?;
+    Ok(out)
+}
+
+fn write_compact_yaml(value: &Value, indent: usize, out: &mut String) -> Result<()> {
+    let padding = " ".repeat(indent);
+    match value {
+        Value::Mapping(mapping) if !is_flat_yaml(value) => {
+            for (key, value) in mapping {
+                out.push_str(&format!("{}{}:", padding, compact_yaml_key(key)?));
+                write_compact_yaml_child(value, indent + 2, out)?;
+            }
+        }
+        Value::Sequence(sequence) if !is_flat_yaml(value) => {
+            for item in sequence {
+                let mut item_out = String::new();
+                write_compact_yaml(item, indent + 2, &mut item_out)?;
+                // Put the start of the item on the same line as the dash.
+                out.push_str(&format!("{}- {}", padding, item_out.trim_start()));
+            }
+        }
+        _ => out.push_str(&format!("{}{}\n", padding, serde_json::to_string(value)?)),
+    }
+    Ok(())
+}
+
+fn write_compact_yaml_child(value: &Value, indent: usize, out: &mut String) -> Result<()> {
+    if is_flat_yaml(value) {
+        out.push_str(&format!(" {}\n", serde_json::to_string(value)?));
+        Ok(())
+    } else {
+        out.push('\n');
+        write_compact_yaml(value, indent, out)
+    }
+}
+
+/// Whether `value` is a scalar or a collection of scalars.
+fn is_flat_yaml(value: &Value) -> bool {
+    let is_collection = |value: &Value| matches!(value, Value::Mapping(_) | Value::Sequence(_));
+    match value {
+        Value::Mapping(mapping) => mapping.iter().all(|(_, value)| !is_collection(value)),
+        Value::Sequence(sequence) => sequence.iter().all(|value| !is_collection(value)),
+        _ => true,
+    }
+}
+
+/// Keys that are plain identifiers, like all the Prometheus fields, don't need
+/// quoting.
+fn compact_yaml_key(key: &Value) -> Result<String> {
+    match key.as_str() {
+        Some(key)
+            if !key.is_empty()
+                && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                && !key.starts_with(|c: char| c.is_ascii_digit()) =>
+        {
+            Ok(key.to_string())
+        }
+        _ => Ok(serde_json::to_string(key)?),
+    }
+}
+
+/// Write a skeleton `promtool test rules` file for the rules in each config in
+/// `outputs`, along with the file it's written to, to `tests_file`. With no
+/// rules this is still a valid test file, it just has no tests.
+fn write_promtool_tests_to_file(
+    tests_file: &Path,
+    outputs: &[(PathBuf, PrometheusRulesConfig)],
+) -> Result<()> {
+    let mut tests = PromtoolTestFile {
+        rule_files: vec![],
+        evaluation_interval: "1m".into(),
+        tests: vec![],
+    };
+    for (rules_file, config) in outputs {
+        // promtool finds rule files relative to the test file.
+        let rules_file = if tests_file.parent() == rules_file.parent() {
+            rules_file.file_name().map(PathBuf::from)
+        } else {
+            fs::canonicalize(rules_file).ok()
+        }
+        .unwrap_or_else(|| rules_file.to_path_buf());
+        let file_tests = build_promtool_tests(rules_file, config);
+        tests.rule_files.extend(file_tests.rule_files);
+        tests.tests.extend(file_tests.tests);
+    }
+    fs::write(tests_file, serde_yaml::to_string(&tests)?)
+        .with_context(|| format!("failed to write {}", tests_file.display()))
+}
+
+/// Build a test for each alert in `config` checking that it fires once its
+/// `for` has passed without any data at all.
+fn build_promtool_tests(rules_file: PathBuf, config: &PrometheusRulesConfig) -> PromtoolTestFile {
+    let tests = config
+        .groups
+        .iter()
+        .flat_map(|group| &group.rules)
+        .filter_map(|rule| {
+            let alertname = rule.untyped_fields.get("alert")?.as_str()?.to_string();
+            let for_seconds = rule
+                .untyped_fields
+                .get("for")
+                .and_then(|r#for| r#for.as_str())
+                .and_then(|r#for| parse_duration(r#for).ok())
+                .map(|r#for| duration_seconds(&r#for))
+                .unwrap_or(0);
+            let string_map = |key: &str| -> BTreeMap<String, String> {
+                rule.untyped_fields
+                    .get(key)
+                    .and_then(|value| serde_yaml::from_value(value.clone()).ok())
+                    .unwrap_or_default()
+            };
+            // `absent` copies the selector's equality matchers onto the series
+            // it returns.
+            let exp_labels = match prometheus_parser::parse_expr(&rule.expr)
+                .ok()
+                .and_then(|expr| absent_expression_labels(&expr))
+            {
+                Some(exp_labels) => exp_labels,
+                None => {
+                    log::warn!(
+                        "Not writing a test for '{}', there's no telling which series '{}' returns",
+                        alertname,
+                        rule.expr
+                    );
+                    return None;
+                }
+            };
+            Some(PromtoolTest {
+                interval: "1m".into(),
+                input_series: vec![],
+                alert_rule_test: vec![PromtoolAlertRuleTest {
+                    // Give it an extra evaluation to go from pending to
+                    // firing.
+                    eval_time: format!("{}m", for_seconds / 60 + 1),
+                    alertname,
+                    exp_alerts: exp_labels
+                        .into_iter()
+                        .map(|mut exp_labels| {
+                            exp_labels.extend(string_map("labels"));
+                            PromtoolExpectedAlert {
+                                exp_labels,
+                                exp_annotations: string_map("annotations"),
+                            }
+                        })
+                        .collect(),
+                }],
+            })
+        })
+        .collect();
+    PromtoolTestFile {
+        rule_files: vec![rules_file],
+        evaluation_interval: "1m".into(),
+        tests,
+    }
+}
+
+/// The labels of each series returned by an `absent(...)` or
+/// `absent_over_time(...)` expression, or several of them joined with `or` like
+/// `--combine-variants` makes, which are the selectors' equality matchers.
+/// [None] for anything more complicated.
+fn absent_expression_labels(
+    expr: &prometheus_parser::Expression,
+) -> Option<Vec<BTreeMap<String, String>>> {
+    match expr {
+        prometheus_parser::Expression::Group(group) => absent_expression_labels(&group.expression),
+        prometheus_parser::Expression::Operator(operator)
+            if matches!(operator.kind, prometheus_parser::OperatorKind::Or) =>
+        {
+            let mut labels = absent_expression_labels(&operator.lhs)?;
+            labels.extend(absent_expression_labels(&operator.rhs)?);
+            // `or` only keeps the first series with each set of labels.
+            Some(labels.into_iter().unique().collect())
+        }
+        prometheus_parser::Expression::Function(function)
+            if function.name == "absent" || function.name == "absent_over_time" =>
+        {
+            match function.args.first().map(|arg| arg.as_ref()) {
+                Some(prometheus_parser::Expression::Selector(selector)) => Some(vec![selector
+                    .labels
+                    .iter()
+                    .filter(|label| {
+                        label.op == prometheus_parser::LabelOp::Equal && label.key != "__name__"
+                    })
+                    .map(|label| (label.key.clone(), label.value.clone()))
+                    .collect()]),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Get the selectors back out of a file we generated previously.
+///
+/// Each generated rule becomes the origin rule of the selectors in its
+/// expression, so its "for" carries over when the rules are merged again.
+fn get_selectors_in_generated_file<P: AsRef<Path>>(path: P) -> Result<Vec<SelectorWithOriginRule>> {
+    let content = fs::read_to_string(&path)
+        .with_context(|| format!("failed to read {}", path.as_ref().display()))?;
+    // There's nowhere to put the marker in JSON so those have to be taken on
+    // trust.
+    ensure!(
+        content.contains(&generated_file_marker()) || content.trim_start().starts_with('{'),
+        "{} doesn't look like it was generated by {}",
+        path.as_ref().display(),
+        env!("CARGO_PKG_NAME")
+    );
+    let config: PrometheusRulesConfig = serde_yaml::from_str(&content)?;
+    get_selectors_in_config(&config)
+}
+
+/// Get the selectors back out of the rules we generated in `config`, by
+/// parsing their expressions.
+fn get_selectors_in_config(config: &PrometheusRulesConfig) -> Result<Vec<SelectorWithOriginRule>> {
+    let mut selectors = vec![];
+    for rule in config.groups.iter().flat_map(|group| &group.rules) {
+        let expr = prometheus_parser::parse_expr(&rule.expr)
+            .map_err(|e| anyhow!("Failed to parse expression '{}': {}", rule.expr, e))?;
+        selectors.extend(
+            get_selectors_from_expression(&expr)
+                .into_iter()
+                .map(|selector| SelectorWithOriginRule {
+                    selector,
+                    rule: rule.clone(),
+                    ..Default::default()
+                }),
+        );
+    }
+    Ok(selectors)
+}
+
+/// The selectors that don't say what metric they're for, neither with a metric
+/// name nor a `__name__` matcher, for `--require-metric`.
+fn find_nameless_selectors(selectors: &[SelectorWithOriginRule]) -> Vec<&SelectorWithOriginRule> {
+    selectors
+        .iter()
+        .filter(|s| {
+            s.selector.metric.is_none()
+                && !s
+                    .selector
+                    .labels
+                    .iter()
+                    .any(|label| label.key == "__name__")
+        })
+        .collect()
+}
+
+/// A line of `--selector-delta` output, green for additions and red for
+/// removals if `color` is set.
+fn format_delta_line(sign: char, selector: &str, color: bool) -> String {
+    let line = format!("{} {}", sign, selector);
+    if !color {
+        return line;
+    }
+    let color_code = if sign == '+' { 32 } else { 31 };
+    format!("\x1b[{}m{}\x1b[0m", color_code, line)
+}
+
+/// The selectors in `new` but not `existing`, and the ones in `existing` but
+/// not `new`, for `--selector-delta`. Each is sorted and only listed once.
+fn selector_delta(
+    existing: &[SelectorWithOriginRule],
+    new: &[SelectorWithOriginRule],
+) -> (Vec<String>, Vec<String>) {
+    let to_strings = |selectors: &[SelectorWithOriginRule]| -> Vec<String> {
+        selectors
+            .iter()
+            .map(|s| s.selector.to_string())
+            .sorted()
+            .dedup()
+            .collect()
+    };
+    let (existing, new) = (to_strings(existing), to_strings(new));
+    let added = new
+        .iter()
+        .filter(|selector| !existing.contains(selector))
+        .cloned()
+        .collect();
+    let removed = existing
+        .iter()
+        .filter(|selector| !new.contains(selector))
+        .cloned()
+        .collect();
+    (added, removed)
+}
+
+/// What a run would do, for `--plan-json`.
+#[derive(Serialize, Debug)]
+struct Plan {
+    files_scanned: Vec<PathBuf>,
+    selectors_found: usize,
+    ignored_selectors: Vec<String>,
+    outputs: Vec<PlannedOutput>,
+}
+
+/// What would be written to one output file, for `--plan-json`.
+#[derive(Serialize, Debug)]
+struct PlannedOutput {
+    file: PathBuf,
+    /// Names of the rules that aren't in the existing file.
+    rules_to_create: Vec<String>,
+    /// Names of the rules in the existing file that won't be any more.
+    rules_to_remove: Vec<String>,
+    /// Every generated rule, with the `for`, labels, etc. chosen for it.
+    rules: Vec<PrometheusRule>,
+}
+
+/// Compare `config` with what's already in `output_file`, which might not
+/// exist yet, for `--plan-json`.
+fn plan_output(output_file: &Path, config: &PrometheusRulesConfig) -> Result<PlannedOutput> {
+    let rule_names = |config: &PrometheusRulesConfig| -> Vec<String> {
+        config
+            .groups
+            .iter()
+            .flat_map(|group| &group.rules)
+            .filter_map(|rule| rule.name())
+            .map(str::to_string)
+            .sorted()
+            .dedup()
+            .collect()
+    };
+    let existing = if output_file.exists() {
+        rule_names(&load_rules_from_file(output_file)?)
+    } else {
+        vec![]
+    };
+    let new = rule_names(config);
+    Ok(PlannedOutput {
+        file: output_file.to_path_buf(),
+        rules_to_create: new
+            .iter()
+            .filter(|name| !existing.contains(name))
+            .cloned()
+            .collect(),
+        rules_to_remove: existing
+            .iter()
+            .filter(|name| !new.contains(name))
+            .cloned()
+            .collect(),
+        rules: config
+            .groups
+            .iter()
+            .flat_map(|group| group.rules.iter().cloned())
+            .collect(),
+    })
+}
+
+/// A failure to get the selectors out of a rules file, for `--errors-file`.
+#[derive(Serialize, Debug)]
+struct SelectorExtractionError {
+    file: PathBuf,
+    /// The rest are only set if the failure was for a specific rule.
+    group: Option<String>,
+    rule_name: Option<String>,
+    expr: Option<String>,
+    error: String,
+}
+
+impl SelectorExtractionError {
+    /// Log `error` about `rule` and keep hold of it.
+    fn for_rule<P: AsRef<Path>>(
+        rules_path: P,
+        group: &str,
+        rule: &PrometheusRule,
+        error: String,
+    ) -> SelectorExtractionError {
+        log::error!("{}", error);
+        SelectorExtractionError {
+            file: rules_path.as_ref().to_path_buf(),
+            group: Some(group.to_string()),
+            rule_name: rule.name().map(str::to_string),
+            expr: Some(rule.expr.clone()),
+            error,
+        }
+    }
+}
+
+/// [get_selectors_and_errors_in_file] for when any error is a failure.
+#[cfg(test)]
+fn get_selectors_in_file<P: AsRef<Path>>(
+    rules_path: P,
+    opts: &Opts,
+) -> Result<Vec<SelectorWithOriginRule>> {
+    let (selectors, errors) = get_selectors_and_errors_in_file(&rules_path, opts)?;
+    ensure!(
+        errors.is_empty(),
+        "There was a failure getting selectors from {}, see logs for details.",
+        rules_path.as_ref().display()
+    );
+    Ok(selectors)
+}
+
+/// Get the selectors in `rules_path` along with all the rules we couldn't get
+/// selectors out of. It's only an [Err] if the file couldn't be loaded at all.
+fn get_selectors_and_errors_in_file<P: AsRef<Path>>(
+    rules_path: P,
+    opts: &Opts,
+) -> Result<(Vec<SelectorWithOriginRule>, Vec<SelectorExtractionError>)> {
+    let config = load_rules_from_file(&rules_path)?;
+    let mut selectors: Vec<SelectorWithOriginRule> = vec![];
+    let mut errors: Vec<SelectorExtractionError> = vec![];
+    for group in config.groups {
+        let group_labels = yaml_label_values(&group.labels);
+        let group_name = group.name;
+        for rule in group.rules {
+            if let Some(disabled_annotation) = &opts.disabled_annotation {
+                if rule.is_disabled(disabled_annotation) {
+                    log::debug!(
+                        "Skipping disabled rule '{}' in {}",
+                        rule.name().unwrap_or(&rule.expr),
+                        rules_path.as_ref().display()
+                    );
+                    continue;
+                }
+            }
+            if opts.validate_fields {
+                for field in rule.unknown_fields() {
+                    log::warn!(
+                        "Unknown field '{}' in rule '{}' in {}",
+                        field,
+                        rule.name().unwrap_or(&rule.expr),
+                        rules_path.as_ref().display()
+                    );
+                }
+            }
+            let mut at_modifier_positions = vec![];
+            let parsed = prometheus_parser::parse_expr(&rule.expr).or_else(|e| {
+                // prometheus_parser doesn't know about `@` modifiers so have
+                // another go without them, remembering where they were so the
+                // selectors they anchored can be skipped.
+                let (stripped, positions) = strip_at_modifiers(&rule.expr);
+                if positions.is_empty() {
+                    return Err(e);
+                }
+                at_modifier_positions = positions;
+                prometheus_parser::parse_expr(&stripped).map_err(|_| e)
+            });
+            let expr_selectors = match parsed {
+                Ok(expr) if opts.skip_vector_fallbacks && has_vector_fallback(&expr) => {
+                    log::info!(
+                        "Skipping the selectors in '{}' because it falls back to vector(...) so always has data",
+                        rule.expr
+                    );
+                    vec![]
+                }
+                Ok(expr) => get_selectors_from_expression(&expr)
+                    .into_iter()
+                    .filter(|selector| {
+                        let anchored = has_at_modifier(selector, &at_modifier_positions);
+                        if anchored {
+                            log::debug!(
+                                "Skipping selector '{}' in '{}' because it has an `@` modifier",
+                                selector,
+                                rule.expr
+                            );
+                        }
+                        !anchored
+                    })
+                    .collect(),
+                Err(e) => {
+                    // The expression might use PromQL that's newer than
+                    // prometheus_parser. Rather than losing coverage for the
+                    // whole rule have a go at picking the selectors out
+                    // anyway.
+                    let fallback_selectors = get_selectors_from_unparseable_expression(&rule.expr);
+                    if fallback_selectors.is_empty() {
+                        errors.push(SelectorExtractionError::for_rule(
+                            &rules_path,
+                            &group_name,
+                            &rule,
+                            format!("Failed to parse expression '{}': {}", rule.expr, e),
+                        ));
+                        continue;
+                    }
+                    log::warn!(
+                        "Failed to parse expression '{}': {}. Falling back to picking out anything that looks like a selector: {}",
+                        rule.expr,
+                        e,
+                        fallback_selectors.iter().join(", ")
+                    );
+                    fallback_selectors
+                }
+            };
+            let mut rule_selectors: Vec<SelectorWithOriginRule> = expr_selectors
+                .into_iter()
+                .map(|selector| SelectorWithOriginRule {
+                    selector,
+                    rule: rule.clone(),
+                    group_labels: group_labels.clone(),
+                    group_name: group_name.clone(),
+                    source_file: rules_path.as_ref().to_path_buf(),
+                    origin: SelectorOrigin::Expr,
+                })
+                .collect();
+            selectors.append(&mut rule_selectors);
+            // Also explicitly get the recordings we've defined. Even if
+            // they're not used in other Prometheus rules they may be used
+            // in places like Grafana. We've defined them for a reason so we
+            // should alert if they're missing.
+            if let Some(record_name_value) = rule.untyped_fields.get("record") {
+                let maybe_record_name = record_name_value.as_str();
+                if let Some(record_name) = maybe_record_name {
+                    match record_name_selector(record_name) {
+                        Some(selector) => {
+                            selectors.push(SelectorWithOriginRule {
+                                selector: add_recording_rule_labels(selector, &rule),
+                                rule: rule.clone(),
+                                group_labels: group_labels.clone(),
+                                group_name: group_name.clone(),
+                                source_file: rules_path.as_ref().to_path_buf(),
+                                origin: SelectorOrigin::Record,
+                            });
+                        }
+                        None => {
+                            errors.push(SelectorExtractionError::for_rule(
+                                &rules_path,
+                                &group_name,
+                                &rule,
+                                format!(
+                                    "Expected record name '{}' to be a valid metric name",
+                                    record_name
+                                ),
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    // While metrics are being renamed rules can use either name. Treat them all
+    // as the new name so they end up in the same rule.
+    for s in &mut selectors {
+        if let Some(new_name) = s
+            .selector
+            .metric
+            .as_ref()
+            .and_then(|metric| opts.rename_metrics.get(metric))
+        {
+            s.selector.metric = Some(new_name.clone());
+        }
+    }
+    // Guard metrics are things like maintenance flags that only exist to
+    // silence other alerts. It doesn't matter if they're missing so they
+    // never get absent rules, whichever side of an expression they're on.
+    selectors.retain(|s| match &s.selector.metric {
+        Some(metric) if opts.guard_metrics.contains(metric) => {
+            log::debug!("Skipping guard metric selector '{}'", s.selector);
+            false
+        }
+        _ => true,
+    });
+    Ok((selectors, errors))
+}
+
+/// Get the selectors listed in an `--extra-metrics` file.
+///
+/// Each selector's origin rule is just the selector itself as there's no real
+/// rule behind it.
+fn get_extra_selectors<P: AsRef<Path>>(path: P) -> Result<Vec<SelectorWithOriginRule>> {
+    let mut selectors = vec![];
+    let mut failed = false;
+    for line in read_list_file(&path)? {
+        match prometheus_parser::parse_expr(&line) {
+            Ok(prometheus_parser::Expression::Selector(selector)) => {
+                selectors.push(SelectorWithOriginRule {
+                    selector: promote_name_label_to_metric(&selector),
+                    rule: PrometheusRule {
+                        expr: line,
+                        untyped_fields: BTreeMap::new(),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                })
+            }
+            Ok(_) => {
+                log::error!("Expected extra metric '{}' to be a selector", line);
+                failed = true;
+            }
+            Err(e) => {
+                log::error!("Failed to parse extra metric '{}': {}", line, e);
+                failed = true;
+            }
+        }
+    }
+    ensure!(
+        !failed,
+        "There was a failure getting selectors from {}, see logs for details.",
+        path.as_ref().display()
+    );
+    Ok(selectors)
+}
+
+/// Words that look like metric names when they're on their own but are actually
+/// part of PromQL's syntax.
+const PROMQL_KEYWORDS: &[&str] = &[
+    "and",
+    "atan2",
+    "avg",
+    "bool",
+    "bottomk",
+    "count",
+    "count_values",
+    "group",
+    "inf",
+    "limit_ratio",
+    "limitk",
+    "max",
+    "min",
+    "nan",
+    "offset",
+    "or",
+    "quantile",
+    "stddev",
+    "stdvar",
+    "sum",
+    "topk",
+    "unless",
+];
+
+/// Best-effort selector extraction for expressions that [prometheus_parser]
+/// can't parse.
+///
+/// This scans the expression for things that look like selectors, i.e. a
+/// metric name optionally followed by label matchers, skipping strings,
+/// durations, label lists in grouping clauses, function calls, and keywords.
+/// Each candidate is then parsed on its own so we only ever return selectors
+/// that [prometheus_parser] is happy with.
+fn get_selectors_from_unparseable_expression(expr: &str) -> Vec<prometheus_parser::Selector> {
+    // These regexes are constant so panicing on them being incorrect is okay
+    // as it would be a developer error. The alternatives are tried in order
+    // so the things we want to skip need to come before the selector.
+    let token_re = Regex::new(
+        r#"(?x)
+        "(?:[^"\\]|\\.)*" | '(?:[^'\\]|\\.)*'       # strings
+        | \[[^\]]*\]                                   # ranges and subqueries
+        | \b(?:by|without|on|ignoring|group_left|group_right)\s*\([^)]*\)
+        | (?P<selector>(?:\b[a-zA-Z_:][a-zA-Z0-9_:]*)?\s*(?:\{[^}]*\})?)(?P<call>\s*\()?
+        "#,
+    )
+    .expect("invalid regex");
+    token_re
+        .captures_iter(expr)
+        .filter(|captures| captures.name("call").is_none())
+        .filter_map(|captures| captures.name("selector"))
+        .map(|selector| selector.as_str().trim())
+        .filter(|selector| !selector.is_empty() && !PROMQL_KEYWORDS.contains(selector))
+        .filter_map(|selector| match prometheus_parser::parse_expr(selector) {
+            Ok(prometheus_parser::Expression::Selector(selector)) => {
+                Some(promote_name_label_to_metric(&selector))
+            }
+            _ => None,
+        })
+        // A selector without a metric is most likely the label matchers from
+        // something we don't understand.
+        .filter(|selector| selector.metric.is_some())
+        .unique_by(|selector| selector.to_string())
+        .collect()
+}
+
+/// Remove any `@` modifiers, e.g. `@ start()`, `@ end()`, or `@ 1609746000`,
+/// from `expr`, leaving everything else, including offsets, as it is.
+///
+/// Also returns the positions in the stripped expression where modifiers were
+/// removed, see [has_at_modifier].
+fn strip_at_modifiers(expr: &str) -> (String, Vec<usize>) {
+    // This regex is constant so panicing on it being incorrect is okay as it
+    // would be a developer error. Strings are matched so an `@` in a label
+    // value is left alone.
+    let at_modifier_re = Regex::new(
+        r#"(?x)
+        "(?:[^"\\]|\\.)*" | '(?:[^'\\]|\\.)*'
+        | \s*@\s*(?:start\(\s*\)|end\(\s*\)|[-+]?[0-9]+(?:\.[0-9]+)?)
+        "#,
+    )
+    .expect("invalid regex");
+    let mut stripped = String::with_capacity(expr.len());
+    let mut positions = vec![];
+    let mut last_end = 0;
+    for matched in at_modifier_re.find_iter(expr) {
+        stripped.push_str(&expr[last_end..matched.start()]);
+        if matched.as_str().starts_with('"') || matched.as_str().starts_with('\'') {
+            stripped.push_str(matched.as_str());
+        } else {
+            positions.push(stripped.len());
+        }
+        last_end = matched.end();
+    }
+    stripped.push_str(&expr[last_end..]);
+    (stripped, positions)
+}
+
+/// Whether `selector`, parsed from an expression stripped by
+/// [strip_at_modifiers], had one of the `at_modifier_positions` removed from
+/// it.
+///
+/// `@` modifiers pin a selector to a fixed point in time so an absent rule for
+/// it doesn't make sense. A selector without a span can't be checked so it's
+/// kept, which is still valid as its modifier has been stripped.
+fn has_at_modifier(
+    selector: &prometheus_parser::Selector,
+    at_modifier_positions: &[usize],
+) -> bool {
+    match &selector.span {
+        Some(span) => at_modifier_positions
+            .iter()
+            .any(|&position| span.start < position && position <= span.end),
+        None => false,
+    }
+}
+
+/// Get all the selectors in an expression.
+///
+/// Recursively traverse the AST and return all the selectors it finds.
+fn get_selectors_from_expression(
+    expr: &prometheus_parser::Expression,
+) -> Vec<prometheus_parser::Selector> {
+    match expr {
+        prometheus_parser::Expression::Float(_) => vec![],
+        prometheus_parser::Expression::String(_) => vec![],
+        // [prometheus_parser] doesn't have an expression for subqueries,
+        // instead selectors, functions, and groups have an optional
+        // `subquery`. The subquery of a selector, e.g. `foo[30m:1m]`, is about
+        // how it's evaluated rather than what it selects so it's dropped,
+        // otherwise `absent(foo[30m:1m])` would be generated, which isn't
+        // valid. The subqueries of functions and groups don't matter here
+        // and the selectors inside them keep their own ranges.
+        prometheus_parser::Expression::Selector(selector) => {
+            vec![prometheus_parser::Selector {
+                subquery: None,
+                ..promote_name_label_to_metric(selector)
+            }]
+        }
+        prometheus_parser::Expression::Group(prometheus_parser::Group { expression, .. }) => {
+            get_selectors_from_expression(&expression)
+        }
+        prometheus_parser::Expression::Function(function) => function
+            .args
+            .iter()
+            .flat_map(|arg| get_selectors_from_expression(&arg))
+            .collect(),
+        prometheus_parser::Expression::Operator(operator) => {
+            let mut selectors = get_selectors_from_expression(&operator.lhs);
+            selectors.extend(get_selectors_from_expression(&operator.rhs));
+            selectors
+        }
+        prometheus_parser::Expression::BoolOperator(bool_operator) => {
+            let mut selectors = get_selectors_from_expression(&bool_operator.lhs);
+            selectors.extend(get_selectors_from_expression(&bool_operator.rhs));
+            selectors
+        }
+    }
+}
+
+/// Whether the whole of `expr` is something `or vector(...)`. The author of an
+/// expression like that wants it to always have data, whatever the selectors
+/// in it are doing.
+fn has_vector_fallback(expr: &prometheus_parser::Expression) -> bool {
+    match expr {
+        prometheus_parser::Expression::Group(group) => has_vector_fallback(&group.expression),
+        prometheus_parser::Expression::Operator(operator)
+            if matches!(operator.kind, prometheus_parser::OperatorKind::Or) =>
+        {
+            is_vector_call(&operator.rhs)
+        }
+        _ => false,
+    }
+}
+
+fn is_vector_call(expr: &prometheus_parser::Expression) -> bool {
+    match expr {
+        prometheus_parser::Expression::Group(group) => is_vector_call(&group.expression),
+        prometheus_parser::Expression::Function(function) => function.name == "vector",
+        _ => false,
+    }
+}
+
+/// Load the list of rules files and globs from a `--files-from` manifest.
+///
+/// Relative entries are resolved against the directory the manifest is in so
+/// the manifest can live alongside the rules it lists.
+fn load_files_from_manifest<P: AsRef<Path>>(manifest: P) -> Result<Vec<String>> {
+    let manifest = manifest.as_ref();
+    let base_dir = manifest.parent().unwrap_or_else(|| Path::new(""));
+    Ok(read_list_file(manifest)?
+        .iter()
+        .map(|line| base_dir.join(line).display().to_string())
+        .collect())
+}
+
+/// Read a file with one entry per line, trimming whitespace and skipping blank
+/// lines and lines starting with `#`.
+fn read_list_file<P: AsRef<Path>>(path: P) -> Result<Vec<String>> {
+    let contents = fs::read_to_string(&path)
+        .with_context(|| format!("failed to read {}", path.as_ref().display()))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect())
+}
+
+/// Read a YAML file that maps strings to strings, e.g. metric names to their
+/// HELP text.
+fn read_map_file<P: AsRef<Path>>(path: P) -> Result<BTreeMap<String, String>> {
+    let contents = fs::read_to_string(&path)
+        .with_context(|| format!("failed to read {}", path.as_ref().display()))?;
+    serde_yaml::from_str(&contents)
+        .with_context(|| format!("failed to parse {}", path.as_ref().display()))
+}
+
+/// Read the `--for-overrides` file, a YAML mapping of metric name regexes to
+/// durations, keeping them in the same order as the file.
+fn read_for_overrides(path: &Path) -> Result<Vec<(Regex, prometheus_parser::PromDuration)>> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let mapping: serde_yaml::Mapping = serde_yaml::from_str(&contents)
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+    mapping
+        .iter()
+        .map(
+            |(pattern, duration)| match (pattern.as_str(), duration.as_str()) {
+                (Some(pattern), Some(duration)) => Ok((
+                    Regex::new(&format!("^(?:{})$", pattern)).with_context(|| {
+                        format!("Invalid regex '{}' in {}", pattern, path.display())
+                    })?,
+                    parse_duration(duration).with_context(|| {
+                        format!("Invalid 'for' for '{}' in {}", pattern, path.display())
+                    })?,
+                )),
+                _ => Err(anyhow!(
+                    "Expected metric name regexes mapped to durations in {}, found {:?}: {:?}",
+                    path.display(),
+                    pattern,
+                    duration
+                )),
+            },
+        )
+        .collect()
+}
+
+/// Try to load each of `rule_files`, logging the ones that fail and returning
+/// how many did.
+///
+/// This only checks that the files deserialize into a [PrometheusRulesConfig],
+/// it doesn't look at the expressions inside them.
+fn find_malformed_rule_files(rule_files: &[PathBuf]) -> usize {
+    rule_files
+        .iter()
+        .filter(|path| match load_rules_from_file(path) {
+            Ok(_) => false,
+            Err(e) => {
+                log::error!("Malformed rules file {}: {}", path.display(), e);
+                true
+            }
+        })
+        .count()
+}
+
+/// Check for the common setup mistakes that lead to an empty or missing output
+/// file, printing what was found. Returns the number of problems.
+fn diagnose_setup(opts: &Opts, rule_files: &[PathBuf]) -> usize {
+    let mut problems = 0;
+    let mut check = |ok: bool, message: String| {
+        if ok {
+            println!("ok:      {}", message);
+        } else {
+            println!("problem: {}", message);
+            problems += 1;
+        }
+    };
+    for rules_dir in &opts.rules_dirs {
+        match fs::read_dir(rules_dir) {
+            Ok(_) => check(
+                true,
+                format!("rules directory {} is readable", rules_dir.display()),
+            ),
+            Err(e) => check(
+                false,
+                format!("can't read rules directory {}: {}", rules_dir.display(), e),
+            ),
+        }
+    }
+    if let Some(files_from) = &opts.files_from {
+        match fs::metadata(files_from) {
+            Ok(_) => check(true, format!("manifest {} exists", files_from.display())),
+            Err(e) => check(
+                false,
+                format!("can't read manifest {}: {}", files_from.display(), e),
+            ),
+        }
+    }
+    check(
+        !rule_files.is_empty(),
+        format!(
+            "found {} rules files, only files matching {} are picked up from directories",
+            rule_files.len(),
+            rules_dir_glob(opts)
+        ),
+    );
+    let malformed = find_malformed_rule_files(rule_files);
+    check(
+        malformed == 0,
+        format!("{} rules files can't be parsed", malformed),
+    );
+    if is_stdio(&opts.ignore_file) {
+        // Reading it here would leave nothing on stdin for the real run.
+        println!("note:    ignore file is read from stdin, skipped checking it");
+    } else if let Some(url) = ignore_file_url(opts) {
+        // Unlike a missing file, a failed fetch stops the run.
+        match load_ignore_file(opts) {
+            Ok(metrics) => check(
+                true,
+                format!("ignore file {} lists {} metrics", url, metrics.len()),
+            ),
+            Err(e) => check(false, format!("{:#}", e)),
+        }
+    } else {
+        match fs::read_to_string(&opts.ignore_file) {
+            Ok(contents) => check(
+                true,
+                format!(
+                    "ignore file {} lists {} metrics",
+                    opts.ignore_file.display(),
+                    parse_ignore_list(&contents, opts).len()
+                ),
+            ),
+            // We carry on without an ignore file so it's not a problem as such.
+            Err(e) => println!(
+                "note:    can't read ignore file {}, no metrics will be ignored: {}",
+                opts.ignore_file.display(),
+                e
+            ),
+        }
+    }
+    let output_dir = match opts.output_file.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    check(
+        output_dir.is_dir(),
+        format!("output directory {} exists", output_dir.display()),
+    );
+    match std::process::Command::new("promtool")
+        .arg("--version")
+        .output()
+    {
+        Ok(_) => println!("ok:      promtool is installed"),
+        Err(_) => println!(
+            "note:    promtool isn't installed, it's only needed for --validate-only and to run the tests"
+        ),
+    }
+    problems
+}
+
+/// Load the rules in `rules_path`, or stdin if it's `-`.
+fn load_rules_from_file<P: AsRef<Path>>(rules_path: P) -> Result<PrometheusRulesConfig> {
+    let content = if is_stdio(rules_path.as_ref()) {
+        let mut content = String::new();
+        std::io::stdin()
+            .read_to_string(&mut content)
+            .context("failed to read rules from stdin")?;
+        content
+    } else {
+        fs::read_to_string(rules_path)?
+    };
+    let config = serde_yaml::from_str(&content)?;
+    Ok(config)
+}
+
+/// Parse the provided command line options into [Opts].
+fn parse_options() -> Result<Opts> {
+    let mut args = pico_args::Arguments::from_env();
+    if args.contains(["-h", "--help"]) {
+        println!("{}", USAGE);
+        std::process::exit(1);
+    }
+    if let Some(expr) = args.opt_value_from_str::<_, String>("--dump-ast")? {
+        dump_ast(&expr)?;
+        std::process::exit(0);
+    }
+    let dry_run = args.contains("--dry-run");
+    let maybe_output_file: Option<PathBuf> = args.opt_value_from_str("--output-file")?;
+    let ignore_file: PathBuf = args
+        .opt_value_from_str("--ignore-file")?
+        .unwrap_or_else(|| {
+            let mut path = PathBuf::new();
+            path = path.join(env!("CARGO_MANIFEST_DIR"));
+            path.join("ignore_metrics.txt")
+        });
+    let playbook_link = args.opt_value_from_str("--playbook-link")?;
+    let prometheus_url: Option<String> = args.opt_value_from_str("--prometheus-url")?;
+    let tag_currently_absent = args.contains("--tag-currently-absent");
+    let prometheus_timeout = args.opt_value_from_str("--prometheus-timeout")?;
+    let annotate_count = args.contains("--annotate-count");
+    let allow_duplicate_names = args.contains("--allow-duplicate-names");
+    let high_cardinality_file = args.opt_value_from_str("--high-cardinality-file")?;
+    let lint_only = args.contains("--lint-only");
+    let for_from_range = args.contains("--for-from-range");
+    let group_limit = args
+        .opt_value_from_str::<_, String>("--group-limit")?
+        .map(|limit| parse_group_limit(&limit))
+        .transpose()?;
+    let validate_fields = args.contains("--validate-fields");
+    let combine_variants = args.contains("--combine-variants");
+    let disabled_annotation = args.opt_value_from_str("--disabled-annotation")?;
+    let label_order = args
+        .opt_value_from_str::<_, String>("--label-order")?
+        .map(|order| order.split(',').map(|key| key.trim().to_string()).collect())
+        .unwrap_or_default();
+    let guard_metrics = args
+        .opt_value_from_str::<_, String>("--guard-metrics")?
+        .map(|metrics| {
+            metrics
+                .split(',')
+                .map(|metric| metric.trim().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    let migrate = args.contains("--migrate");
+    let expr_label = args.opt_value_from_str("--expr-label")?;
+    let extra_metrics = args.opt_value_from_str("--extra-metrics")?;
+    let candidate = args.contains("--candidate");
+    let candidate_severity = args.opt_value_from_str("--candidate-severity")?;
+    let dedup_report = args.opt_value_from_str("--dedup-report")?;
+    let warn_for_divergence: Option<f64> = args.opt_value_from_str("--warn-for-divergence")?;
+    ensure!(
+        warn_for_divergence.map_or(true, |factor| factor >= 1.0),
+        "--warn-for-divergence must be at least 1"
+    );
+    let strict = args.contains("--strict");
+    let metadata_file = args.opt_value_from_str("--metadata-file")?;
+    let compact = args.contains("--compact");
+    let doctor = args.contains("--doctor");
+    let ignore_range = args.contains("--ignore-range");
+    let range_collapse_strategy = args
+        .opt_value_from_str("--range-collapse-strategy")?
+        .unwrap_or_default();
+    let rename_metrics = match args.opt_value_from_str::<_, PathBuf>("--rename-metrics")? {
+        Some(path) => read_map_file(&path)?,
+        None => BTreeMap::new(),
+    };
+    let no_trailing_newline = args.contains("--no-trailing-newline");
+    let group_by_label = args.opt_value_from_str("--group-by-label")?;
+    let for_by_severity = match args.opt_value_from_str::<_, String>("--for-by-severity")? {
+        Some(mapping) => parse_for_by_severity(&mapping)?,
+        None => BTreeMap::new(),
+    };
+    let severity_for_floors = args.contains("--severity-for-floors") || !for_by_severity.is_empty();
+    let dedup_scope = args
+        .opt_value_from_str("--dedup-scope")?
+        .unwrap_or_default();
+    let errors_file = args.opt_value_from_str("--errors-file")?;
+    let rich_description = args.contains("--rich-description");
+    let emit_tests = args.opt_value_from_str("--emit-tests")?;
+    let ignore_file_timeout = args.opt_value_from_str("--ignore-file-timeout")?;
+    let ignore_file_cache = args.opt_value_from_str("--ignore-file-cache")?;
+    let expr_wrapper: Option<String> = args.opt_value_from_str("--expr-wrapper")?;
+    if let Some(expr_wrapper) = &expr_wrapper {
+        ensure!(
+            expr_wrapper.contains("{absent}"),
+            "--expr-wrapper must contain {{absent}}"
+        );
+        let example = expr_wrapper.replace("{absent}", "absent(up)");
+        prometheus_parser::parse_expr(&example).map_err(|e| {
+            anyhow!(
+                "--expr-wrapper doesn't make a valid expression, e.g. '{}': {}",
+                example,
+                e
+            )
+        })?;
+    }
+    let skip_vector_fallbacks = args.contains("--skip-vector-fallbacks");
+    let profile = args.contains("--profile");
+    let records_output_file = args.opt_value_from_str("--records-output-file")?;
+    let exprs_output_file = args.opt_value_from_str("--exprs-output-file")?;
+    let expand_by: Option<String> = args.opt_value_from_str("--expand-by")?;
+    ensure!(
+        expand_by.is_none() || prometheus_url.is_some(),
+        "--expand-by needs --prometheus-url to look up the label's values"
+    );
+    let normalize_exprs = args.contains("--normalize-exprs");
+    let counter_for = args
+        .opt_value_from_str::<_, String>("--counter-for")?
+        .map(|duration| parse_duration(&duration))
+        .transpose()?;
+    let gauge_for = args
+        .opt_value_from_str::<_, String>("--gauge-for")?
+        .map(|duration| parse_duration(&duration))
+        .transpose()?;
+    let heuristic_for =
+        args.contains("--heuristic-for") || counter_for.is_some() || gauge_for.is_some();
+    let hash_label = args.contains("--hash-label");
+    let ignore_comment_prefix = args.opt_value_from_str("--ignore-comment-prefix")?;
+    let sustained_window = args
+        .opt_value_from_str::<_, String>("--sustained-window")?
+        .map(|duration| parse_duration(&duration))
+        .transpose()?;
+    let validate_only = args.contains("--validate-only");
+    let severity_label_key = args.opt_value_from_str("--severity-label-key")?;
+    let base_labels = match args.opt_value_from_str::<_, PathBuf>("--base-labels-file")? {
+        Some(path) => read_map_file(&path)?,
+        None => BTreeMap::new(),
+    };
+    let prefer_range = args.contains("--prefer-range");
+    let prefer_instant = args.contains("--prefer-instant");
+    ensure!(
+        !(prefer_range && prefer_instant),
+        "--prefer-range and --prefer-instant can't be used together"
+    );
+    let mixed_vector_preference = if prefer_range {
+        MixedVectorPreference::Range
+    } else if prefer_instant {
+        MixedVectorPreference::Instant
+    } else {
+        MixedVectorPreference::Both
+    };
+    let group_meta = args
+        .values_from_str::<_, String>("--group-meta")?
+        .iter()
+        .map(|pair| parse_group_meta(pair))
+        .collect::<Result<_>>()?;
+    let write_baseline: Option<PathBuf> = args.opt_value_from_str("--write-baseline")?;
+    let check = args.contains("--check");
+    let baseline: Option<PathBuf> = args.opt_value_from_str("--baseline")?;
+    ensure!(
+        !(check && write_baseline.is_some()),
+        "--check and --write-baseline can't be used together"
+    );
+    let numeric_for_unit: Option<String> = args.opt_value_from_str("--numeric-for-unit")?;
+    if let Some(unit) = &numeric_for_unit {
+        parse_duration(&format!("1{}", unit)).context("Invalid --numeric-for-unit")?;
+    }
+    let query_offset = args
+        .opt_value_from_str::<_, String>("--query-offset")?
+        .map(|duration| parse_duration(&duration).context("Invalid --query-offset"))
+        .transpose()?;
+    let split_by_label: Option<String> = args.opt_value_from_str("--split-by-label")?;
+    let split_by_dir = args.contains("--split-by-dir");
+    let output_dir: Option<PathBuf> = args.opt_value_from_str("--output-dir")?;
+    ensure!(
+        !(split_by_label.is_some() && split_by_dir),
+        "--split-by-label and --split-by-dir can't be used together"
+    );
+    ensure!(
+        (split_by_label.is_some() || split_by_dir) == output_dir.is_some(),
+        "--output-dir has to be used with --split-by-label or --split-by-dir"
+    );
+    ensure!(
+        output_dir.is_none()
+            || (records_output_file.is_none() && exprs_output_file.is_none() && !migrate),
+        "--split-by-label and --split-by-dir can't be used with --records-output-file, --exprs-output-file, or --migrate"
+    );
+    let selector_delta = args.contains("--selector-delta");
+    let no_sort = args.contains("--no-sort");
+    let embed_params = args.contains("--embed-params");
+    let require_metric = args.contains("--require-metric");
+    let transform_script = args.opt_value_from_str("--transform-script")?;
+    let color = args.opt_value_from_str("--color")?.unwrap_or_default();
+    let merge_label_values = args.contains("--merge-label-values");
+    let plan_json: Option<PathBuf> = args.opt_value_from_str("--plan-json")?;
+    ensure!(
+        plan_json.is_none() || !is_stdio(&ignore_file),
+        "--plan-json can't be used with --ignore-file - as stdin can only be read once"
+    );
+    let severity: Option<String> = args.opt_value_from_str("--severity")?;
+    if let Some(severity) = &severity {
+        ensure!(!severity.trim().is_empty(), "--severity can't be empty");
+        if severity.chars().any(char::is_control) {
+            log::warn!(
+                "--severity {:?} contains control characters, which Alertmanager routes and templates probably won't handle",
+                severity
+            );
+        }
+    }
+    let no_default_labels = args.contains("--no-default-labels");
+    let labels = args
+        .values_from_str::<_, String>("--label")?
+        .iter()
+        .map(|pair| parse_label(pair))
+        .collect::<Result<_>>()?;
+    let min_for = args
+        .opt_value_from_str::<_, String>("--min-for")?
+        .map(|duration| parse_duration(&duration).context("Invalid --min-for"))
+        .transpose()?;
+    let stdout = args.contains("--stdout");
+    let format = args.opt_value_from_str("--format")?.unwrap_or_default();
+    ensure!(
+        format == OutputFormat::Yaml || !(compact || embed_params),
+        "--compact and --embed-params can't be used with --format json"
+    );
+    let group_name: Option<String> = args.opt_value_from_str("--group-name")?;
+    if let Some(group_name) = &group_name {
+        ensure!(!group_name.trim().is_empty(), "--group-name can't be empty");
+        if group_name.trim() != group_name || group_name.chars().any(char::is_control) {
+            log::warn!(
+                "--group-name {:?} has surrounding whitespace or control characters, which will make it hard to find in Prometheus",
+                group_name
+            );
+        }
+    }
+    let summary_template = args.opt_value_from_str("--summary-template")?;
+    let description_template = args.opt_value_from_str("--description-template")?;
+    let inherit_labels = args.values_from_str("--inherit-label")?;
+    let glob: Option<String> = args.opt_value_from_str("--glob")?;
+    if let Some(pattern) = &glob {
+        glob::Pattern::new(pattern).with_context(|| format!("Invalid --glob '{}'", pattern))?;
+    }
+    let strict_ignore = args.contains("--strict-ignore");
+    let for_overrides = match args.opt_value_from_str::<_, PathBuf>("--for-overrides")? {
+        Some(path) => read_for_overrides(&path)?,
+        None => vec![],
+    };
+    let annotate_source = args.contains("--annotate-source");
+    let instant_absent_over_time = args
+        .opt_value_from_str::<_, String>("--instant-absent-over-time")?
+        .map(|duration| parse_duration(&duration).context("Invalid --instant-absent-over-time"))
+        .transpose()?;
+    ensure!(
+        !(instant_absent_over_time.is_some() && sustained_window.is_some()),
+        "--instant-absent-over-time and --sustained-window can't be used together"
+    );
+    let diff = args.contains("--diff");
+    ensure!(
+        !(diff && (check || write_baseline.is_some() || selector_delta)),
+        "--diff can't be used with --check, --write-baseline, or --selector-delta"
+    );
+    let max_name_length: Option<usize> = args.opt_value_from_str("--max-name-length")?;
+    ensure!(
+        max_name_length.map_or(true, |length| length >= MIN_MAX_NAME_LENGTH),
+        "--max-name-length must be at least {}",
+        MIN_MAX_NAME_LENGTH
+    );
+    let name_prefix: Option<String> = args.opt_value_from_str("--name-prefix")?;
+    if let Some(name_prefix) = &name_prefix {
+        // This regex is constant so panicing on it being incorrect is okay as
+        // it would be a developer error.
+        let valid_name_re = Regex::new("^[a-zA-Z_][a-zA-Z0-9_]*$").expect("invalid regex");
+        ensure!(
+            valid_name_re.is_match(name_prefix),
+            "--name-prefix '{}' must start with a letter or underscore and only have letters, digits, and underscores",
+            name_prefix
+        );
+    }
+    let files_from: Option<PathBuf> = args.opt_value_from_str("--files-from")?;
+    // Everything left should be a <PATH>, apart from any options we don't know
+    // about.
+    let (junk, free_args): (Vec<_>, Vec<_>) = args
+        .finish()
+        .into_iter()
+        .partition(|arg| arg.to_string_lossy().starts_with('-') && arg != "-");
+    if !junk.is_empty() {
+        log::warn!("Ignoring junk: {:?}", junk);
+    }
+    let (rules_dirs, rule_paths) = split_rules_dirs_from_paths(
+        free_args
+            .iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect(),
+    );
+    ensure!(
+        !rules_dirs.is_empty() || !rule_paths.is_empty() || files_from.is_some() || migrate,
+        "Either a rules directory or files <PATH>, or --files-from must be given"
+    );
+    let rules_from_stdin = rule_paths.iter().any(|path| is_stdio(Path::new(path)));
+    ensure!(
+        !rules_from_stdin
+            || (rule_paths.len() == 1 && rules_dirs.is_empty() && files_from.is_none()),
+        "A <PATH> of - can't be used with any other <PATH> or --files-from"
+    );
+    ensure!(
+        !rules_from_stdin || !is_stdio(&ignore_file),
+        "A <PATH> of - can't be used with --ignore-file - as stdin can only be read once"
+    );
+    let output_file = maybe_output_file
+        .or_else(|| {
+            if rules_from_stdin {
+                Some(PathBuf::from("-"))
+            } else {
+                None
+            }
+        })
+        .or_else(|| rules_dirs.first().map(|dir| dir.join("absent.rules.yml")))
+        .or_else(|| {
+            output_dir
+                .as_ref()
+                .map(|dir| dir.join(split_output_file_name(None)))
+        })
+        .ok_or_else(|| {
+            anyhow!("--output-file is required when <PATH> isn't a single rules directory")
+        })?;
+    let opts = Opts {
+        dry_run,
+        output_file,
+        rules_dirs,
+        rule_paths,
+        files_from,
+        ignore_file,
+        playbook_link,
+        prometheus_url,
+        tag_currently_absent,
+        prometheus_timeout,
+        annotate_count,
+        allow_duplicate_names,
+        high_cardinality_file,
+        lint_only,
+        for_from_range,
+        group_limit,
+        validate_fields,
+        combine_variants,
+        disabled_annotation,
+        label_order,
+        guard_metrics,
+        migrate,
+        expr_label,
+        extra_metrics,
+        candidate,
+        candidate_severity,
+        dedup_report,
+        warn_for_divergence,
+        strict,
+        metadata_file,
+        compact,
+        doctor,
+        ignore_range,
+        range_collapse_strategy,
+        rename_metrics,
+        no_trailing_newline,
+        group_by_label,
+        severity_for_floors,
+        for_by_severity,
+        dedup_scope,
+        errors_file,
+        rich_description,
+        emit_tests,
+        ignore_file_timeout,
+        ignore_file_cache,
+        expr_wrapper,
+        skip_vector_fallbacks,
+        profile,
+        records_output_file,
+        exprs_output_file,
+        expand_by,
+        normalize_exprs,
+        heuristic_for,
+        counter_for,
+        gauge_for,
+        hash_label,
+        ignore_comment_prefix,
+        sustained_window,
+        validate_only,
+        severity_label_key,
+        base_labels,
+        mixed_vector_preference,
+        group_meta,
+        write_baseline,
+        check,
+        baseline,
+        numeric_for_unit,
+        query_offset,
+        split_by_label,
+        output_dir,
+        selector_delta,
+        no_sort,
+        embed_params,
+        require_metric,
+        transform_script,
+        color,
+        merge_label_values,
+        plan_json,
+        severity,
+        no_default_labels,
+        labels,
+        min_for,
+        stdout,
+        format,
+        group_name,
+        summary_template,
+        description_template,
+        inherit_labels,
+        glob,
+        strict_ignore,
+        for_overrides,
+        annotate_source,
+        instant_absent_over_time,
+        diff,
+        split_by_dir,
+        max_name_length,
+        name_prefix,
+    };
+    Ok(opts)
+}
+
+/// Parse `--group-limit`, which has to be a positive integer.
+fn parse_group_limit(limit: &str) -> Result<u64> {
+    match limit.parse() {
+        Ok(limit) if limit > 0 => Ok(limit),
+        _ => Err(anyhow!(
+            "--group-limit must be a positive integer, not '{}'",
+            limit
+        )),
+    }
+}
+
+/// Work out what the <PATH> arguments are. A single argument that isn't a file,
+/// a glob, or `-` for stdin is the rules directory, like it's always been, even
+/// if it doesn't exist so that we can complain about it later. Otherwise every
+/// directory is a rules directory and anything else is a rules file or glob to
+/// read.
+fn split_rules_dirs_from_paths(paths: Vec<String>) -> (Vec<PathBuf>, Vec<String>) {
+    let is_glob = |path: &str| path.contains(|c| c == '*' || c == '?' || c == '[');
+    if paths.len() == 1
+        && !is_glob(&paths[0])
+        && !is_stdio(Path::new(&paths[0]))
+        && !Path::new(&paths[0]).is_file()
+    {
+        return (paths.into_iter().map(PathBuf::from).collect(), vec![]);
+    }
+    let (rules_dirs, paths): (Vec<String>, Vec<String>) = paths
+        .into_iter()
+        .partition(|path| !is_glob(path) && Path::new(path).is_dir());
+    (rules_dirs.into_iter().map(PathBuf::from).collect(), paths)
+}
+
+/// The selector for the series recorded by a rule with the `record` name
+/// `record_name`, if it's a valid metric name.
+///
+/// Record names are metric names so they're usually bare selectors, but
+/// [prometheus_parser] doesn't parse every metric name as one, e.g. names that
+/// are also PromQL keywords or functions. Those are turned into a selector
+/// directly instead.
+fn record_name_selector(record_name: &str) -> Option<prometheus_parser::Selector> {
+    if let Ok(prometheus_parser::Expression::Selector(selector)) =
+        prometheus_parser::parse_expr(record_name)
+    {
+        if selector.metric.is_some()
+            && selector.labels.is_empty()
+            && selector.range.is_none()
+            && selector.offset.is_none()
+            && selector.subquery.is_none()
+        {
+            return Some(selector);
+        }
+    }
+    // This regex is constant so panicing on it being incorrect is okay as it
+    // would be a developer error.
+    let metric_name_re = Regex::new("^[a-zA-Z_:][a-zA-Z0-9_:]*$").expect("invalid regex");
+    if metric_name_re.is_match(record_name) {
+        Some(prometheus_parser::Selector::new().metric(record_name))
+    } else {
+        None
+    }
+}
+
+/// Selectors can give their metric name as a `__name__` label instead, e.g.
+/// `{__name__="foo",job="x"}`, which [prometheus_parser] leaves as a selector
+/// with no metric. Move an equality `__name__` matcher into the metric so these
+/// are named and grouped the same as `foo{job="x"}`.
+fn promote_name_label_to_metric(
+    selector: &prometheus_parser::Selector,
+) -> prometheus_parser::Selector {
+    let mut selector = selector.clone();
+    if selector.metric.is_none() {
+        if let Some(index) = selector.labels.iter().position(|label| {
+            label.key == "__name__" && label.op == prometheus_parser::LabelOp::Equal
+        }) {
+            let name_label = selector.labels.remove(index);
+            selector.metric = Some(name_label.value);
+        }
+    }
+    selector
+}
+
+/// A label value from YAML as the string Prometheus treats it as. YAML is
+/// happy to turn label values into numbers and booleans, e.g. `priority: 1`,
+/// but anything that isn't a scalar isn't a label value at all.
+fn yaml_label_value(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// The values of group `labels` as strings, see [yaml_label_value]. Labels
+/// that aren't scalars are skipped with a warning.
+fn yaml_label_values(labels: &BTreeMap<String, Value>) -> BTreeMap<String, String> {
+    labels
+        .iter()
+        .filter_map(|(key, value)| match yaml_label_value(value) {
+            Some(value) => Some((key.clone(), value)),
+            None => {
+                log::warn!("Ignoring non-scalar group label '{}'", key);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Recording rules can add labels to the series they produce. Add those to the
+/// recording's `selector` as equality matchers so an absent rule for it only
+/// matches what the recording rule actually produces.
+fn add_recording_rule_labels(
+    mut selector: prometheus_parser::Selector,
+    rule: &PrometheusRule,
+) -> prometheus_parser::Selector {
+    if let Some(Value::Mapping(labels)) = rule.untyped_fields.get("labels") {
+        for (key, value) in labels {
+            let key = match key.as_str() {
+                Some(key) => key,
+                None => continue,
+            };
+            let value = match yaml_label_value(value) {
+                Some(value) => value,
+                None => {
+                    log::warn!("Ignoring non-scalar label '{}' on recording rule", key);
+                    continue;
+                }
+            };
+            if selector.labels.iter().any(|label| label.key == key) {
+                continue;
+            }
+            selector.labels.push(prometheus_parser::Label {
+                op: prometheus_parser::LabelOp::Equal,
+                key: key.to_string(),
+                value,
+                span: None,
+            });
+        }
+    }
+    selector
+}
+
+/// Strip a selector back to just its metric name, dropping any labels, range,
+/// and offset.
+fn strip_selector_to_metric(selector: &prometheus_parser::Selector) -> prometheus_parser::Selector {
+    prometheus_parser::Selector {
+        metric: selector.metric.clone(),
+        ..Default::default()
+    }
+}
+
+/// Print the AST [prometheus_parser] parses `expr` into along with the
+/// selectors we pull out of it.
+fn dump_ast(expr: &str) -> Result<()> {
+    let parsed = prometheus_parser::parse_expr(expr)
+        .map_err(|e| anyhow!("Failed to parse expression '{}': {}", expr, e))?;
+    println!("{:#?}", parsed);
+    println!();
+    println!("Selectors:");
+    for selector in get_selectors_from_expression(&parsed) {
+        println!("    {}", selector);
+    }
+    Ok(())
+}
+
+/// Wrap the given [prometheus_parser::Expression] in the applicable absent
+/// function.
+///
+/// Prometheus has two functions in the absent family, `absent` and
+/// `absent_over_time`
+/// (https://prometheus.io/docs/prometheus/latest/querying/functions/#absent).
+/// `absent` expects an instant-vector selector and `absent_over_time` expects a
+/// range-vector selector. We can easily differentiate between the two in
+/// [prometheus_parser]'s AST because the [prometheus_parser::Selector] struct
+/// will have a `range` if it is a range-vector selector and otherwise it's an
+/// instant-vector.
+fn wrap_selector_in_absent(selector: &prometheus_parser::Selector) -> prometheus_parser::Function {
+    let function_name = if selector.range.is_some() {
+        "absent_over_time"
+    } else {
+        "absent"
+    };
+    prometheus_parser::Function::new(function_name).arg(selector.clone().wrap())
+}
+
+/// Converting a BTreeMap to a serde_yaml::Value turns out to be a massive pain.
+/// The best I could find is converting it to an intermediate Mapping here. You
+/// can't convert a BTreeMap directly to a mapping, instead you need an Iterator
+/// with an Item type of (Value, Value). Hence the shenanigans below.
+fn btree_to_yaml_mapping<K: Into<Value> + Clone, V: Into<Value> + Clone>(
+    btree: BTreeMap<K, V>,
+) -> serde_yaml::Mapping {
+    btree
+        .into_iter()
+        .map(|(key, value)| -> (Value, Value) { (key.into(), value.into()) })
+        .collect()
+}
+
+/// Reorder `mapping` so the keys in `order` come first, in that order, followed
+/// by the rest of the keys alphabetically. This relies on [serde_yaml::Mapping]
+/// keeping insertion order when it's serialized.
+fn order_yaml_mapping(mapping: &serde_yaml::Mapping, order: &[String]) -> serde_yaml::Mapping {
+    let position = |key: &Value| {
+        key.as_str()
+            .and_then(|key| order.iter().position(|ordered| ordered == key))
+            .unwrap_or_else(|| order.len())
+    };
+    mapping
+        .iter()
+        .sorted_by(|(left, _), (right, _)| {
+            position(left).cmp(&position(right)).then_with(|| {
+                left.as_str()
+                    .unwrap_or_default()
+                    .cmp(right.as_str().unwrap_or_default())
+            })
+        })
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
     use anyhow::Result;
     use pretty_assertions::assert_eq;
     use xshell::cmd;
 
-    use super::*;
+    use super::*;
+
+    fn temp_file() -> Result<String> {
+        let tmp_file = tempfile::NamedTempFile::new()?;
+        // Unlink so we can write to it in this process but noone else can use it.
+        std::fs::remove_file(&tmp_file)?;
+        Ok(tmp_file.path().to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn test_wrap_selector_in_absent() {
+        let expr_and_expected = vec![
+            (
+                "stack:public_http_errors_5xx_non_L3:rate1m_sum",
+                "absent(stack:public_http_errors_5xx_non_L3:rate1m_sum)",
+            ),
+            (
+                r#"publicapi_http_errors_5xx_count{is_load_shedding!="true",slo="L1"}[30s]"#,
+                r#"absent_over_time(publicapi_http_errors_5xx_count{is_load_shedding!="true",slo="L1"}[30s])"#,
+            ),
+        ];
+        for (expr, expected_expr) in expr_and_expected {
+            let selector = if let prometheus_parser::Expression::Selector(s) =
+                prometheus_parser::parse_expr(expr).expect("failed to parse expression")
+            {
+                s
+            } else {
+                panic!("Expressions must be a selector");
+            };
+            let wrapped_in_absent = wrap_selector_in_absent(&selector);
+            // Make sure it produces valid syntax.
+            prometheus_parser::parse_expr(&wrapped_in_absent.to_string())
+                .expect("wrap_in_absent produce an invalid expression");
+            assert_eq!(wrapped_in_absent.to_string(), expected_expr);
+        }
+    }
+
+    #[test]
+    fn test_strip_selector_to_metric() {
+        let selector = if let prometheus_parser::Expression::Selector(s) =
+            prometheus_parser::parse_expr(r#"node_cpu{mode!="idle",cpu="0"}[5m] offset 1h"#)
+                .expect("failed to parse expression")
+        {
+            s
+        } else {
+            panic!("Expressions must be a selector");
+        };
+        assert_eq!(strip_selector_to_metric(&selector).to_string(), "node_cpu");
+    }
+
+    #[test]
+    fn test_get_selectors_from_file() {
+        let file_name = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/test_get_selectors_from_file.yml"
+        );
+        let actual_selectors: Vec<String> = get_selectors_in_file(file_name, &Opts::default())
+            .expect("failed to get selectors from file")
+            .iter()
+            .map(|it| it.selector.to_string())
+            .sorted()
+            .collect();
+        let mut expected_selectors = vec![
+            r#"node_load1{box_type="data-warehouse"}"#,
+            "a_recording:cpu",
+            r#"node_cpu{mode!="idle"}[1m]"#,
+        ];
+        expected_selectors.sort_unstable();
+        assert_eq!(actual_selectors, expected_selectors);
+    }
+
+    #[test]
+    fn test_load_files_from_manifest() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let manifest = dir.path().join("manifest.txt");
+        fs::write(
+            &manifest,
+            "# Team rules\nteam/*.rules.yml\n\n  /etc/prometheus/extra.yml  \n",
+        )
+        .expect("failed to write manifest");
+        let matchers = load_files_from_manifest(&manifest).expect("failed to load manifest");
+        assert_eq!(
+            matchers,
+            vec![
+                dir.path().join("team/*.rules.yml").display().to_string(),
+                "/etc/prometheus/extra.yml".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_malformed_rule_files() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let malformed = dir.path().join("malformed.rules.yml");
+        fs::write(&malformed, "groups:\n  - name: no_rules\n").expect("failed to write file");
+        let rule_files = vec![
+            concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/fixtures/test_get_selectors_from_file.yml"
+            )
+            .into(),
+            malformed,
+        ];
+        assert_eq!(find_malformed_rule_files(&rule_files), 1);
+    }
+
+    #[test]
+    fn test_get_selectors_in_generated_file() {
+        let output_file = temp_file().expect("failed to get temp file");
+        let config = PrometheusRulesConfig {
+            groups: vec![PrometheusRuleGroup {
+                name: "absent_label_alerts".into(),
+                rules: vec![PrometheusRule {
+                    expr: "absent_over_time(some_metric[5m])".into(),
+                    untyped_fields: btree_map! {
+                        "alert" => "absent_some_metric_5m",
+                        "for" => "2h"
+                    },
+                    ..Default::default()
+                }],
+                limit: None,
+                labels: BTreeMap::new(),
+                query_offset: None,
+                extra_fields: BTreeMap::new(),
+            }],
+        };
+        write_generated_config_to_file(&output_file, &config, &Opts::default())
+            .expect("failed to write config");
+        let selectors =
+            get_selectors_in_generated_file(&output_file).expect("failed to get selectors");
+        assert_eq!(selectors.len(), 1);
+        assert_eq!(selectors[0].selector.to_string(), "some_metric[5m]");
+        assert_eq!(
+            selectors[0].rule.untyped_fields.get("for"),
+            Some(&Value::String("2h".into()))
+        );
+
+        let not_generated = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/test_get_selectors_from_file.yml"
+        );
+        assert!(get_selectors_in_generated_file(not_generated).is_err());
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        assert_eq!(
+            unified_diff(
+                Path::new("absent.rules.yml"),
+                "groups:\n- name: a\n  rules: []\n",
+                "groups:\n- name: b\n  rules: []\n"
+            ),
+            "--- a/absent.rules.yml
++++ b/absent.rules.yml
+@@ -1,3 +1,3 @@
+ groups:
+-- name: a
++- name: b
+   rules: []
+"
+        );
+    }
+
+    #[test]
+    fn test_selector_delta() {
+        let selectors = |exprs: Vec<&str>| -> Vec<SelectorWithOriginRule> {
+            exprs
+                .into_iter()
+                .map(|expr| SelectorWithOriginRule {
+                    selector: prometheus_parser::Selector {
+                        metric: Some(expr.into()),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                })
+                .collect()
+        };
+        let (added, removed) = selector_delta(
+            &selectors(vec!["kept", "removed", "removed"]),
+            &selectors(vec!["added", "kept"]),
+        );
+        assert_eq!(added, vec!["added"]);
+        assert_eq!(removed, vec!["removed"]);
+    }
+
+    #[test]
+    fn test_format_delta_line() {
+        assert_eq!(format_delta_line('+', "foo", false), "+ foo");
+        assert_eq!(format_delta_line('-', "foo", false), "- foo");
+        assert_eq!(format_delta_line('+', "foo", true), "\x1b[32m+ foo\x1b[0m");
+        assert_eq!(format_delta_line('-', "foo", true), "\x1b[31m- foo\x1b[0m");
+    }
+
+    #[test]
+    fn test_get_extra_selectors() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let extra_metrics = dir.path().join("extra_metrics.txt");
+        fs::write(
+            &extra_metrics,
+            "# Third party exporters\nvendor_up\nvendor_queue_depth{queue=\"main\"}\n",
+        )
+        .expect("failed to write extra metrics");
+        let selectors: Vec<String> = get_extra_selectors(&extra_metrics)
+            .expect("failed to get extra selectors")
+            .iter()
+            .map(|it| it.selector.to_string())
+            .collect();
+        assert_eq!(
+            selectors,
+            vec!["vendor_up", r#"vendor_queue_depth{queue="main"}"#]
+        );
+
+        fs::write(&extra_metrics, "sum(vendor_up)\n").expect("failed to write extra metrics");
+        assert!(get_extra_selectors(&extra_metrics).is_err());
+    }
+
+    #[test]
+    fn test_get_selectors_from_expression() {
+        let expr_and_expected = vec![
+            (
+                "stack:public_http_errors_5xx_non_L3:rate1m_sum",
+                vec!["stack:public_http_errors_5xx_non_L3:rate1m_sum"],
+            ),
+            (
+                r#"publicapi_http_errors_5xx_count{is_load_shedding!="true",slo="L1"}[30s]"#,
+                vec![r#"publicapi_http_errors_5xx_count{is_load_shedding!="true",slo="L1"}[30s]"#],
+            ),
+            ("(month() > bool 9) + (month() < bool 4)", vec![]),
+            (
+                r#"count(max by(stack_id) (up{job="rabbitmq"} == 1))"#,
+                vec![r#"up{job="rabbitmq"}"#],
+            ),
+            (
+                r#"up{job="aws_rds"} == 1 unless aws_rds_free_storage_space_minimum{dbinstance_identifier=~"live-db-.\\d"}"#,
+                vec![
+                    r#"up{job="aws_rds"}"#,
+                    r#"aws_rds_free_storage_space_minimum{dbinstance_identifier=~"live-db-.\\d"}"#,
+                ],
+            ),
+            (
+                r#"sum(irate(publicapi_http_request_count[30s])) by (stack_id, slo, route, method) and on(stack_id) slb_live_stack_number{slb="prod"} == 1"#,
+                vec![
+                    "publicapi_http_request_count[30s]",
+                    r#"slb_live_stack_number{slb="prod"}"#,
+                ],
+            ),
+            // Scalar arguments mixed in with the vector shouldn't get picked
+            // up.
+            ("clamp(some_metric, 0, 100)", vec!["some_metric"]),
+            (
+                r#"clamp_min(some_metric{job="x"}, 0)"#,
+                vec![r#"some_metric{job="x"}"#],
+            ),
+            (
+                "clamp_max(rate(some_metric[5m]), 100)",
+                vec!["some_metric[5m]"],
+            ),
+            ("round(some_metric, 5)", vec!["some_metric"]),
+            (
+                "clamp_max(some_metric, scalar(other_metric))",
+                vec!["some_metric", "other_metric"],
+            ),
+            // Subqueries.
+            (
+                "max_over_time(rate(http_requests_total[5m])[30m:1m])",
+                vec!["http_requests_total[5m]"],
+            ),
+            (
+                r#"max_over_time(some_metric{job="x"}[30m:1m])"#,
+                vec![r#"some_metric{job="x"}"#],
+            ),
+            (
+                "min_over_time(max_over_time(rate(http_requests_total[5m])[30m:1m])[1h:5m])",
+                vec!["http_requests_total[5m]"],
+            ),
+            (
+                "avg_over_time((some_metric + other_metric[5m:])[1h:])",
+                vec!["some_metric", "other_metric"],
+            ),
+        ];
+        for (expr, expected_selectors) in expr_and_expected {
+            let parsed = prometheus_parser::parse_expr(expr).expect("failed to parse expression");
+            let selectors: Vec<String> = get_selectors_from_expression(&parsed)
+                .iter()
+                .map(|s| s.to_string())
+                .collect();
+            assert_eq!(selectors, expected_selectors);
+        }
+    }
+
+    #[test]
+    fn test_has_vector_fallback() {
+        let expr_and_expected = vec![
+            ("some_metric or vector(0)", true),
+            ("(sum(some_metric) or (vector(0)))", true),
+            ("some_metric or other_metric or vector(0)", true),
+            ("(some_metric or vector(0)) > 1", false),
+            ("some_metric or other_metric", false),
+            ("some_metric > 1", false),
+        ];
+        for (expr, expected) in expr_and_expected {
+            let parsed = prometheus_parser::parse_expr(expr).expect("failed to parse expression");
+            assert_eq!(has_vector_fallback(&parsed), expected, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_get_selectors_from_unparseable_expression() {
+        let expr_and_expected = vec![
+            (
+                r#"sort_by_label(sum by (job) (rate(http_requests_total{code="500"}[5m])), "job")"#,
+                vec![r#"http_requests_total{code="500"}"#],
+            ),
+            (
+                r#"info(up{job="api"} offset 5m, {k8s_cluster_name=~".+"}) unless on(job) maintenance"#,
+                vec![r#"up{job="api"}"#, "maintenance"],
+            ),
+            (
+                "histogram_avg(rate(request_duration_seconds[5m]))",
+                vec!["request_duration_seconds"],
+            ),
+            ("vector(1) > bool 0", vec![]),
+        ];
+        for (expr, expected_selectors) in expr_and_expected {
+            let selectors: Vec<String> = get_selectors_from_unparseable_expression(expr)
+                .iter()
+                .map(|s| s.to_string())
+                .collect();
+            assert_eq!(selectors, expected_selectors);
+        }
+    }
+
+    #[test]
+    fn test_build_absent_selector_alert_name() {
+        let expr_and_expected = vec![
+            ("stile_log_messages_logged_count{level=~\"error|fatal\",client_sent!=\"true\"}[15m]", "absent_stile_log_messages_logged_count_level_regexequal_error_fatal_client_sent_notequal_true_15m"),
+            ("stack:error_log:rate15m_sum", "absent_stack:error_log:rate15m_sum"),
+            ("publicapi_http_errors_5xx_count{is_load_shedding!=\"true\",is_internal_admin=\"false\",slo!=\"L3\"}[1m]", "absent_publicapi_http_errors_5xx_count_is_load_shedding_notequal_true_is_internal_admin_equal_false_slo_notequal_L3_1m"),
+            ("publicapi_http_response_time_bucket[1m]", "absent_publicapi_http_response_time_bucket_1m"),
+            (r#"aws_elasticache_evictions_maximum{cache_cluster_id=~"prod-redis-shard-.*"}"#, "absent_aws_elasticache_evictions_maximum_cache_cluster_id_regexequal_prod_redis_shard___")
+        ];
+        for (expr, expected_name) in expr_and_expected {
+            let selector = if let prometheus_parser::Expression::Selector(s) =
+                prometheus_parser::parse_expr(expr).expect("failed to parse expression")
+            {
+                s
+            } else {
+                panic!("Expressions must be a selector");
+            };
+            let name = build_absent_selector_alert_name(&selector, "absent");
+            assert_eq!(name, expected_name);
+        }
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_name_prefix() {
+        let selectors = vec![SelectorWithOriginRule {
+            selector: prometheus_parser::Selector {
+                metric: Some("foo".into()),
+                range: Some(prometheus_parser::PromDuration::Minutes(5)),
+                ..Default::default()
+            },
+            ..Default::default()
+        }];
+        for (name_prefix, expected_name) in vec![
+            (None, "absent_foo_5m"),
+            (Some("NoData"), "NoData_foo_5m"),
+            (Some("NoData_"), "NoData_foo_5m"),
+            (Some("_team_a"), "_team_a_foo_5m"),
+        ] {
+            let opts = Opts {
+                name_prefix: name_prefix.map(String::from),
+                ..Default::default()
+            };
+            let rule = merge_selectors_into_rule(&selectors, &opts, None);
+            assert_eq!(rule.name(), Some(expected_name));
+        }
+    }
+
+    #[test]
+    fn test_get_selectors_from_file_skips_disabled_rules() {
+        let file_name = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/test_disabled_rules.rules.yml"
+        );
+        let opts = Opts {
+            disabled_annotation: Some("disabled".into()),
+            ..Default::default()
+        };
+        let actual_selectors: Vec<String> = get_selectors_in_file(file_name, &opts)
+            .expect("failed to get selectors from file")
+            .iter()
+            .map(|it| it.selector.to_string())
+            .collect();
+        assert_eq!(actual_selectors, vec!["enabled_metric"]);
+    }
+
+    #[test]
+    fn test_get_selectors_from_file_skips_guard_metrics() {
+        let file_name = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/test_guard_metrics.rules.yml"
+        );
+        let opts = Opts {
+            guard_metrics: vec!["maintenance_mode".into()],
+            ..Default::default()
+        };
+        let actual_selectors: Vec<String> = get_selectors_in_file(file_name, &opts)
+            .expect("failed to get selectors from file")
+            .iter()
+            .map(|it| it.selector.to_string())
+            .collect();
+        assert_eq!(actual_selectors, vec!["some_metric", "other_metric"]);
+    }
+
+    #[test]
+    fn test_labeled_recording_rule_selectors() {
+        let file_name = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/test_labeled_recording_rule.rules.yml"
+        );
+        let actual_selectors: Vec<String> = get_selectors_in_file(file_name, &Opts::default())
+            .expect("failed to get selectors from file")
+            .iter()
+            .map(|it| it.selector.to_string())
+            .collect();
+        assert_eq!(
+            actual_selectors,
+            vec![
+                "requests_total[5m]",
+                r#"job:requests:rate5m{team="platform",tier="1"}"#
+            ]
+        );
+    }
+
+    #[test]
+    fn test_record_name_selector() {
+        let record_names_and_expected = vec![
+            ("job:requests:rate5m", Some("job:requests:rate5m")),
+            ("foo:bar:rate", Some("foo:bar:rate")),
+            ("some_metric", Some("some_metric")),
+            // Names the parser would see as something other than a selector.
+            ("sum", Some("sum")),
+            ("offset", Some("offset")),
+            ("bool", Some("bool")),
+            // Not metric names at all.
+            (r#"foo{job="x"}"#, None),
+            ("foo[5m]", None),
+            ("foo-bar", None),
+            ("1foo", None),
+            ("", None),
+        ];
+        for (record_name, expected) in record_names_and_expected {
+            assert_eq!(
+                record_name_selector(record_name)
+                    .map(|selector| selector.to_string())
+                    .as_deref(),
+                expected,
+                "for record name '{}'",
+                record_name
+            );
+        }
+    }
+
+    #[test]
+    fn test_rename_metrics() {
+        let file_name = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/test_rename_metrics.rules.yml"
+        );
+        let opts = Opts {
+            rename_metrics: btree_map! {
+                "old_metric" => "new_metric"
+            },
+            ..Default::default()
+        };
+        let selectors =
+            get_selectors_in_file(file_name, &opts).expect("failed to get selectors from file");
+        let config = generate_config(selectors, &opts).expect("failed to generate config");
+        let names: Vec<&str> = config.groups[0]
+            .rules
+            .iter()
+            .filter_map(|rule| rule.name())
+            .collect();
+        assert_eq!(names, vec!["absent_new_metric"]);
+    }
+
+    #[test]
+    fn test_generate_config_group_by_label() {
+        let selectors: Vec<SelectorWithOriginRule> = vec![
+            r#"foo{service="b"}"#,
+            r#"bar{service="a"}"#,
+            "baz",
+            r#"qux{service!="a"}"#,
+        ]
+        .into_iter()
+        .map(|expr| {
+            let selector = if let prometheus_parser::Expression::Selector(s) =
+                prometheus_parser::parse_expr(expr).expect("failed to parse expression")
+            {
+                s
+            } else {
+                panic!("Expressions must be a selector");
+            };
+            SelectorWithOriginRule {
+                selector,
+                ..Default::default()
+            }
+        })
+        .collect();
+        let opts = Opts {
+            group_by_label: Some("service".into()),
+            ..Default::default()
+        };
+        let config = generate_config(selectors, &opts).expect("failed to generate config");
+        let groups: Vec<(&str, usize)> = config
+            .groups
+            .iter()
+            .map(|group| (group.name.as_str(), group.rules.len()))
+            .collect();
+        assert_eq!(
+            groups,
+            vec![
+                ("absent_label_alerts", 2),
+                ("absent_service_a", 1),
+                ("absent_service_b", 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_generate_config_dedup_scope() {
+        let selectors: Vec<SelectorWithOriginRule> = vec!["a", "a", "b"]
+            .into_iter()
+            .map(|group_name| SelectorWithOriginRule {
+                selector: prometheus_parser::Selector {
+                    metric: Some("some_metric".into()),
+                    ..Default::default()
+                },
+                group_name: group_name.into(),
+                ..Default::default()
+            })
+            .collect();
+        for (dedup_scope, expected_rules) in vec![(DedupScope::Global, 1), (DedupScope::Group, 2)] {
+            let opts = Opts {
+                dedup_scope,
+                ..Default::default()
+            };
+            let config =
+                generate_config(selectors.clone(), &opts).expect("failed to generate config");
+            assert_eq!(config.groups[0].rules.len(), expected_rules);
+        }
+    }
+
+    #[test]
+    fn test_generate_config_mixed_vector_preference() {
+        let selectors: Vec<SelectorWithOriginRule> = vec![
+            None,
+            Some(prometheus_parser::PromDuration::Minutes(5)),
+            Some(prometheus_parser::PromDuration::Minutes(10)),
+        ]
+        .into_iter()
+        .map(|range| SelectorWithOriginRule {
+            selector: prometheus_parser::Selector {
+                metric: Some("some_metric".into()),
+                range,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .collect();
+        let preference_and_expected = vec![
+            (
+                MixedVectorPreference::Both,
+                vec![
+                    "absent(some_metric)",
+                    "absent_over_time(some_metric[10m])",
+                    "absent_over_time(some_metric[5m])",
+                ],
+            ),
+            (
+                MixedVectorPreference::Range,
+                vec!["absent_over_time(some_metric[10m])"],
+            ),
+            (MixedVectorPreference::Instant, vec!["absent(some_metric)"]),
+        ];
+        for (mixed_vector_preference, expected_exprs) in preference_and_expected {
+            let opts = Opts {
+                mixed_vector_preference,
+                ..Default::default()
+            };
+            let config =
+                generate_config(selectors.clone(), &opts).expect("failed to generate config");
+            let exprs: Vec<&str> = config.groups[0]
+                .rules
+                .iter()
+                .map(|rule| rule.expr.as_str())
+                .sorted()
+                .collect();
+            assert_eq!(exprs, expected_exprs, "{:?}", mixed_vector_preference);
+        }
+    }
+
+    #[test]
+    fn test_group_extra_fields_round_trip() {
+        let input = "groups:\n  - name: some_group\n    partial_response_strategy: warn\n    rules:\n      - alert: some_alert\n        expr: some_metric > 1\n";
+        let config: PrometheusRulesConfig =
+            serde_yaml::from_str(input).expect("failed to parse rules");
+        assert_eq!(
+            config.groups[0]
+                .extra_fields
+                .get("partial_response_strategy"),
+            Some(&Value::String("warn".into()))
+        );
+        let output = serde_yaml::to_string(&config).expect("failed to serialize rules");
+        assert!(output.contains("partial_response_strategy: warn"));
+    }
+
+    #[test]
+    fn test_generate_config_group_meta() {
+        let opts = Opts {
+            group_meta: btree_map! {
+                "partial_response_strategy" => "warn"
+            },
+            ..Default::default()
+        };
+        let config = generate_config(vec![], &opts).expect("failed to generate config");
+        assert_eq!(
+            config.groups[0]
+                .extra_fields
+                .get("partial_response_strategy"),
+            Some(&Value::String("warn".into()))
+        );
+        assert_eq!(
+            parse_group_meta("owner = team-a").expect("failed to parse group meta"),
+            ("owner".to_string(), "team-a".to_string())
+        );
+        assert!(parse_group_meta("name=other").is_err());
+        assert!(parse_group_meta("no_value").is_err());
+    }
+
+    #[test]
+    fn test_generate_config_query_offset() {
+        let config = generate_config(vec![], &Opts::default()).expect("failed to generate config");
+        assert_eq!(config.groups[0].query_offset, None);
+        assert!(!serde_yaml::to_string(&config)
+            .expect("failed to serialize config")
+            .contains("query_offset"));
+        let opts = Opts {
+            query_offset: Some(prometheus_parser::PromDuration::Minutes(5)),
+            ..Default::default()
+        };
+        let config = generate_config(vec![], &opts).expect("failed to generate config");
+        assert_eq!(config.groups[0].query_offset.as_deref(), Some("5m"));
+    }
+
+    #[test]
+    fn test_generate_configs_by_label() {
+        let selector_with_team =
+            |metric: &str, group_team: Option<&str>, rule_team: Option<&str>| {
+                let mut group_labels = BTreeMap::new();
+                if let Some(team) = group_team {
+                    group_labels.insert("team".to_string(), team.to_string());
+                }
+                let mut untyped_fields = BTreeMap::new();
+                if let Some(team) = rule_team {
+                    let labels: BTreeMap<String, String> = btree_map! {
+                        "team" => team
+                    };
+                    untyped_fields.insert(
+                        "labels".to_string(),
+                        Value::Mapping(btree_to_yaml_mapping(labels)),
+                    );
+                }
+                SelectorWithOriginRule {
+                    selector: prometheus_parser::Selector {
+                        metric: Some(metric.into()),
+                        ..Default::default()
+                    },
+                    rule: PrometheusRule {
+                        expr: metric.into(),
+                        untyped_fields,
+                        ..Default::default()
+                    },
+                    group_labels,
+                    ..Default::default()
+                }
+            };
+        let selectors = vec![
+            selector_with_team("foo", Some("a"), None),
+            selector_with_team("bar", Some("a"), Some("b")),
+            selector_with_team("baz", None, None),
+            // Used by both teams so it isn't owned by either.
+            selector_with_team("qux", Some("a"), None),
+            selector_with_team("qux", Some("b"), None),
+        ];
+        let opts = Opts {
+            split_by_label: Some("team".into()),
+            ..Default::default()
+        };
+        let configs =
+            generate_configs_by_label(selectors, &opts).expect("failed to generate configs");
+        let rule_counts: Vec<(Option<&str>, usize)> = configs
+            .iter()
+            .map(|(value, config)| (value.as_deref(), config.groups[0].rules.len()))
+            .collect();
+        assert_eq!(rule_counts, vec![(None, 2), (Some("a"), 1), (Some("b"), 1)]);
+    }
+
+    #[test]
+    fn test_generate_configs_by_dir() {
+        let selector_in = |metric: &str, source_file: &str| SelectorWithOriginRule {
+            selector: prometheus_parser::Selector {
+                metric: Some(metric.into()),
+                ..Default::default()
+            },
+            source_file: source_file.into(),
+            ..Default::default()
+        };
+        let selectors = vec![
+            selector_in("foo", "rules/team-a/foo.rules.yml"),
+            selector_in("bar", "rules/team-a/nested/bar.rules.yml"),
+            selector_in("baz", "rules/team-b/baz.rules.yml"),
+            selector_in("qux", "rules/top.rules.yml"),
+            // Used under both subdirectories so it's in both files.
+            selector_in("quux", "rules/team-a/foo.rules.yml"),
+            selector_in("quux", "rules/team-b/baz.rules.yml"),
+        ];
+        let opts = Opts {
+            rules_dirs: vec!["rules".into()],
+            split_by_dir: true,
+            ..Default::default()
+        };
+        let configs =
+            generate_configs_by_label(selectors, &opts).expect("failed to generate configs");
+        let rule_names: Vec<(Option<&str>, Vec<&str>)> = configs
+            .iter()
+            .map(|(subdir, config)| {
+                (
+                    subdir.as_deref(),
+                    config.groups[0]
+                        .rules
+                        .iter()
+                        .filter_map(|rule| rule.name())
+                        .collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            rule_names,
+            vec![
+                (None, vec!["absent_qux"]),
+                (
+                    Some("team-a"),
+                    vec!["absent_bar", "absent_foo", "absent_quux"]
+                ),
+                (Some("team-b"), vec!["absent_baz", "absent_quux"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rules_subdir() {
+        let rules_dirs: Vec<PathBuf> = vec!["rules".into(), "other/rules".into()];
+        let subdir = |source_file: &str| rules_subdir(Path::new(source_file), &rules_dirs);
+        assert_eq!(
+            subdir("rules/team-a/foo.rules.yml").as_deref(),
+            Some("team-a")
+        );
+        assert_eq!(
+            subdir("rules/team-a/b/c.rules.yml").as_deref(),
+            Some("team-a")
+        );
+        assert_eq!(
+            subdir("other/rules/team-b/x.rules.yml").as_deref(),
+            Some("team-b")
+        );
+        assert_eq!(subdir("rules/top.rules.yml"), None);
+        assert_eq!(subdir("elsewhere/team-a/foo.rules.yml"), None);
+    }
+
+    #[test]
+    fn test_generate_config_disambiguates_colliding_names() {
+        let selector_with_slo = |slo: &str| SelectorWithOriginRule {
+            selector: prometheus_parser::Selector {
+                metric: Some("foo".into()),
+                labels: vec![prometheus_parser::Label::new(
+                    prometheus_parser::LabelOp::RegexEqual,
+                    "slo",
+                    slo,
+                )],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let selectors = vec![
+            selector_with_slo("L1|L2"),
+            selector_with_slo("L1_L2"),
+            selector_with_slo("L3"),
+        ];
+        let config = generate_config(selectors.clone(), &Opts::default())
+            .expect("failed to generate config");
+        let names: Vec<&str> = config.groups[0]
+            .rules
+            .iter()
+            .filter_map(|rule| rule.name())
+            .collect();
+        assert_eq!(names.len(), 3);
+        assert_ne!(names[0], names[1]);
+        for name in &names[..2] {
+            assert!(
+                name.starts_with("absent_foo_slo_regexequal_L1_L2_"),
+                "unexpected name {}",
+                name
+            );
+        }
+        assert_eq!(names[2], "absent_foo_slo_regexequal_L3");
+        // With --allow-duplicate-names the names are left alone.
+        let opts = Opts {
+            allow_duplicate_names: true,
+            ..Default::default()
+        };
+        let config = generate_config(selectors, &opts).expect("failed to generate config");
+        let names: Vec<&str> = config.groups[0]
+            .rules
+            .iter()
+            .filter_map(|rule| rule.name())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "absent_foo_slo_regexequal_L1_L2",
+                "absent_foo_slo_regexequal_L1_L2",
+                "absent_foo_slo_regexequal_L3",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_truncate_alert_name() {
+        let name = "absent_foo_job_equal_some_really_long_job_name";
+        assert_eq!(truncate_alert_name(name, 100), name);
+        assert_eq!(truncate_alert_name(name, name.len()), name);
+        let truncated = truncate_alert_name(name, 20);
+        assert_eq!(truncated, format!("absent_foo_{}", short_hash(name)));
+        assert_eq!(truncated.len(), 19);
+        let other = truncate_alert_name("absent_foo_job_equal_some_other_job_name", 20);
+        assert!(other.starts_with("absent_foo_"));
+        assert_ne!(truncated, other);
+        let shortest = truncate_alert_name(name, MIN_MAX_NAME_LENGTH);
+        assert_eq!(shortest, format!("a_{}", short_hash(name)));
+    }
+
+    #[test]
+    fn test_generate_config_max_name_length() {
+        let selectors: Vec<SelectorWithOriginRule> = vec!["short", "a_much_longer_metric_name"]
+            .into_iter()
+            .map(|metric| SelectorWithOriginRule {
+                selector: prometheus_parser::Selector {
+                    metric: Some(metric.into()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .collect();
+        let opts = Opts {
+            max_name_length: Some(20),
+            ..Default::default()
+        };
+        let config = generate_config(selectors, &opts).expect("failed to generate config");
+        let names: Vec<&str> = config.groups[0]
+            .rules
+            .iter()
+            .filter_map(|rule| rule.name())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                format!(
+                    "absent_a_mu_{}",
+                    short_hash("absent_a_much_longer_metric_name")
+                )
+                .as_str(),
+                "absent_short",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disambiguate_alert_names() {
+        let rule_named = |name: &str| {
+            let mut untyped_fields = BTreeMap::new();
+            untyped_fields.insert("alert".to_string(), Value::String(name.into()));
+            PrometheusRule {
+                expr: "vector(1)".into(),
+                untyped_fields,
+                ..Default::default()
+            }
+        };
+        let mut rules = vec![
+            rule_named("absent_a"),
+            rule_named("absent_a"),
+            rule_named("absent_b"),
+            rule_named("absent_b"),
+            rule_named("absent_c"),
+        ];
+        let selector_keys: Vec<String> = vec!["a|b", "a_b", "b", "b", "c"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        disambiguate_alert_names(&mut rules, &selector_keys);
+        let names: Vec<&str> = rules.iter().filter_map(|rule| rule.name()).collect();
+        assert_eq!(
+            names,
+            vec![
+                format!("absent_a_{}", short_hash("a|b")).as_str(),
+                format!("absent_a_{}", short_hash("a_b")).as_str(),
+                "absent_b",
+                "absent_b",
+                "absent_c",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_output_file_name() {
+        assert_eq!(split_output_file_name(Some("team-a")), "team-a.rules.yml");
+        assert_eq!(split_output_file_name(Some("../a b")), "___a_b.rules.yml");
+        assert_eq!(split_output_file_name(None), "unowned.rules.yml");
+    }
+
+    #[test]
+    fn test_find_split_output_files() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let selector_in = |file_name: &str, team: Option<&str>| {
+            let source_file = dir.path().join(file_name);
+            fs::write(&source_file, "groups: []\n").expect("failed to write rules file");
+            let mut selector = SelectorWithOriginRule {
+                source_file,
+                ..Default::default()
+            };
+            if let Some(team) = team {
+                selector.group_labels.insert("team".into(), team.into());
+            }
+            selector
+        };
+        // The rules directory is the output directory too.
+        let selectors = vec![
+            selector_in("foo.rules.yml", Some("a")),
+            selector_in("bar.rules.yml", None),
+            // Written by an earlier run.
+            selector_in("a.rules.yml", Some("a")),
+            selector_in("unowned.rules.yml", None),
+        ];
+        let opts = Opts {
+            split_by_label: Some("team".into()),
+            output_dir: Some(dir.path().into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            find_split_output_files(dir.path(), &selectors, &opts),
+            vec![
+                dir.path().join("a.rules.yml"),
+                dir.path().join("unowned.rules.yml")
+            ]
+            .into_iter()
+            .collect()
+        );
+    }
+
+    #[test]
+    fn test_generate_config_no_sort() {
+        let selectors: Vec<SelectorWithOriginRule> = vec!["b", "a", "b", "c"]
+            .into_iter()
+            .map(|metric| SelectorWithOriginRule {
+                selector: prometheus_parser::Selector {
+                    metric: Some(metric.into()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .collect();
+        for (no_sort, expected_names) in vec![
+            (false, vec!["absent_a", "absent_b", "absent_c"]),
+            (true, vec!["absent_b", "absent_a", "absent_c"]),
+        ] {
+            let opts = Opts {
+                no_sort,
+                ..Default::default()
+            };
+            let config =
+                generate_config(selectors.clone(), &opts).expect("failed to generate config");
+            let names: Vec<&str> = config.groups[0]
+                .rules
+                .iter()
+                .filter_map(|rule| rule.name())
+                .collect();
+            assert_eq!(names, expected_names);
+        }
+    }
+
+    #[test]
+    fn test_get_selectors_and_errors_in_file() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let rules_file = dir.path().join("broken.rules.yml");
+        fs::write(
+            &rules_file,
+            "groups:\n  - name: broken\n    rules:\n      - alert: unparseable\n        expr: (((\n      - alert: fine\n        expr: some_metric > 1\n",
+        )
+        .expect("failed to write rules file");
+        let (selectors, errors) = get_selectors_and_errors_in_file(&rules_file, &Opts::default())
+            .expect("failed to load rules file");
+        assert_eq!(selectors.len(), 1);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].group.as_deref(), Some("broken"));
+        assert_eq!(errors[0].rule_name.as_deref(), Some("unparseable"));
+        assert_eq!(errors[0].expr.as_deref(), Some("((("));
+        assert!(get_selectors_in_file(&rules_file, &Opts::default()).is_err());
+    }
+
+    #[test]
+    fn test_negative_offset_selector_names() {
+        let file_name = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/test_negative_offset.rules.yml"
+        );
+        let valid_name_re = Regex::new("^[a-zA-Z_][a-zA-Z0-9_:]*$").expect("invalid regex");
+        let names: Vec<String> = get_selectors_in_file(file_name, &Opts::default())
+            .expect("failed to get selectors from file")
+            .iter()
+            .map(|it| build_absent_selector_alert_name(&it.selector, "absent"))
+            .collect();
+        assert!(names.contains(&"absent_other_metric_5m_offset_5m".to_string()));
+        for name in &names {
+            assert!(valid_name_re.is_match(name), "invalid alert name {}", name);
+        }
+    }
+
+    #[test]
+    fn test_strip_at_modifiers() {
+        let expr_and_expected = vec![
+            (
+                "rate(foo[5m] @ start() offset 5m)",
+                ("rate(foo[5m] offset 5m)", vec![12]),
+            ),
+            ("bar offset 1h @ end()", ("bar offset 1h", vec![13])),
+            (
+                r#"baz{email="a@1"} @ 1609746000"#,
+                (r#"baz{email="a@1"}"#, vec![16]),
+            ),
+            ("foo @ start() + bar @ end()", ("foo + bar", vec![3, 9])),
+            ("foo", ("foo", vec![])),
+        ];
+        for (expr, (expected, expected_positions)) in expr_and_expected {
+            assert_eq!(
+                strip_at_modifiers(expr),
+                (expected.to_string(), expected_positions)
+            );
+        }
+    }
+
+    #[test]
+    fn test_has_at_modifier() {
+        let selector = prometheus_parser::Selector {
+            span: Some(prometheus_parser::Span { start: 5, end: 22 }),
+            ..prometheus_parser::Selector::new().metric("foo")
+        };
+        assert!(has_at_modifier(&selector, &[12]));
+        assert!(has_at_modifier(&selector, &[30, 22]));
+        assert!(!has_at_modifier(&selector, &[5]));
+        assert!(!has_at_modifier(&selector, &[]));
+        let spanless = prometheus_parser::Selector::new().metric("foo");
+        assert!(!has_at_modifier(&spanless, &[12]));
+    }
+
+    #[test]
+    fn test_at_modifier_selectors() {
+        let file_name = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/test_at_modifier.rules.yml"
+        );
+        let selectors = get_selectors_in_file(file_name, &Opts::default())
+            .expect("failed to get selectors from file");
+        let selector_strings: Vec<String> =
+            selectors.iter().map(|it| it.selector.to_string()).collect();
+        // Only the selector without an `@` modifier is kept, with its offset.
+        assert_eq!(selector_strings, vec!["qux offset 5m"]);
+        assert_eq!(
+            build_absent_selector_alert_name(&selectors[0].selector, "absent"),
+            "absent_qux_offset_5m"
+        );
+    }
+
+    #[test]
+    fn test_name_label_matcher_selectors() {
+        let file_name = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/test_name_label_matcher.rules.yml"
+        );
+        let names: Vec<String> = get_selectors_in_file(file_name, &Opts::default())
+            .expect("failed to get selectors from file")
+            .iter()
+            .map(|it| build_absent_selector_alert_name(&it.selector, "absent"))
+            .collect();
+        assert_eq!(
+            names,
+            vec!["absent_foo_job_equal_x", "absent_foo_job_equal_x"]
+        );
+    }
+
+    #[test]
+    fn test_prometheus_rule_unknown_fields() {
+        let rule = PrometheusRule {
+            expr: "some_metric > 1".into(),
+            untyped_fields: btree_map! {
+                "alert" => "some_alert",
+                "fo" => "1h",
+                "labels" => serde_yaml::Mapping::new()
+            },
+            ..Default::default()
+        };
+        assert_eq!(rule.name(), Some("some_alert"));
+        assert_eq!(rule.unknown_fields(), vec!["fo"]);
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule() {
+        let selectors = vec![
+            SelectorWithOriginRule {
+                selector: prometheus_parser::Selector {
+                    metric: Some("some_metric".into()),
+                    ..Default::default()
+                },
+                rule: PrometheusRule {
+                    expr: "some_metric".into(),
+                    untyped_fields: btree_map! {
+                        "for" => "1h"
+                    },
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            SelectorWithOriginRule {
+                selector: prometheus_parser::Selector {
+                    metric: Some("some_metric".into()),
+                    ..Default::default()
+                },
+                rule: PrometheusRule {
+                    expr: "some_metric".into(),
+                    untyped_fields: btree_map! {
+                        "for" => "5h"
+                    },
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        ];
+        let expected_rule: PrometheusRule = PrometheusAbsentSelectorAlertRule {
+            name: "absent_some_metric".into(),
+            expr: "absent(some_metric)".into(),
+            selector_expr: "some_metric".into(),
+            r#for: prometheus_parser::PromDuration::Hours(1),
+            labels: btree_map! {
+                "severity" => "low_urgency_page"
+            },
+            extra_annotations: BTreeMap::new(),
+            metric_help: None,
+            summary_template: None,
+            description_template: None,
+            origin_rules: vec![],
+        }
+        .into();
+        let actual_rule = merge_selectors_into_rule(&selectors, &Opts::default(), None);
+        assert_eq!(actual_rule, expected_rule);
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_min_1h() {
+        let playbook_link = "test".to_string();
+        let selectors = vec![
+            SelectorWithOriginRule {
+                selector: prometheus_parser::Selector {
+                    metric: Some("some_metric".into()),
+                    ..Default::default()
+                },
+                rule: PrometheusRule {
+                    expr: "some_metric".into(),
+                    untyped_fields: btree_map! {
+                        "for" => "1m"
+                    },
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            SelectorWithOriginRule {
+                selector: prometheus_parser::Selector {
+                    metric: Some("some_metric".into()),
+                    ..Default::default()
+                },
+                rule: PrometheusRule {
+                    expr: "some_metric".into(),
+                    untyped_fields: btree_map! {
+                        "for" => "30s"
+                    },
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        ];
+        let expected_rule: PrometheusRule = PrometheusAbsentSelectorAlertRule {
+            name: "absent_some_metric".into(),
+            expr: "absent(some_metric)".into(),
+            selector_expr: "some_metric".into(),
+            r#for: prometheus_parser::PromDuration::Hours(1),
+            labels: btree_map! {
+                "severity" => "low_urgency_page",
+                "playbook" => "test"
+            },
+            extra_annotations: BTreeMap::new(),
+            metric_help: None,
+            summary_template: None,
+            description_template: None,
+            origin_rules: vec![],
+        }
+        .into();
+        let opts = Opts {
+            playbook_link: Some(playbook_link),
+            ..Default::default()
+        };
+        let actual_rule = merge_selectors_into_rule(&selectors, &opts, None);
+        assert_eq!(actual_rule, expected_rule);
+    }
+
+    #[test]
+    fn test_find_currently_absent_metrics() {
+        let grouped_selectors: Vec<(String, Vec<SelectorWithOriginRule>)> =
+            vec![Some("foo"), Some("bar"), Some("foo"), Some("broken"), None]
+                .into_iter()
+                .map(|metric| {
+                    let selector = SelectorWithOriginRule {
+                        selector: prometheus_parser::Selector {
+                            metric: metric.map(String::from),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    };
+                    (selector.sort_key(), vec![selector])
+                })
+                .collect();
+        let queried = std::cell::RefCell::new(vec![]);
+        let absent = find_currently_absent_metrics(&grouped_selectors, |metric| {
+            queried.borrow_mut().push(metric.to_string());
+            match metric {
+                "foo" => Ok(false),
+                "bar" => Ok(true),
+                _ => Err(anyhow::anyhow!("connection refused")),
+            }
+        });
+        // A failed query is treated as having data so the rule isn't lost.
+        assert_eq!(absent, vec!["foo"]);
+        // Each metric is only queried once and selectors without one aren't.
+        assert_eq!(queried.into_inner(), vec!["foo", "bar", "broken"]);
+    }
+
+    #[test]
+    fn test_tag_currently_absent() {
+        let mut rule = PrometheusRule {
+            expr: "absent(foo)".into(),
+            ..Default::default()
+        };
+        tag_currently_absent(&mut rule);
+        assert_eq!(
+            rule.untyped_fields
+                .get("labels")
+                .and_then(|labels| labels.get("currently_absent")),
+            Some(&Value::String("true".into()))
+        );
+        // Existing labels are kept.
+        let mut rule = merge_selectors_into_rule(
+            &[SelectorWithOriginRule {
+                selector: prometheus_parser::Selector {
+                    metric: Some("foo".into()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }],
+            &Opts::default(),
+            None,
+        );
+        tag_currently_absent(&mut rule);
+        let labels = rule.untyped_fields.get("labels").expect("no labels");
+        assert_eq!(
+            labels.get("severity"),
+            Some(&Value::String("low_urgency_page".into()))
+        );
+        assert_eq!(
+            labels.get("currently_absent"),
+            Some(&Value::String("true".into()))
+        );
+    }
+
+    #[test]
+    fn test_non_string_group_labels() {
+        let file_name = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/test_non_string_group_labels.rules.yml"
+        );
+        let config = load_rules_from_file(file_name).expect("failed to load rules file");
+        let group_labels = yaml_label_values(&config.groups[0].labels);
+        assert_eq!(
+            group_labels,
+            btree_map! {
+                "absent_severity" => "page",
+                "page" => "true",
+                "priority" => "1",
+                "team" => "platform"
+            }
+        );
+    }
+
+    #[test]
+    fn test_dedup_report() {
+        let selector = |metric: &str, labels: Vec<(&str, &str)>| SelectorWithOriginRule {
+            selector: prometheus_parser::Selector {
+                metric: Some(metric.into()),
+                labels: labels
+                    .into_iter()
+                    .map(|(key, value)| prometheus_parser::Label {
+                        op: prometheus_parser::LabelOp::Equal,
+                        key: key.into(),
+                        value: value.into(),
+                        span: None,
+                    })
+                    .collect(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let selectors = vec![
+            selector("foo", vec![("a", "1"), ("b", "2")]),
+            // Only the order of the matchers differs so it's the same rule.
+            selector("foo", vec![("b", "2"), ("a", "1")]),
+            selector("foo", vec![("a", "1"), ("b", "2")]),
+            selector("bar", vec![]),
+        ];
+        let dedup_report = temp_file().expect("failed to get temp file");
+        let opts = Opts {
+            dedup_report: Some(dedup_report.clone().into()),
+            ..Default::default()
+        };
+        let config = generate_config(selectors, &opts).expect("failed to generate config");
+        let names: Vec<&str> = config.groups[0]
+            .rules
+            .iter()
+            .filter_map(|rule| rule.name())
+            .collect();
+        assert_eq!(names.len(), 2);
+        let report: BTreeMap<String, Vec<String>> = serde_json::from_str(
+            &fs::read_to_string(&dedup_report).expect("failed to read dedup report"),
+        )
+        .expect("dedup report isn't valid JSON");
+        let expected_report: BTreeMap<String, Vec<String>> = btree_map! {
+            names[0] => vec!["bar".to_string()],
+            names[1] => vec![r#"foo{a="1",b="2"}"#.to_string(), r#"foo{b="2",a="1"}"#.to_string()]
+        };
+        assert_eq!(report, expected_report);
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_group_labels() {
+        let group_labels: BTreeMap<String, String> = btree_map! {
+            "absent_min_for" => "2h",
+            "absent_severity" => "critical"
+        };
+        let selectors: Vec<SelectorWithOriginRule> = vec!["30m", "3h"]
+            .into_iter()
+            .map(|r#for| SelectorWithOriginRule {
+                selector: prometheus_parser::Selector {
+                    metric: Some("some_metric".into()),
+                    ..Default::default()
+                },
+                rule: PrometheusRule {
+                    expr: "some_metric".into(),
+                    untyped_fields: btree_map! {
+                        "for" => r#for
+                    },
+                    ..Default::default()
+                },
+                group_labels: group_labels.clone(),
+                ..Default::default()
+            })
+            .collect();
+        let rule = merge_selectors_into_rule(&selectors, &Opts::default(), None);
+        assert_eq!(
+            rule.untyped_fields.get("for"),
+            Some(&Value::String("2h".into()))
+        );
+        assert_eq!(
+            rule.untyped_fields
+                .get("labels")
+                .and_then(|labels| labels.get("severity")),
+            Some(&Value::String("critical".into()))
+        );
+
+        // Groups that don't agree fall back to the defaults.
+        let mut disagreeing_selectors = selectors;
+        disagreeing_selectors[1].group_labels = BTreeMap::new();
+        let rule = merge_selectors_into_rule(&disagreeing_selectors, &Opts::default(), None);
+        assert_eq!(
+            rule.untyped_fields.get("for"),
+            Some(&Value::String("1h".into()))
+        );
+        assert_eq!(
+            rule.untyped_fields
+                .get("labels")
+                .and_then(|labels| labels.get("severity")),
+            Some(&Value::String("low_urgency_page".into()))
+        );
+    }
+
+    #[test]
+    fn test_parse_rule_for() {
+        let seconds = Opts {
+            numeric_for_unit: Some("s".to_string()),
+            ..Default::default()
+        };
+        let value_and_expected = vec![
+            (Value::String("5m".into()), true, true),
+            (Value::Number(300.into()), false, true),
+            (Value::String("300".into()), false, true),
+            (Value::String("30x".into()), false, false),
+            (Value::Bool(true), false, false),
+        ];
+        for (value, ok_by_default, ok_with_unit) in value_and_expected {
+            assert_eq!(
+                parse_rule_for(&value, &Opts::default()).is_ok(),
+                ok_by_default,
+                "{:?}",
+                value
+            );
+            assert_eq!(
+                parse_rule_for(&value, &seconds).is_ok(),
+                ok_with_unit,
+                "{:?}",
+                value
+            );
+        }
+        assert_eq!(
+            parse_rule_for(&Value::Number(300.into()), &seconds).expect("failed to parse for"),
+            prometheus_parser::PromDuration::Seconds(300)
+        );
+        let error = parse_rule_for(&Value::Number(300.into()), &Opts::default())
+            .expect_err("numeric for without a unit should fail");
+        assert!(format!("{:#}", error).contains("no unit"));
+    }
+
+    #[test]
+    fn test_for_divergence() {
+        let selectors: Vec<SelectorWithOriginRule> = vec!["30s", "6h", "not a duration"]
+            .into_iter()
+            .map(|duration| SelectorWithOriginRule {
+                rule: PrometheusRule {
+                    expr: "some_metric".into(),
+                    untyped_fields: btree_map! {
+                        "for" => duration
+                    },
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .collect();
+        assert_eq!(for_divergence(&selectors), Some(720.0));
+        assert_eq!(for_divergence(&selectors[..1]), None);
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_for_from_range() {
+        let opts = Opts {
+            for_from_range: true,
+            ..Default::default()
+        };
+        let lower_floor_opts = Opts {
+            for_from_range: true,
+            min_for: Some(prometheus_parser::PromDuration::Minutes(5)),
+            ..Default::default()
+        };
+        let for_and_expected = vec![
+            // Still has the 1h floor.
+            (prometheus_parser::PromDuration::Minutes(10), &opts, "1h"),
+            (prometheus_parser::PromDuration::Hours(3), &opts, "3h"),
+            (
+                prometheus_parser::PromDuration::Minutes(10),
+                &lower_floor_opts,
+                "10m",
+            ),
+        ];
+        for (range, opts, expected_for) in for_and_expected {
+            let selectors = vec![SelectorWithOriginRule {
+                selector: prometheus_parser::Selector {
+                    metric: Some("some_metric".into()),
+                    range: Some(range),
+                    ..Default::default()
+                },
+                rule: PrometheusRule {
+                    expr: format!("rate(some_metric[{}])", range),
+                    untyped_fields: BTreeMap::new(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }];
+            let rule = merge_selectors_into_rule(&selectors, opts, None);
+            assert_eq!(
+                rule.expr,
+                format!("absent_over_time(some_metric[{}])", range)
+            );
+            assert_eq!(
+                rule.untyped_fields.get("for"),
+                Some(&Value::String(expected_for.into()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_ignore_range() {
+        let selectors: Vec<SelectorWithOriginRule> = vec![
+            prometheus_parser::PromDuration::Minutes(5),
+            prometheus_parser::PromDuration::Minutes(1),
+            prometheus_parser::PromDuration::Minutes(10),
+        ]
+        .into_iter()
+        .map(|range| SelectorWithOriginRule {
+            selector: prometheus_parser::Selector {
+                metric: Some("some_metric".into()),
+                range: Some(range),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .collect();
+        let strategy_and_expected = vec![
+            (
+                RangeCollapseStrategy::Longest,
+                "absent_over_time(some_metric[10m])",
+            ),
+            (
+                RangeCollapseStrategy::Shortest,
+                "absent_over_time(some_metric[1m])",
+            ),
+        ];
+        for (range_collapse_strategy, expected_expr) in strategy_and_expected {
+            let opts = Opts {
+                ignore_range: true,
+                range_collapse_strategy,
+                ..Default::default()
+            };
+            let rule = merge_selectors_into_rule(&selectors, &opts, None);
+            assert_eq!(rule.expr, expected_expr);
+        }
+    }
+
+    #[test]
+    fn test_merge_label_values() {
+        use prometheus_parser::LabelOp::{Equal, NotEqual, RegexEqual};
+        let selector =
+            |labels: Vec<(prometheus_parser::LabelOp, &str, &str)>| prometheus_parser::Selector {
+                metric: Some("foo".into()),
+                labels: labels
+                    .into_iter()
+                    .map(|(op, key, value)| prometheus_parser::Label {
+                        op,
+                        key: key.into(),
+                        value: value.into(),
+                        span: None,
+                    })
+                    .collect(),
+                ..Default::default()
+            };
+        let merged = merge_label_values(&[
+            selector(vec![(Equal, "env", "b.prod"), (Equal, "job", "x")]),
+            selector(vec![(Equal, "job", "x"), (Equal, "env", "a+1")]),
+            selector(vec![(Equal, "env", "b.prod"), (Equal, "job", "x")]),
+        ])
+        .expect("selectors should be merged");
+        assert_eq!(
+            merged,
+            selector(vec![
+                (RegexEqual, "env", r"a\\+1|b\\.prod"),
+                (Equal, "job", "x")
+            ])
+        );
+        // More than one label differs.
+        assert_eq!(
+            merge_label_values(&[
+                selector(vec![(Equal, "env", "a"), (Equal, "job", "x")]),
+                selector(vec![(Equal, "env", "b"), (Equal, "job", "y")]),
+            ]),
+            None
+        );
+        // Only equality matchers can be merged.
+        assert_eq!(
+            merge_label_values(&[
+                selector(vec![(NotEqual, "env", "a")]),
+                selector(vec![(NotEqual, "env", "b")]),
+            ]),
+            None
+        );
+        // Different label keys.
+        assert_eq!(
+            merge_label_values(&[
+                selector(vec![(Equal, "env", "a")]),
+                selector(vec![(Equal, "job", "a")]),
+            ]),
+            None
+        );
+        assert_eq!(merge_label_values(&[selector(vec![])]), None);
+        // Values with string escapes aren't merged rather than escaped twice.
+        for escaped_value in vec![r#"a\"b"#, r"\\d"] {
+            assert_eq!(
+                merge_label_values(&[
+                    selector(vec![(Equal, "env", escaped_value)]),
+                    selector(vec![(Equal, "env", "c")]),
+                ]),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_merge_label_values() {
+        let selectors_with_envs = |envs: Vec<&str>| -> Vec<SelectorWithOriginRule> {
+            envs.into_iter()
+                .map(|env| SelectorWithOriginRule {
+                    selector: prometheus_parser::Selector {
+                        metric: Some("foo".into()),
+                        labels: vec![prometheus_parser::Label {
+                            op: prometheus_parser::LabelOp::Equal,
+                            key: "env".into(),
+                            value: env.into(),
+                            span: None,
+                        }],
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                })
+                .collect()
+        };
+        let opts = Opts {
+            merge_label_values: true,
+            ..Default::default()
+        };
+        let rule = merge_selectors_into_rule(&selectors_with_envs(vec!["a", "b"]), &opts, None);
+        assert_eq!(rule.expr, r#"absent(foo{env=~"a|b"})"#);
+        assert_eq!(rule.name(), Some("absent_foo"));
+        prometheus_parser::parse_expr(&rule.expr).expect("merged expression should parse");
+        // Regex metacharacters are escaped with a backslash, which has to be
+        // escaped in the PromQL string too.
+        let rule =
+            merge_selectors_into_rule(&selectors_with_envs(vec!["b.prod", "a+1"]), &opts, None);
+        assert_eq!(rule.expr, r#"absent(foo{env=~"a\\+1|b\\.prod"})"#);
+        prometheus_parser::parse_expr(&rule.expr).expect("merged expression should parse");
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_normalize_exprs() {
+        let selector = if let prometheus_parser::Expression::Selector(s) =
+            prometheus_parser::parse_expr(r#"some_metric{job="x",env="prod",app!="a"}"#)
+                .expect("failed to parse expression")
+        {
+            s
+        } else {
+            panic!("Expressions must be a selector");
+        };
+        let selectors = vec![SelectorWithOriginRule {
+            selector,
+            ..Default::default()
+        }];
+        let opts = Opts {
+            normalize_exprs: true,
+            ..Default::default()
+        };
+        let rule = merge_selectors_into_rule(&selectors, &opts, None);
+        assert_eq!(
+            rule.expr,
+            r#"absent(some_metric{app!="a",env="prod",job="x"})"#
+        );
+    }
+
+    #[test]
+    fn test_generate_config_label_order() {
+        let selector_with_labels = |labels: Vec<(&str, &str)>| {
+            let selector = prometheus_parser::Selector {
+                metric: Some("foo".into()),
+                ..Default::default()
+            };
+            SelectorWithOriginRule {
+                selector: labels.into_iter().fold(selector, |selector, (key, value)| {
+                    with_equal_matcher(&selector, key, value)
+                }),
+                ..Default::default()
+            }
+        };
+        let config = generate_config(
+            vec![
+                selector_with_labels(vec![("b", "2"), ("a", "1")]),
+                selector_with_labels(vec![("a", "1"), ("b", "2")]),
+            ],
+            &Opts::default(),
+        )
+        .expect("failed to generate config");
+        let rules = &config.groups[0].rules;
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].expr, r#"absent(foo{a="1",b="2"})"#);
+        // A selector only ever written one way keeps its order.
+        let config = generate_config(
+            vec![selector_with_labels(vec![("b", "2"), ("a", "1")])],
+            &Opts::default(),
+        )
+        .expect("failed to generate config");
+        assert_eq!(
+            config.groups[0].rules[0].expr,
+            r#"absent(foo{b="2",a="1"})"#
+        );
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_combine_variants() {
+        let selectors: Vec<SelectorWithOriginRule> = vec![
+            r#"some_metric{env="b"}"#,
+            r#"some_metric{env="a"}[5m]"#,
+            r#"some_metric{env="b"}"#,
+        ]
+        .into_iter()
+        .map(|expr| {
+            let selector = if let prometheus_parser::Expression::Selector(s) =
+                prometheus_parser::parse_expr(expr).expect("failed to parse expression")
+            {
+                s
+            } else {
+                panic!("Expressions must be a selector");
+            };
+            SelectorWithOriginRule {
+                selector,
+                rule: PrometheusRule {
+                    expr: expr.to_string(),
+                    untyped_fields: BTreeMap::new(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }
+        })
+        .collect();
+        let opts = Opts {
+            combine_variants: true,
+            ..Default::default()
+        };
+        let rule = merge_selectors_into_rule(&selectors, &opts, None);
+        assert_eq!(
+            rule.expr,
+            r#"absent_over_time(some_metric{env="a"}[5m]) or absent(some_metric{env="b"})"#
+        );
+        prometheus_parser::parse_expr(&rule.expr).expect("combined expression is invalid");
+        assert_eq!(
+            rule.untyped_fields.get("alert"),
+            Some(&Value::String("absent_some_metric".into()))
+        );
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_expr_label() {
+        let selectors = vec![SelectorWithOriginRule {
+            selector: prometheus_parser::Selector {
+                metric: Some("some_metric".into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }];
+        let opts = Opts {
+            expr_label: Some("absent_expr".into()),
+            ..Default::default()
+        };
+        let rule = merge_selectors_into_rule(&selectors, &opts, None);
+        assert_eq!(
+            rule.untyped_fields
+                .get("labels")
+                .and_then(|labels| labels.get("absent_expr")),
+            Some(&Value::String("some_metric".into()))
+        );
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_expr_wrapper() {
+        let selectors = vec![SelectorWithOriginRule {
+            selector: prometheus_parser::Selector {
+                metric: Some("some_metric".into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }];
+        let opts = Opts {
+            expr_wrapper: Some("count({absent}) > 0".into()),
+            ..Default::default()
+        };
+        let rule = merge_selectors_into_rule(&selectors, &opts, None);
+        assert_eq!(rule.expr, "count(absent(some_metric)) > 0");
+    }
+
+    #[test]
+    fn test_sanitize_label_value() {
+        assert_eq!(
+            sanitize_label_value("some_metric{job=\"a\tb\n\u{fffd}\"}"),
+            r#"some_metric{job="ab"}"#
+        );
+        let long_value = "a".repeat(MAX_LABEL_VALUE_LENGTH + 10);
+        let sanitized = sanitize_label_value(&long_value);
+        assert_eq!(sanitized.chars().count(), MAX_LABEL_VALUE_LENGTH);
+        assert!(sanitized.ends_with("..."));
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_severity_for_floors() {
+        let selectors = vec![SelectorWithOriginRule {
+            selector: prometheus_parser::Selector {
+                metric: Some("some_metric".into()),
+                ..Default::default()
+            },
+            group_labels: btree_map! {
+                "absent_severity" => "page"
+            },
+            ..Default::default()
+        }];
+        let opts_and_expected = vec![
+            (Opts::default(), "1h"),
+            (
+                Opts {
+                    severity_for_floors: true,
+                    ..Default::default()
+                },
+                "15m",
+            ),
+            (
+                Opts {
+                    severity_for_floors: true,
+                    for_by_severity: parse_for_by_severity("page=5m")
+                        .expect("failed to parse --for-by-severity"),
+                    ..Default::default()
+                },
+                "5m",
+            ),
+        ];
+        for (opts, expected_for) in opts_and_expected {
+            let rule = merge_selectors_into_rule(&selectors, &opts, None);
+            assert_eq!(
+                rule.untyped_fields.get("for"),
+                Some(&Value::String(expected_for.into()))
+            );
+        }
+        assert!(parse_for_by_severity("page").is_err());
+    }
+
+    #[test]
+    fn test_describe_origin_rules() {
+        let selectors: Vec<SelectorWithOriginRule> = (0..12)
+            .map(|i| SelectorWithOriginRule {
+                rule: PrometheusRule {
+                    expr: "some_metric".into(),
+                    untyped_fields: btree_map! {
+                        "alert" => format!("alert_{}", i % 11)
+                    },
+                    ..Default::default()
+                },
+                source_file: "some.rules.yml".into(),
+                ..Default::default()
+            })
+            .collect();
+        let described = describe_origin_rules(&selectors);
+        assert_eq!(described.len(), MAX_DESCRIBED_ORIGIN_RULES + 1);
+        assert_eq!(described[0], "'alert_0' in some.rules.yml");
+        assert_eq!(described.last().map(String::as_str), Some("1 more"));
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_hash_label() {
+        let opts = Opts {
+            hash_label: true,
+            ..Default::default()
+        };
+        let selectors_for_metric = |metric: &str| {
+            vec![SelectorWithOriginRule {
+                selector: prometheus_parser::Selector {
+                    metric: Some(metric.into()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }]
+        };
+        let rule_hash = |rule: &PrometheusRule| {
+            rule.untyped_fields
+                .get("labels")
+                .and_then(|labels| labels.get("rule_hash"))
+                .and_then(|hash| hash.as_str())
+                .map(str::to_string)
+        };
+        let first = merge_selectors_into_rule(&selectors_for_metric("foo"), &opts, None);
+        let second = merge_selectors_into_rule(&selectors_for_metric("foo"), &opts, None);
+        let other = merge_selectors_into_rule(&selectors_for_metric("bar"), &opts, None);
+        assert!(rule_hash(&first).is_some());
+        assert_eq!(rule_hash(&first), rule_hash(&second));
+        assert_ne!(rule_hash(&first), rule_hash(&other));
+        let without_hash =
+            merge_selectors_into_rule(&selectors_for_metric("foo"), &Opts::default(), None);
+        assert_eq!(rule_hash(&without_hash), None);
+    }
+
+    #[test]
+    fn test_rule_definition_hash() {
+        let hash = rule_definition_hash(
+            "foo",
+            "absent(foo)",
+            &prometheus_parser::PromDuration::Hours(1),
+        );
+        assert_eq!(hash.len(), 8);
+        assert_eq!(
+            hash,
+            rule_definition_hash(
+                "foo",
+                "absent(foo)",
+                &prometheus_parser::PromDuration::Hours(1),
+            )
+        );
+        assert_ne!(
+            hash,
+            rule_definition_hash(
+                "foo",
+                "absent(foo)",
+                &prometheus_parser::PromDuration::Hours(2),
+            )
+        );
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_sustained_window() {
+        let selectors_with_range = |range| {
+            vec![SelectorWithOriginRule {
+                selector: prometheus_parser::Selector {
+                    metric: Some("foo".into()),
+                    range,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }]
+        };
+        let opts = Opts {
+            sustained_window: Some(prometheus_parser::PromDuration::Hours(2)),
+            ..Default::default()
+        };
+        let rule = merge_selectors_into_rule(&selectors_with_range(None), &opts, None);
+        assert_eq!(rule.expr, "absent_over_time(foo[2h])");
+        assert_eq!(
+            rule.untyped_fields.get("for"),
+            Some(&Value::String("2h".into()))
+        );
+        assert_eq!(rule.name(), Some("absent_foo"));
+
+        // Range selectors keep their own range and a short window doesn't
+        // lower the usual `for`.
+        let opts = Opts {
+            sustained_window: Some(prometheus_parser::PromDuration::Minutes(10)),
+            ..Default::default()
+        };
+        let rule = merge_selectors_into_rule(
+            &selectors_with_range(Some(prometheus_parser::PromDuration::Minutes(5))),
+            &opts,
+            None,
+        );
+        assert_eq!(rule.expr, "absent_over_time(foo[5m])");
+        assert_eq!(
+            rule.untyped_fields.get("for"),
+            Some(&Value::String("1h".into()))
+        );
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_instant_absent_over_time() {
+        let opts = Opts {
+            instant_absent_over_time: Some(prometheus_parser::PromDuration::Minutes(5)),
+            ..Default::default()
+        };
+        let selectors = vec![SelectorWithOriginRule {
+            selector: prometheus_parser::Selector {
+                metric: Some("foo".into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }];
+        let rule = merge_selectors_into_rule(&selectors, &opts, None);
+        assert_eq!(rule.expr, "absent_over_time(foo[5m])");
+        assert_eq!(
+            rule.untyped_fields.get("for"),
+            Some(&Value::String("1h".into()))
+        );
+        assert_eq!(rule.name(), Some("absent_foo"));
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_heuristic_for() {
+        let opts = Opts {
+            heuristic_for: true,
+            gauge_for: Some(prometheus_parser::PromDuration::Minutes(10)),
+            ..Default::default()
+        };
+        let metric_and_expected = vec![
+            ("http_requests_total", "2h"),
+            ("request_duration_seconds_count", "2h"),
+            ("node_load1", "10m"),
+        ];
+        for (metric, expected_for) in metric_and_expected {
+            let selectors = vec![SelectorWithOriginRule {
+                selector: prometheus_parser::Selector {
+                    metric: Some(metric.into()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }];
+            let rule = merge_selectors_into_rule(&selectors, &opts, None);
+            assert_eq!(
+                rule.untyped_fields.get("for"),
+                Some(&Value::String(expected_for.into())),
+                "{}",
+                metric
+            );
+        }
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_severity_label_key() {
+        let selectors = vec![SelectorWithOriginRule {
+            selector: prometheus_parser::Selector {
+                metric: Some("foo".into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }];
+        let opts = Opts {
+            severity_label_key: Some("priority".to_string()),
+            severity_for_floors: true,
+            for_by_severity: btree_map! {
+                "low_urgency_page" => prometheus_parser::PromDuration::Hours(3)
+            },
+            ..Default::default()
+        };
+        let rule = merge_selectors_into_rule(&selectors, &opts, None);
+        let labels = rule.untyped_fields.get("labels");
+        assert_eq!(
+            labels.and_then(|labels| labels.get("priority")),
+            Some(&Value::String("low_urgency_page".into()))
+        );
+        assert_eq!(labels.and_then(|labels| labels.get("severity")), None);
+        // The severity floors follow the label wherever it is.
+        assert_eq!(
+            rule.untyped_fields.get("for"),
+            Some(&Value::String("3h".into()))
+        );
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_severity() {
+        let selectors = vec![SelectorWithOriginRule {
+            selector: prometheus_parser::Selector {
+                metric: Some("foo".into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }];
+        let opts = Opts {
+            severity: Some("warning".to_string()),
+            ..Default::default()
+        };
+        let rule = merge_selectors_into_rule(&selectors, &opts, None);
+        assert_eq!(
+            rule.untyped_fields
+                .get("labels")
+                .and_then(|labels| labels.get("severity")),
+            Some(&Value::String("warning".into()))
+        );
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_no_default_labels() {
+        let selectors = vec![SelectorWithOriginRule {
+            selector: prometheus_parser::Selector {
+                metric: Some("foo".into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }];
+        let opts = Opts {
+            no_default_labels: true,
+            ..Default::default()
+        };
+        let rule = merge_selectors_into_rule(&selectors, &opts, None);
+        assert_eq!(rule.untyped_fields.get("labels"), None);
+        let opts = Opts {
+            playbook_link: Some("https://example.com".to_string()),
+            ..opts
+        };
+        let rule = merge_selectors_into_rule(&selectors, &opts, None);
+        let labels = rule.untyped_fields.get("labels");
+        assert_eq!(labels.and_then(|labels| labels.get("severity")), None);
+        assert_eq!(
+            labels.and_then(|labels| labels.get("playbook")),
+            Some(&Value::String("https://example.com".into()))
+        );
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_base_labels() {
+        let selector = SelectorWithOriginRule {
+            selector: prometheus_parser::Selector {
+                metric: Some("foo".into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let opts = Opts {
+            base_labels: btree_map! {
+                "environment" => "production",
+                "severity" => "warning"
+            },
+            ..Default::default()
+        };
+        let rule = merge_selectors_into_rule(&[selector.clone()], &opts, None);
+        let expected_labels: BTreeMap<String, String> = btree_map! {
+            "environment" => "production",
+            "severity" => "warning"
+        };
+        assert_eq!(
+            rule.untyped_fields.get("labels"),
+            Some(&Value::Mapping(btree_to_yaml_mapping(expected_labels)))
+        );
+
+        // Labels inherited from the rule's group win over base labels.
+        let inherited_selector = SelectorWithOriginRule {
+            group_labels: btree_map! {
+                "absent_severity" => "critical"
+            },
+            ..selector
+        };
+        let rule = merge_selectors_into_rule(&[inherited_selector], &opts, None);
+        assert_eq!(
+            rule.untyped_fields
+                .get("labels")
+                .and_then(|labels| labels.get("severity")),
+            Some(&Value::String("critical".into()))
+        );
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_labels() {
+        let selectors = vec![SelectorWithOriginRule {
+            selector: prometheus_parser::Selector {
+                metric: Some("foo".into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }];
+        let opts = Opts {
+            base_labels: btree_map! {
+                "team" => "infra"
+            },
+            labels: btree_map! {
+                "team" => "payments",
+                "severity" => "warning"
+            },
+            ..Default::default()
+        };
+        let rule = merge_selectors_into_rule(&selectors, &opts, None);
+        let expected_labels: BTreeMap<String, String> = btree_map! {
+            "team" => "payments",
+            "severity" => "warning"
+        };
+        assert_eq!(
+            rule.untyped_fields.get("labels"),
+            Some(&Value::Mapping(btree_to_yaml_mapping(expected_labels)))
+        );
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_min_for() {
+        let selectors = vec![SelectorWithOriginRule {
+            selector: prometheus_parser::Selector {
+                metric: Some("foo".into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }];
+        let opts = Opts {
+            min_for: Some(prometheus_parser::PromDuration::Minutes(30)),
+            ..Default::default()
+        };
+        let rule = merge_selectors_into_rule(&selectors, &opts, None);
+        assert_eq!(
+            rule.untyped_fields.get("for"),
+            Some(&Value::String("30m".into()))
+        );
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_inherit_labels() {
+        let selector_in_rule = |labels: BTreeMap<String, String>| SelectorWithOriginRule {
+            selector: prometheus_parser::Selector {
+                metric: Some("foo".into()),
+                ..Default::default()
+            },
+            rule: PrometheusRule {
+                expr: "foo".into(),
+                untyped_fields: btree_map! {
+                    "labels" => btree_to_yaml_mapping(labels)
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let selectors = vec![
+            selector_in_rule(btree_map! {
+                "team" => "payments",
+                "service" => "billing"
+            }),
+            selector_in_rule(btree_map! {
+                "team" => "accounts"
+            }),
+        ];
+        let opts = Opts {
+            inherit_labels: vec!["team".into(), "service".into(), "missing".into()],
+            ..Default::default()
+        };
+        let rule = merge_selectors_into_rule(&selectors, &opts, None);
+        let labels = rule.untyped_fields.get("labels");
+        assert_eq!(
+            labels.and_then(|labels| labels.get("team")),
+            Some(&Value::String("accounts".into()))
+        );
+        assert_eq!(
+            labels.and_then(|labels| labels.get("service")),
+            Some(&Value::String("billing".into()))
+        );
+        assert_eq!(labels.and_then(|labels| labels.get("missing")), None);
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_for_overrides() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let overrides_file = dir.path().join("overrides.yml");
+        fs::write(&overrides_file, "'node_.*': 6h\nnode_load1: 5m\n")
+            .expect("failed to write overrides");
+        let opts = Opts {
+            for_overrides: read_for_overrides(&overrides_file).expect("failed to read overrides"),
+            ..Default::default()
+        };
+        let rule_for = |metric: &str| {
+            let selectors = vec![SelectorWithOriginRule {
+                selector: prometheus_parser::Selector {
+                    metric: Some(metric.into()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }];
+            merge_selectors_into_rule(&selectors, &opts, None)
+                .untyped_fields
+                .get("for")
+                .cloned()
+        };
+        // The first match wins.
+        assert_eq!(rule_for("node_load1"), Some(Value::String("6h".into())));
+        assert_eq!(rule_for("other_metric"), Some(Value::String("1h".into())));
+        fs::write(&overrides_file, "node_load1: soon\n").expect("failed to write overrides");
+        assert!(read_for_overrides(&overrides_file).is_err());
+        fs::write(&overrides_file, "'node_(': 5m\n").expect("failed to write overrides");
+        assert!(read_for_overrides(&overrides_file).is_err());
+    }
+
+    #[test]
+    fn test_parse_label() {
+        assert_eq!(
+            parse_label("team=payments").expect("failed to parse label"),
+            ("team".to_string(), "payments".to_string())
+        );
+        assert!(parse_label("team").is_err());
+        assert!(parse_label("=payments").is_err());
+        assert!(parse_label("not-a-label=x").is_err());
+    }
 
-    fn temp_file() -> Result<String> {
-        let tmp_file = tempfile::NamedTempFile::new()?;
-        // Unlink so we can write to it in this process but noone else can use it.
-        std::fs::remove_file(&tmp_file)?;
-        Ok(tmp_file.path().to_str().unwrap().to_string())
+    #[test]
+    fn test_merge_selectors_into_rule_candidate() {
+        let selectors = vec![SelectorWithOriginRule {
+            selector: prometheus_parser::Selector {
+                metric: Some("some_metric".into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }];
+        let opts = Opts {
+            candidate: true,
+            ..Default::default()
+        };
+        let rule = merge_selectors_into_rule(&selectors, &opts, None);
+        let labels: BTreeMap<String, String> = btree_map! {
+            "candidate" => "true",
+            "severity" => "info"
+        };
+        assert_eq!(
+            rule.untyped_fields.get("labels"),
+            Some(&Value::Mapping(btree_to_yaml_mapping(labels)))
+        );
     }
 
     #[test]
-    fn test_wrap_selector_in_absent() {
-        let expr_and_expected = vec![
-            (
-                "stack:public_http_errors_5xx_non_L3:rate1m_sum",
-                "absent(stack:public_http_errors_5xx_non_L3:rate1m_sum)",
-            ),
-            (
-                r#"publicapi_http_errors_5xx_count{is_load_shedding!="true",slo="L1"}[30s]"#,
-                r#"absent_over_time(publicapi_http_errors_5xx_count{is_load_shedding!="true",slo="L1"}[30s])"#,
-            ),
-        ];
-        for (expr, expected_expr) in expr_and_expected {
-            let selector = if let prometheus_parser::Expression::Selector(s) =
-                prometheus_parser::parse_expr(expr).expect("failed to parse expression")
-            {
-                s
-            } else {
-                panic!("Expressions must be a selector");
+    fn test_merge_selectors_into_rule_annotate_count() {
+        let selectors: Vec<SelectorWithOriginRule> =
+            ["some_metric", "some_metric * 2", "some_metric > 3"]
+                .iter()
+                .map(|expr| SelectorWithOriginRule {
+                    selector: prometheus_parser::Selector {
+                        metric: Some("some_metric".into()),
+                        ..Default::default()
+                    },
+                    rule: PrometheusRule {
+                        expr: expr.to_string(),
+                        untyped_fields: BTreeMap::new(),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                })
+                .collect();
+        let opts = Opts {
+            annotate_count: true,
+            ..Default::default()
+        };
+        let rule = merge_selectors_into_rule(&selectors, &opts, None);
+        let count = rule
+            .untyped_fields
+            .get("annotations")
+            .and_then(|annotations| annotations.get("referenced_by_count"))
+            .cloned();
+        assert_eq!(count, Some(Value::String(selectors.len().to_string())));
+    }
+
+    #[test]
+    fn test_group_limit() {
+        assert_eq!(parse_group_limit("5").ok(), Some(5));
+        assert!(parse_group_limit("0").is_err());
+        assert!(parse_group_limit("-1").is_err());
+        let rendered = |group_limit: Option<u64>| {
+            let opts = Opts {
+                group_limit,
+                ..Default::default()
             };
-            let wrapped_in_absent = wrap_selector_in_absent(&selector);
-            // Make sure it produces valid syntax.
-            prometheus_parser::parse_expr(&wrapped_in_absent.to_string())
-                .expect("wrap_in_absent produce an invalid expression");
-            assert_eq!(wrapped_in_absent.to_string(), expected_expr);
+            let selectors = vec![SelectorWithOriginRule {
+                selector: prometheus_parser::Selector {
+                    metric: Some("foo".into()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }];
+            let config = generate_config(selectors, &opts).expect("failed to generate config");
+            render_generated_config(&config, &opts).expect("failed to render config")
+        };
+        assert!(rendered(Some(5)).contains("\n    limit: 5\n"));
+        assert!(!rendered(None).contains("limit:"));
+    }
+
+    #[test]
+    fn test_is_output_file_unmatched_in_rules_dir() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let rules_dir = dir.path().join("rules");
+        fs::create_dir_all(rules_dir.join("nested")).expect("failed to create rules dir");
+        let unmatched = |output_file: PathBuf| {
+            is_output_file_unmatched_in_rules_dir(&rules_dir, &output_file, &Opts::default())
+        };
+        // Never read or cleaned up.
+        assert!(unmatched(rules_dir.join("absent.yml")));
+        assert!(!unmatched(rules_dir.join("absent.rules.yml")));
+        assert!(!unmatched(rules_dir.join("nested/absent.rules.yml")));
+        // Nothing to do with the rules directory.
+        assert!(!unmatched(dir.path().join("absent.yml")));
+    }
+
+    #[test]
+    fn test_merge_selectors_into_rule_annotate_source() {
+        let selectors: Vec<SelectorWithOriginRule> =
+            vec!["b.rules.yml", "a.rules.yml", "b.rules.yml", ""]
+                .into_iter()
+                .map(|source_file| SelectorWithOriginRule {
+                    selector: prometheus_parser::Selector {
+                        metric: Some("some_metric".into()),
+                        ..Default::default()
+                    },
+                    source_file: source_file.into(),
+                    ..Default::default()
+                })
+                .collect();
+        let opts = Opts {
+            annotate_source: true,
+            ..Default::default()
+        };
+        let rule = merge_selectors_into_rule(&selectors, &opts, None);
+        let source_file = rule
+            .untyped_fields
+            .get("annotations")
+            .and_then(|annotations| annotations.get("source_file"))
+            .cloned();
+        assert_eq!(
+            source_file,
+            Some(Value::String("a.rules.yml, b.rules.yml".into()))
+        );
+    }
+
+    #[test]
+    fn test_prometheus_rule_from_prometheus_absent_selector_alert_rule() {
+        let rule: PrometheusRule = PrometheusAbsentSelectorAlertRule {
+            expr: "absent(some_expr)".into(),
+            r#for: prometheus_parser::PromDuration::Hours(1),
+            name: "this_thing".into(),
+            selector_expr: "some_expr".into(),
+            labels: btree_map! {
+                "severity" => "low_urgency_page"
+            },
+            extra_annotations: BTreeMap::new(),
+            metric_help: None,
+            summary_template: None,
+            description_template: None,
+            origin_rules: vec![],
         }
+        .into();
+        let annotations: BTreeMap<String, String> = btree_map! {
+            "description" => "No data for 'some_expr'. This alert rule was generated by prometheus-absent-data-alert-rule-generator.",
+            "summary" => "No data for 'some_expr'"
+        };
+        let labels: BTreeMap<String, String> = btree_map! {
+            "severity" => "low_urgency_page"
+        };
+        let expected_rule = PrometheusRule {
+            expr: "absent(some_expr)".into(),
+            untyped_fields: btree_map! {
+                "for" => "1h",
+                "alert" => "this_thing",
+                "annotations" => btree_to_yaml_mapping(annotations),
+                "labels" => btree_to_yaml_mapping(labels)
+            },
+            ..Default::default()
+        };
+        assert_eq!(rule, expected_rule);
     }
 
     #[test]
-    fn test_get_selectors_from_file() {
-        let file_name = concat!(
-            env!("CARGO_MANIFEST_DIR"),
-            "/fixtures/test_get_selectors_from_file.yml"
+    fn test_prometheus_rule_from_prometheus_absent_selector_alert_rule_with_templates() {
+        let rule: PrometheusRule = PrometheusAbsentSelectorAlertRule {
+            expr: "absent(some_expr)".into(),
+            r#for: prometheus_parser::PromDuration::Hours(1),
+            name: "this_thing".into(),
+            selector_expr: "some_expr".into(),
+            labels: BTreeMap::new(),
+            extra_annotations: BTreeMap::new(),
+            metric_help: Some("Number of things.".into()),
+            origin_rules: vec![],
+            summary_template: Some("Metric {{selector}} is absent".into()),
+            description_template: Some("{{selector}} has gone, says {{tool}}".into()),
+        }
+        .into();
+        let annotations = rule.untyped_fields.get("annotations");
+        assert_eq!(
+            annotations.and_then(|annotations| annotations.get("summary")),
+            Some(&Value::String("Metric some_expr is absent".into()))
         );
-        let actual_selectors: Vec<String> = get_selectors_in_file(file_name)
-            .expect("failed to get selectors from file")
+        assert_eq!(
+            annotations.and_then(|annotations| annotations.get("description")),
+            Some(&Value::String(
+                "some_expr has gone, says prometheus-absent-data-alert-rule-generator".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_prometheus_rule_from_prometheus_absent_selector_alert_rule_without_labels() {
+        let rule: PrometheusRule = PrometheusAbsentSelectorAlertRule {
+            expr: "absent(some_expr)".into(),
+            r#for: prometheus_parser::PromDuration::Hours(1),
+            name: "this_thing".into(),
+            selector_expr: "some_expr".into(),
+            labels: BTreeMap::new(),
+            extra_annotations: BTreeMap::new(),
+            metric_help: None,
+            summary_template: None,
+            description_template: None,
+            origin_rules: vec![],
+        }
+        .into();
+        assert_eq!(rule.untyped_fields.get("labels"), None);
+    }
+
+    #[test]
+    fn test_prometheus_rule_from_prometheus_absent_selector_alert_rule_with_metric_help() {
+        let rule: PrometheusRule = PrometheusAbsentSelectorAlertRule {
+            expr: "absent(some_expr)".into(),
+            r#for: prometheus_parser::PromDuration::Hours(1),
+            name: "this_thing".into(),
+            selector_expr: "some_expr".into(),
+            labels: BTreeMap::new(),
+            extra_annotations: BTreeMap::new(),
+            metric_help: Some("Number of things.".into()),
+            summary_template: None,
+            description_template: None,
+            origin_rules: vec![],
+        }
+        .into();
+        let description = rule
+            .untyped_fields
+            .get("annotations")
+            .and_then(|annotations| annotations.get("description"))
+            .cloned();
+        assert_eq!(
+            description,
+            Some(Value::String("No data for 'some_expr' (Number of things). This alert rule was generated by prometheus-absent-data-alert-rule-generator.".into()))
+        );
+    }
+
+    #[test]
+    fn test_to_compact_yaml() {
+        let config = PrometheusRulesConfig {
+            groups: vec![PrometheusRuleGroup {
+                name: "absent_label_alerts".into(),
+                rules: vec![PrometheusAbsentSelectorAlertRule {
+                    expr: r#"absent(some_metric{job="x"})"#.into(),
+                    r#for: prometheus_parser::PromDuration::Hours(1),
+                    name: "absent_some_metric_job_equal_x".into(),
+                    selector_expr: r#"some_metric{job="x"}"#.into(),
+                    labels: btree_map! {
+                        "severity" => "low_urgency_page"
+                    },
+                    extra_annotations: BTreeMap::new(),
+                    metric_help: None,
+                    summary_template: None,
+                    description_template: None,
+                    origin_rules: vec![],
+                }
+                .into()],
+                limit: None,
+                labels: BTreeMap::new(),
+                query_offset: None,
+                extra_fields: BTreeMap::new(),
+            }],
+        };
+        let compact = to_compact_yaml(&serde_yaml::to_value(&config).unwrap())
+            .expect("failed to serialize config");
+        assert!(compact.contains(r#"  labels: {"severity":"low_urgency_page"}"#));
+        // It has to read back the same.
+        let round_tripped: Value = serde_yaml::from_str(&compact).expect("invalid YAML");
+        assert_eq!(round_tripped, serde_yaml::to_value(&config).unwrap());
+    }
+
+    #[test]
+    fn test_write_generated_config_to_file_trailing_newline() {
+        let config = PrometheusRulesConfig { groups: vec![] };
+        for (no_trailing_newline, expected_ending) in vec![(false, "[]\n"), (true, "[]")] {
+            let output_file = temp_file().expect("failed to get temp file");
+            let opts = Opts {
+                no_trailing_newline,
+                ..Default::default()
+            };
+            write_generated_config_to_file(&output_file, &config, &opts)
+                .expect("failed to write config");
+            let contents = fs::read_to_string(&output_file).expect("failed to read output file");
+            assert!(contents.ends_with(expected_ending), "{:?}", contents);
+        }
+    }
+
+    #[test]
+    fn test_write_promtool_tests_to_file_without_rules() -> Result<()> {
+        let tests_file = temp_file()?;
+        write_promtool_tests_to_file(Path::new(&tests_file), &[])?;
+        let written: Value = serde_yaml::from_str(&fs::read_to_string(&tests_file)?)?;
+        assert_eq!(written["tests"], Value::Sequence(vec![]));
+        assert_eq!(written["rule_files"], Value::Sequence(vec![]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_config_strict_ignore() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let ignore_file = dir.path().join("ignore_metrics.txt");
+        fs::write(&ignore_file, "foo\nmetric:renamed_metric\n")
+            .expect("failed to write ignore file");
+        let selectors = vec![SelectorWithOriginRule {
+            selector: prometheus_parser::Selector {
+                metric: Some("foo".into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }];
+        let opts = Opts {
+            ignore_file,
+            ..Default::default()
+        };
+        // Only a warning by default.
+        let config = generate_config(selectors.clone(), &opts).expect("failed to generate config");
+        assert!(config.groups[0].rules.is_empty());
+        let opts = Opts {
+            strict_ignore: true,
+            ..opts
+        };
+        assert!(generate_config(selectors, &opts).is_err());
+    }
+
+    #[test]
+    fn test_generate_config_group_name() {
+        let opts = Opts {
+            group_name: Some("platform_absent_alerts".into()),
+            ..Default::default()
+        };
+        let config = generate_config(vec![], &opts).expect("failed to generate config");
+        assert_eq!(config.groups.len(), 1);
+        assert_eq!(config.groups[0].name, "platform_absent_alerts");
+    }
+
+    #[test]
+    fn test_render_generated_config_json() {
+        let config = generate_config(vec![], &Opts::default()).expect("failed to generate config");
+        let opts = Opts {
+            format: OutputFormat::Json,
+            ..Default::default()
+        };
+        let rendered = render_generated_config(&config, &opts).expect("failed to render config");
+        let json: serde_json::Value = serde_json::from_str(&rendered).expect("output isn't JSON");
+        assert_eq!(json["groups"][0]["rules"], serde_json::json!([]));
+        assert!(rendered.ends_with("}\n"));
+        // Prometheus reads it as YAML, which it still is.
+        let _: PrometheusRulesConfig =
+            serde_yaml::from_str(&rendered).expect("output isn't valid YAML");
+    }
+
+    #[test]
+    fn test_render_generated_config_embed_params() {
+        let config = generate_config(vec![], &Opts::default()).expect("failed to generate config");
+        let opts = Opts {
+            embed_params: true,
+            playbook_link: Some("https://example.com/playbook\nmore".into()),
+            ..Default::default()
+        };
+        let rendered = render_generated_config(&config, &opts).expect("failed to render config");
+        assert!(rendered.contains("#   playbook link: https://example.com/playbook more\n"));
+        assert!(rendered.contains("#   severity: low_urgency_page\n"));
+        assert!(rendered.contains("#   min for: 1h\n"));
+        assert!(rendered.contains("#   groups: 1\n"));
+        assert!(rendered.contains("#   rules: 0\n"));
+        let opts = Opts {
+            embed_params: true,
+            severity: Some("page".into()),
+            min_for: Some(prometheus_parser::PromDuration::Minutes(30)),
+            ..Default::default()
+        };
+        let rendered = render_generated_config(&config, &opts).expect("failed to render config");
+        assert!(rendered.contains("#   severity: page\n"));
+        assert!(rendered.contains("#   min for: 30m\n"));
+        let opts = Opts {
+            embed_params: true,
+            no_default_labels: true,
+            ..Default::default()
+        };
+        let rendered = render_generated_config(&config, &opts).expect("failed to render config");
+        assert!(rendered.contains("#   severity: none\n"));
+        // The parameters are only comments so it's still the same config.
+        let round_tripped: Value = serde_yaml::from_str(&rendered).expect("invalid YAML");
+        assert_eq!(round_tripped, serde_yaml::to_value(&config).unwrap());
+        let without_params =
+            render_generated_config(&config, &Opts::default()).expect("failed to render config");
+        assert!(!without_params.contains("Generated with"));
+    }
+
+    #[test]
+    fn test_build_promtool_tests() {
+        let config = PrometheusRulesConfig {
+            groups: vec![PrometheusRuleGroup {
+                name: "absent_label_alerts".into(),
+                rules: vec![PrometheusAbsentSelectorAlertRule {
+                    expr: r#"absent(some_metric{job="x",mode!="idle"})"#.into(),
+                    r#for: prometheus_parser::PromDuration::Hours(1),
+                    name: "absent_some_metric".into(),
+                    selector_expr: r#"some_metric{job="x",mode!="idle"}"#.into(),
+                    labels: btree_map! {
+                        "severity" => "low_urgency_page"
+                    },
+                    extra_annotations: BTreeMap::new(),
+                    metric_help: None,
+                    summary_template: None,
+                    description_template: None,
+                    origin_rules: vec![],
+                }
+                .into()],
+                limit: None,
+                labels: BTreeMap::new(),
+                query_offset: None,
+                extra_fields: BTreeMap::new(),
+            }],
+        };
+        let tests = build_promtool_tests("absent.rules.yml".into(), &config);
+        assert_eq!(tests.rule_files, vec![PathBuf::from("absent.rules.yml")]);
+        assert_eq!(tests.tests.len(), 1);
+        let alert_rule_test = &tests.tests[0].alert_rule_test[0];
+        assert_eq!(alert_rule_test.alertname, "absent_some_metric");
+        assert_eq!(alert_rule_test.eval_time, "61m");
+        let exp_labels: BTreeMap<String, String> = btree_map! {
+            "job" => "x",
+            "severity" => "low_urgency_page"
+        };
+        assert_eq!(alert_rule_test.exp_alerts[0].exp_labels, exp_labels);
+        assert_eq!(alert_rule_test.exp_alerts[0].exp_annotations.len(), 2);
+    }
+
+    #[test]
+    fn test_build_promtool_tests_combined_variants() {
+        let selector = |value: &str| SelectorWithOriginRule {
+            selector: prometheus_parser::Selector {
+                metric: Some("some_metric".into()),
+                labels: vec![prometheus_parser::Label {
+                    op: prometheus_parser::LabelOp::Equal,
+                    key: "job".into(),
+                    value: value.into(),
+                    span: None,
+                }],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let combined_rule = merge_selectors_into_rule(
+            &[selector("x"), selector("y")],
+            &Opts {
+                combine_variants: true,
+                ..Default::default()
+            },
+            None,
+        );
+        // Nothing to say which series this returns, if any.
+        let wrapped_rule = PrometheusRule {
+            expr: "count(absent(other_metric)) > 0".into(),
+            ..combined_rule.clone()
+        };
+        let config = PrometheusRulesConfig {
+            groups: vec![PrometheusRuleGroup {
+                name: "absent_label_alerts".into(),
+                rules: vec![combined_rule, wrapped_rule],
+                limit: None,
+                labels: BTreeMap::new(),
+                query_offset: None,
+                extra_fields: BTreeMap::new(),
+            }],
+        };
+        let tests = build_promtool_tests("absent.rules.yml".into(), &config);
+        assert_eq!(tests.tests.len(), 1);
+        // Each variant fires an alert of its own.
+        let exp_labels: Vec<&BTreeMap<String, String>> = tests.tests[0].alert_rule_test[0]
+            .exp_alerts
             .iter()
-            .map(|it| it.selector.to_string())
-            .sorted()
+            .map(|alert| &alert.exp_labels)
             .collect();
-        let mut expected_selectors = vec![
-            r#"node_load1{box_type="data-warehouse"}"#,
-            "a_recording:cpu",
-            r#"node_cpu{mode!="idle"}[1m]"#,
-        ];
-        expected_selectors.sort_unstable();
-        assert_eq!(actual_selectors, expected_selectors);
+        let variant_labels = |job: &str| -> BTreeMap<String, String> {
+            btree_map! {
+                "job" => job,
+                "severity" => "low_urgency_page"
+            }
+        };
+        assert_eq!(exp_labels, vec![&variant_labels("x"), &variant_labels("y")]);
     }
 
     #[test]
-    fn test_get_selectors_from_expression() {
-        let expr_and_expected = vec![
-            (
-                "stack:public_http_errors_5xx_non_L3:rate1m_sum",
-                vec!["stack:public_http_errors_5xx_non_L3:rate1m_sum"],
-            ),
-            (
-                r#"publicapi_http_errors_5xx_count{is_load_shedding!="true",slo="L1"}[30s]"#,
-                vec![r#"publicapi_http_errors_5xx_count{is_load_shedding!="true",slo="L1"}[30s]"#],
-            ),
-            ("(month() > bool 9) + (month() < bool 4)", vec![]),
-            (
-                r#"count(max by(stack_id) (up{job="rabbitmq"} == 1))"#,
-                vec![r#"up{job="rabbitmq"}"#],
-            ),
-            (
-                r#"up{job="aws_rds"} == 1 unless aws_rds_free_storage_space_minimum{dbinstance_identifier=~"live-db-.\\d"}"#,
-                vec![
-                    r#"up{job="aws_rds"}"#,
-                    r#"aws_rds_free_storage_space_minimum{dbinstance_identifier=~"live-db-.\\d"}"#,
-                ],
-            ),
-            (
-                r#"sum(irate(publicapi_http_request_count[30s])) by (stack_id, slo, route, method) and on(stack_id) slb_live_stack_number{slb="prod"} == 1"#,
-                vec![
-                    "publicapi_http_request_count[30s]",
-                    r#"slb_live_stack_number{slb="prod"}"#,
-                ],
-            ),
-        ];
-        for (expr, expected_selectors) in expr_and_expected {
-            let parsed = prometheus_parser::parse_expr(expr).expect("failed to parse expression");
-            let selectors: Vec<String> = get_selectors_from_expression(&parsed)
-                .iter()
-                .map(|s| s.to_string())
-                .collect();
-            assert_eq!(selectors, expected_selectors);
-        }
+    fn test_diagnose_setup_ignore_file_url_and_stdin() {
+        let problems = |ignore_file: &str| {
+            let opts = Opts {
+                ignore_file: ignore_file.into(),
+                ignore_file_timeout: Some(1),
+                ..Default::default()
+            };
+            diagnose_setup(&opts, &[])
+        };
+        let missing_file = problems("does/not/exist.txt");
+        // Stdin is skipped rather than read, like a missing file it's not a
+        // problem.
+        assert_eq!(problems("-"), missing_file);
+        // An ignore file URL that can't be fetched stops the run so it is.
+        assert_eq!(problems("http://127.0.0.1:9/ignore.txt"), missing_file + 1);
+    }
+
+    #[test]
+    fn test_load_ignore_file_url_falls_back_to_cache() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let cache = dir.path().join("ignore_metrics.txt");
+        let opts = Opts {
+            // Nothing should be listening on the discard port.
+            ignore_file: "http://127.0.0.1:9/ignore_metrics.txt".into(),
+            ignore_file_timeout: Some(1),
+            ignore_file_cache: Some(cache.clone()),
+            ..Default::default()
+        };
+        assert!(load_ignore_file(&opts).is_err());
+        fs::write(&cache, "some_metric\n").expect("failed to write cache");
+        assert_eq!(
+            load_ignore_file(&opts).expect("failed to load ignore file"),
+            vec!["some_metric"]
+        );
+    }
+
+    #[test]
+    fn test_load_ignore_file_skips_blank_lines_and_comments() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let ignore_file = dir.path().join("ignore_metrics.txt");
+        fs::write(
+            &ignore_file,
+            "# a comment\nfoo\n\n   \n  bar  \n// not a comment by default\n",
+        )
+        .expect("failed to write ignore file");
+        let opts = Opts {
+            ignore_file: ignore_file.clone(),
+            ..Default::default()
+        };
+        assert_eq!(
+            load_ignore_file(&opts).expect("failed to load ignore file"),
+            vec!["foo", "bar", "// not a comment by default"]
+        );
+    }
+
+    #[test]
+    fn test_load_ignore_file_alternate_comment_prefix() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let ignore_file = dir.path().join("ignore_metrics.txt");
+        fs::write(&ignore_file, "// a comment\nfoo\n; not this one\n")
+            .expect("failed to write ignore file");
+        let opts = Opts {
+            ignore_file,
+            ignore_comment_prefix: Some("//".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            load_ignore_file(&opts).expect("failed to load ignore file"),
+            vec!["foo", "; not this one"]
+        );
     }
 
     #[test]
-    fn test_build_absent_selector_alert_name() {
-        let expr_and_expected = vec![
-            ("stile_log_messages_logged_count{level=~\"error|fatal\",client_sent!=\"true\"}[15m]", "absent_stile_log_messages_logged_count_level_regexequal_error_fatal_client_sent_notequal_true_15m"),
-            ("stack:error_log:rate15m_sum", "absent_stack:error_log:rate15m_sum"),
-            ("publicapi_http_errors_5xx_count{is_load_shedding!=\"true\",is_internal_admin=\"false\",slo!=\"L3\"}[1m]", "absent_publicapi_http_errors_5xx_count_is_load_shedding_notequal_true_is_internal_admin_equal_false_slo_notequal_L3_1m"),
-            ("publicapi_http_response_time_bucket[1m]", "absent_publicapi_http_response_time_bucket_1m"),
-            (r#"aws_elasticache_evictions_maximum{cache_cluster_id=~"prod-redis-shard-.*"}"#, "absent_aws_elasticache_evictions_maximum_cache_cluster_id_regexequal_prod_redis_shard___")
-        ];
-        for (expr, expected_name) in expr_and_expected {
-            let selector = if let prometheus_parser::Expression::Selector(s) =
-                prometheus_parser::parse_expr(expr).expect("failed to parse expression")
-            {
-                s
-            } else {
-                panic!("Expressions must be a selector");
+    fn test_parse_ignore_entries() {
+        let entries = parse_ignore_entries(&[
+            "foo".to_string(),
+            "regex: node_.*_bytes".to_string(),
+            "metric:node_load1".to_string(),
+        ])
+        .expect("failed to parse ignore entries");
+        let ignored = |metric: &str, job: Option<&str>| {
+            let selector = prometheus_parser::Selector {
+                metric: Some(metric.into()),
+                ..Default::default()
             };
-            let name = build_absent_selector_alert_name(&selector);
-            assert_eq!(name, expected_name);
-        }
+            let selector = SelectorWithOriginRule {
+                selector: match job {
+                    Some(job) => with_equal_matcher(&selector, "job", job),
+                    None => selector,
+                },
+                ..Default::default()
+            };
+            entries.iter().any(|entry| entry.matches(&selector))
+        };
+        assert!(ignored("foo", None));
+        assert!(!ignored("foo", Some("x")));
+        assert!(ignored("node_memory_bytes", None));
+        assert!(!ignored("node_memory_bytes", Some("x")));
+        assert!(!ignored("some_node_memory_bytes_total", None));
+        assert!(ignored("node_load1", None));
+        assert!(ignored("node_load1", Some("x")));
+        assert!(!ignored("node_load15", None));
+        let error = parse_ignore_entries(&["regex:node_(".to_string()])
+            .expect_err("invalid regex should fail");
+        assert!(format!("{:#}", error).contains("'regex:node_('"));
     }
 
     #[test]
-    fn test_merge_selectors_into_rule() {
-        let selectors = vec![
-            SelectorWithOriginRule {
-                selector: prometheus_parser::Selector {
-                    metric: Some("some_metric".into()),
-                    ..Default::default()
-                },
-                rule: PrometheusRule {
-                    expr: "some_metric".into(),
-                    untyped_fields: btree_map! {
-                        "for" => "1h"
-                    },
-                },
-            },
-            SelectorWithOriginRule {
-                selector: prometheus_parser::Selector {
-                    metric: Some("some_metric".into()),
-                    ..Default::default()
-                },
-                rule: PrometheusRule {
-                    expr: "some_metric".into(),
-                    untyped_fields: btree_map! {
-                        "for" => "5h"
-                    },
-                },
-            },
+    fn test_transform_config() {
+        let config = generate_config(vec![], &Opts::default()).expect("failed to generate config");
+        let transformed =
+            transform_config(&config, Path::new("cat")).expect("failed to transform config");
+        assert_eq!(
+            serde_yaml::to_value(&transformed).unwrap(),
+            serde_yaml::to_value(&config).unwrap()
+        );
+        // Fails if the script does.
+        assert!(transform_config(&config, Path::new("false")).is_err());
+        // Fails if what it outputs aren't rules.
+        assert!(transform_config(&config, Path::new("true")).is_err());
+    }
+
+    #[test]
+    fn test_format_stage_timings() {
+        let timings = vec![
+            ("parsing rules", Duration::from_millis(1500)),
+            ("writing output", Duration::from_micros(250)),
         ];
-        let expected_rule: PrometheusRule = PrometheusAbsentSelectorAlertRule {
-            name: "absent_some_metric".into(),
-            expr: "absent(some_metric)".into(),
-            selector_expr: "some_metric".into(),
-            r#for: prometheus_parser::PromDuration::Hours(1),
-            labels: btree_map! {
-                "severity" => "low_urgency_page"
-            },
-        }
-        .into();
-        let actual_rule = merge_selectors_into_rule(&selectors, None);
-        assert_eq!(actual_rule, expected_rule);
+        assert_eq!(
+            format_stage_timings(&timings),
+            "parsing rules          1500.000ms\nwriting output            0.250ms\ntotal                  1500.250ms"
+        );
     }
 
     #[test]
-    fn test_merge_selectors_into_rule_min_1h() {
-        let playbook_link = "test".to_string();
-        let selectors = vec![
-            SelectorWithOriginRule {
-                selector: prometheus_parser::Selector {
-                    metric: Some("some_metric".into()),
-                    ..Default::default()
-                },
-                rule: PrometheusRule {
-                    expr: "some_metric".into(),
-                    untyped_fields: btree_map! {
-                        "for" => "1m"
-                    },
-                },
-            },
+    fn test_split_config_by_origin() {
+        let selector = |metric: &str, label: Option<(&str, &str)>, origin| {
+            let labels = label
+                .map(|(key, value)| prometheus_parser::Label {
+                    op: prometheus_parser::LabelOp::Equal,
+                    key: key.to_string(),
+                    value: value.to_string(),
+                    span: None,
+                })
+                .into_iter()
+                .collect();
             SelectorWithOriginRule {
                 selector: prometheus_parser::Selector {
-                    metric: Some("some_metric".into()),
+                    metric: Some(metric.to_string()),
+                    labels,
                     ..Default::default()
                 },
-                rule: PrometheusRule {
-                    expr: "some_metric".into(),
-                    untyped_fields: btree_map! {
-                        "for" => "30s"
-                    },
-                },
-            },
-        ];
-        let expected_rule: PrometheusRule = PrometheusAbsentSelectorAlertRule {
-            name: "absent_some_metric".into(),
-            expr: "absent(some_metric)".into(),
-            selector_expr: "some_metric".into(),
-            r#for: prometheus_parser::PromDuration::Hours(1),
-            labels: btree_map! {
-                "severity" => "low_urgency_page",
-                "playbook" => "test"
-            },
-        }
-        .into();
-        let actual_rule = merge_selectors_into_rule(&selectors, Some(playbook_link));
-        assert_eq!(actual_rule, expected_rule);
+                origin,
+                ..Default::default()
+            }
+        };
+        let rules = vec![
+            vec![selector(
+                "job:requests:rate5m",
+                None,
+                SelectorOrigin::Record,
+            )],
+            // Used in an expression but with the same name as a recording.
+            vec![selector(
+                "job:requests:rate5m",
+                Some(("job", "x")),
+                SelectorOrigin::Expr,
+            )],
+            vec![selector("requests_total", None, SelectorOrigin::Expr)],
+            // Recorded and used in an expression, which merge into one rule.
+            vec![
+                selector("job:errors:rate5m", None, SelectorOrigin::Expr),
+                selector("job:errors:rate5m", None, SelectorOrigin::Record),
+            ],
+        ]
+        .iter()
+        .map(|selectors| merge_selectors_into_rule(selectors, &Opts::default(), None))
+        .collect();
+        let config = PrometheusRulesConfig {
+            groups: vec![PrometheusRuleGroup {
+                name: "absent_label_alerts".into(),
+                rules,
+                limit: None,
+                labels: BTreeMap::new(),
+                query_offset: None,
+                extra_fields: BTreeMap::new(),
+            }],
+        };
+        let (records_config, exprs_config) = split_config_by_origin(config);
+        let exprs = |config: &PrometheusRulesConfig| -> Vec<String> {
+            config.groups[0]
+                .rules
+                .iter()
+                .map(|rule| rule.expr.clone())
+                .collect()
+        };
+        assert_eq!(
+            exprs(&records_config),
+            vec!["absent(job:requests:rate5m)", "absent(job:errors:rate5m)"]
+        );
+        assert_eq!(
+            exprs(&exprs_config),
+            vec![
+                r#"absent(job:requests:rate5m{job="x"})"#,
+                "absent(requests_total)"
+            ]
+        );
     }
 
     #[test]
-    fn test_prometheus_rule_from_prometheus_absent_selector_alert_rule() {
-        let rule: PrometheusRule = PrometheusAbsentSelectorAlertRule {
-            expr: "absent(some_expr)".into(),
-            r#for: prometheus_parser::PromDuration::Hours(1),
-            name: "this_thing".into(),
-            selector_expr: "some_expr".into(),
-            labels: btree_map! {
-                "severity" => "low_urgency_page"
+    fn test_expand_selectors_by_label() {
+        let selectors = vec![SelectorWithOriginRule {
+            selector: prometheus_parser::Selector {
+                metric: Some("some_metric".into()),
+                ..Default::default()
             },
-        }
-        .into();
-        let annotations: BTreeMap<String, String> = btree_map! {
-            "description" => "No data for 'some_expr'. This alert rule was generated by prometheus-absent-data-alert-rule-generator.",
-            "summary" => "No data for 'some_expr'"
+            ..Default::default()
+        }];
+        assert_eq!(
+            with_equal_matcher(&selectors[0].selector, "instance", "a:9100").to_string(),
+            r#"some_metric{instance="a:9100"}"#
+        );
+        // Nothing should be listening on the discard port, so it can't expand
+        // anything.
+        let grouped_selectors = vec![("some_metric".to_string(), selectors)];
+        let expanded = expand_selectors_by_label(
+            grouped_selectors,
+            "http://127.0.0.1:9",
+            "instance",
+            &Opts::default(),
+        );
+        assert_eq!(expanded.len(), 1);
+        assert_eq!(expanded[0].1[0].selector.to_string(), "some_metric");
+    }
+
+    /// Serve `respond(request_line)` as JSON to every request on a local port
+    /// and return the URL, to stand in for a Prometheus.
+    fn fake_prometheus(respond: fn(&str) -> String) -> String {
+        use std::io::{BufRead, BufReader, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+        let url = format!("http://{}", listener.local_addr().expect("no address"));
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().filter_map(|stream| stream.ok()) {
+                let mut request_line = String::new();
+                BufReader::new(&stream)
+                    .read_line(&mut request_line)
+                    .expect("failed to read request");
+                let body = respond(&request_line);
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .expect("failed to respond");
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn test_expand_by_skips_currently_absent() {
+        // `--expand-by` needs `--prometheus-url`, which also checks each metric
+        // for data, so rules for metrics with none right now are skipped unless
+        // `--tag-currently-absent` is passed too.
+        let prometheus_url = fake_prometheus(|request_line| {
+            if request_line.contains("/api/v1/label/instance/values") {
+                r#"{"status":"success","data":["a:9100","b:9100"]}"#.to_string()
+            } else if request_line.contains("count%28bar%29") {
+                r#"{"status":"success","data":{"result":[{"value":[0,"2"]}]}}"#.to_string()
+            } else {
+                r#"{"status":"success","data":{"result":[]}}"#.to_string()
+            }
+        });
+        let selectors = || {
+            vec!["foo", "bar"]
+                .into_iter()
+                .map(|metric| SelectorWithOriginRule {
+                    selector: prometheus_parser::Selector::new().metric(metric),
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>()
         };
-        let labels: BTreeMap<String, String> = btree_map! {
-            "severity" => "low_urgency_page"
+        let rules = |opts: &Opts| -> Vec<(String, bool)> {
+            let config = generate_config(selectors(), opts).expect("failed to generate config");
+            config.groups[0]
+                .rules
+                .iter()
+                .map(|rule| {
+                    let tagged = rule
+                        .untyped_fields
+                        .get("labels")
+                        .and_then(|labels| labels.get("currently_absent"))
+                        .is_some();
+                    (rule.expr.clone(), tagged)
+                })
+                .collect()
         };
-        let expected_rule = PrometheusRule {
-            expr: "absent(some_expr)".into(),
-            untyped_fields: btree_map! {
-                "for" => "1h",
-                "alert" => "this_thing",
-                "annotations" => btree_to_yaml_mapping(annotations),
-                "labels" => btree_to_yaml_mapping(labels)
-            },
+        let opts = Opts {
+            expand_by: Some("instance".into()),
+            prometheus_url: Some(prometheus_url),
+            ..Default::default()
         };
-        assert_eq!(rule, expected_rule);
+        assert_eq!(
+            rules(&opts),
+            vec![
+                ("absent(bar{instance=\"a:9100\"})".to_string(), false),
+                ("absent(bar{instance=\"b:9100\"})".to_string(), false),
+            ]
+        );
+        let opts = Opts {
+            tag_currently_absent: true,
+            ..opts
+        };
+        assert_eq!(
+            rules(&opts),
+            vec![
+                ("absent(bar{instance=\"a:9100\"})".to_string(), false),
+                ("absent(bar{instance=\"b:9100\"})".to_string(), false),
+                ("absent(foo{instance=\"a:9100\"})".to_string(), true),
+                ("absent(foo{instance=\"b:9100\"})".to_string(), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_order_yaml_mapping() {
+        let labels: BTreeMap<String, String> = btree_map! {
+            "alpha" => "1",
+            "severity" => "low_urgency_page",
+            "team" => "payments",
+            "beta" => "2"
+        };
+        let ordered = order_yaml_mapping(
+            &btree_to_yaml_mapping(labels),
+            &["severity".to_string(), "team".to_string()],
+        );
+        let keys: Vec<&str> = ordered.iter().filter_map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, vec!["severity", "team", "alpha", "beta"]);
     }
 
     #[test]
     fn generates_no_files_on_dry_run() {
         let manifest_dir = env!("CARGO_MANIFEST_DIR");
         let output_file = temp_file().expect("failed to get temp file");
-        process_rules_dir(
-            &format!("{}/alerts", manifest_dir),
-            &output_file,
-            &"".to_string(),
-            None,
-            true,
-        )
+        process_rules_dir(&Opts {
+            rules_dirs: vec![format!("{}/alerts", manifest_dir).into()],
+            output_file: output_file.clone().into(),
+            dry_run: true,
+            ..Default::default()
+        })
         .expect("failed to process alerts");
         let generated_files =
             glob::glob(&format!("{}/*", output_file)).expect("failed to glob temp dir");
         assert_eq!(generated_files.count(), 0);
     }
 
+    #[test]
+    fn times_the_stages_that_ran() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let mut stage_timings = vec![];
+        // A dry run finishes early, before anything is written.
+        process_rules_dir_stages(
+            &Opts {
+                rules_dirs: vec![dir.path().into()],
+                output_file: dir.path().join("absent.rules.yml"),
+                dry_run: true,
+                ..Default::default()
+            },
+            &mut stage_timings,
+        )
+        .expect("failed to process rules");
+        let stages: Vec<&str> = stage_timings.iter().map(|(stage, _)| *stage).collect();
+        assert_eq!(
+            stages,
+            vec!["finding rules files", "parsing rules", "generating rules"]
+        );
+    }
+
     #[test]
     fn generates_valid_rules_file() {
         let manifest_dir = env!("CARGO_MANIFEST_DIR");
         let output_file = temp_file().expect("failed to get temp file");
-        process_rules_dir(
-            format!("{}/alerts", manifest_dir),
-            output_file.clone(),
-            "".to_string(),
-            None,
-            false,
-        )
-            .expect("failed to process alerts");
+        process_rules_dir(&Opts {
+            rules_dirs: vec![format!("{}/alerts", manifest_dir).into()],
+            output_file: output_file.clone().into(),
+            ..Default::default()
+        })
+        .expect("failed to process alerts");
+        cmd!("promtool check rules {output_file}")
+            .run()
+            .expect("promtool check failed");
+    }
+
+    #[test]
+    fn test_is_generated_output_path() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let output_file = dir.path().join("absent.rules.yml");
+        fs::write(&output_file, "groups: []\n").expect("failed to write output file");
+        let rules_file = dir.path().join("some.rules.yml");
+        fs::write(&rules_file, "groups: []\n").expect("failed to write rules file");
+        let records_output_file = dir.path().join("absent_records.rules.yml");
+        fs::write(&records_output_file, "groups: []\n").expect("failed to write output file");
+        let exprs_output_file = dir.path().join("absent_exprs.rules.yml");
+        fs::write(&exprs_output_file, "groups: []\n").expect("failed to write output file");
+        let opts = Opts {
+            output_file: output_file.clone(),
+            records_output_file: Some(records_output_file.clone()),
+            exprs_output_file: Some(exprs_output_file.clone()),
+            ..Default::default()
+        };
+        assert_eq!(is_generated_output_path(&output_file, &opts), (true, false));
+        assert_eq!(
+            is_generated_output_path(&records_output_file, &opts),
+            (true, false)
+        );
+        assert_eq!(
+            is_generated_output_path(&exprs_output_file, &opts),
+            (true, false)
+        );
+        assert_eq!(is_generated_output_path(&rules_file, &opts), (false, false));
+        // Reading rules from stdin is fine even when the output file exists.
+        assert_eq!(
+            is_generated_output_path(Path::new("-"), &opts),
+            (false, false)
+        );
+    }
+
+    #[test]
+    fn test_split_rules_dirs_from_paths() {
+        let fixtures_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+        assert_eq!(
+            split_rules_dirs_from_paths(vec![fixtures_dir.to_string()]),
+            (vec![fixtures_dir.into()], vec![])
+        );
+        let fixture = format!("{}/test_disabled_rules.rules.yml", fixtures_dir);
+        assert_eq!(
+            split_rules_dirs_from_paths(vec![fixture.clone()]),
+            (vec![], vec![fixture.clone()])
+        );
+        let glob = format!("{}/*.rules.yml", fixtures_dir);
+        assert_eq!(
+            split_rules_dirs_from_paths(vec![glob.clone()]),
+            (vec![], vec![glob.clone()])
+        );
+        assert_eq!(
+            split_rules_dirs_from_paths(vec![glob.clone(), fixture.clone()]),
+            (vec![], vec![glob, fixture.clone()])
+        );
+        let other_dir = tempfile::tempdir().expect("failed to create temp dir");
+        let other_dir = other_dir.path().to_str().unwrap();
+        assert_eq!(
+            split_rules_dirs_from_paths(vec![
+                fixtures_dir.to_string(),
+                other_dir.to_string(),
+                fixture.clone()
+            ]),
+            (vec![fixtures_dir.into(), other_dir.into()], vec![fixture])
+        );
+        assert_eq!(
+            split_rules_dirs_from_paths(vec!["-".to_string()]),
+            (vec![], vec!["-".to_string()])
+        );
+    }
+
+    #[test]
+    fn generates_valid_rules_file_from_paths() {
+        let fixtures_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+        let output_file = temp_file().expect("failed to get temp file");
+        process_rules_dir(&Opts {
+            rule_paths: vec![
+                format!("{}/test_guard_*.rules.yml", fixtures_dir),
+                format!("{}/test_disabled_rules.rules.yml", fixtures_dir),
+            ],
+            output_file: output_file.clone().into(),
+            ..Default::default()
+        })
+        .expect("failed to process fixtures");
         cmd!("promtool check rules {output_file}")
             .run()
             .expect("promtool check failed");
     }
 
+    #[test]
+    fn reads_rules_files_matching_glob() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        fs::copy(
+            concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/fixtures/test_guard_metrics.rules.yml"
+            ),
+            dir.path().join("guard_metrics.yaml"),
+        )
+        .expect("failed to copy fixture");
+        let output_file = dir.path().join("absent.yaml");
+        let opts = Opts {
+            rules_dirs: vec![dir.path().into()],
+            output_file: output_file.clone(),
+            glob: Some("**/*.yaml".into()),
+            ..Default::default()
+        };
+        process_rules_dir(&opts).expect("failed to process rules");
+        let generated = fs::read_to_string(&output_file).expect("failed to read output");
+        assert!(generated.contains("absent_some_metric"));
+        // The output file is matched by the glob now but shouldn't be read as
+        // a rules file the second time around.
+        process_rules_dir(&opts).expect("failed to process rules again");
+        assert_eq!(
+            fs::read_to_string(&output_file).expect("failed to read output"),
+            generated
+        );
+    }
+
+    #[test]
+    fn stdout_generates_no_files() {
+        let fixtures_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+        let output_file = temp_file().expect("failed to get temp file");
+        process_rules_dir(&Opts {
+            rule_paths: vec![format!("{}/test_guard_metrics.rules.yml", fixtures_dir)],
+            output_file: output_file.clone().into(),
+            stdout: true,
+            ..Default::default()
+        })
+        .expect("failed to print fixtures");
+        assert!(!Path::new(&output_file).exists());
+    }
+
+    #[test]
+    fn validate_only_generates_no_files() {
+        let fixtures_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+        let output_file = temp_file().expect("failed to get temp file");
+        process_rules_dir(&Opts {
+            rule_paths: vec![format!("{}/test_guard_metrics.rules.yml", fixtures_dir)],
+            output_file: output_file.clone().into(),
+            validate_only: true,
+            ..Default::default()
+        })
+        .expect("failed to validate fixtures");
+        assert!(!Path::new(&output_file).exists());
+    }
+
+    #[test]
+    fn check_compares_against_baseline() {
+        let fixtures_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+        let output_file = temp_file().expect("failed to get temp file");
+        let baseline = temp_file().expect("failed to get temp file");
+        let opts = Opts {
+            rule_paths: vec![format!("{}/test_guard_metrics.rules.yml", fixtures_dir)],
+            output_file: output_file.clone().into(),
+            write_baseline: Some(baseline.clone().into()),
+            ..Default::default()
+        };
+        process_rules_dir(&opts).expect("failed to write baseline");
+        assert!(!Path::new(&output_file).exists());
+        let opts = Opts {
+            write_baseline: None,
+            check: true,
+            baseline: Some(baseline.clone().into()),
+            ..opts
+        };
+        process_rules_dir(&opts).expect("generated rules should match the baseline");
+        fs::write(&baseline, "groups: []\n").expect("failed to overwrite baseline");
+        assert!(process_rules_dir(&opts).is_err());
+        assert!(!Path::new(&output_file).exists());
+    }
+
+    #[test]
+    fn check_compares_every_output_file() {
+        let fixtures_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+        let records_output_file = temp_file().expect("failed to get temp file");
+        let exprs_output_file = temp_file().expect("failed to get temp file");
+        let opts = Opts {
+            rule_paths: vec![format!(
+                "{}/test_labeled_recording_rule.rules.yml",
+                fixtures_dir
+            )],
+            output_file: exprs_output_file.clone().into(),
+            records_output_file: Some(records_output_file.clone().into()),
+            exprs_output_file: Some(exprs_output_file.clone().into()),
+            ..Default::default()
+        };
+        process_rules_dir(&opts).expect("failed to generate rules");
+        let opts = Opts {
+            check: true,
+            ..opts
+        };
+        process_rules_dir(&opts).expect("generated rules should match the output files");
+        fs::write(&records_output_file, "groups: []\n").expect("failed to overwrite output file");
+        let error = process_rules_dir(&opts).expect_err("records output file should be stale");
+        assert!(error.to_string().contains(&records_output_file));
+        assert!(!error.to_string().contains(&exprs_output_file));
+    }
+
+    #[test]
+    fn diff_compares_against_output_file() {
+        let fixtures_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+        let output_file = temp_file().expect("failed to get temp file");
+        let opts = Opts {
+            rule_paths: vec![format!("{}/test_guard_metrics.rules.yml", fixtures_dir)],
+            output_file: output_file.clone().into(),
+            ..Default::default()
+        };
+        process_rules_dir(&opts).expect("failed to generate rules");
+        let opts = Opts { diff: true, ..opts };
+        process_rules_dir(&opts).expect("generated rules should match the output file");
+        fs::write(&output_file, "groups: []\n").expect("failed to overwrite output file");
+        assert!(process_rules_dir(&opts).is_err());
+        assert_eq!(
+            fs::read_to_string(&output_file).expect("failed to read output file"),
+            "groups: []\n"
+        );
+    }
+
+    #[test]
+    fn plan_json_describes_the_run() {
+        let fixture = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/test_guard_metrics.rules.yml"
+        );
+        let output_file = temp_file().expect("failed to get temp file");
+        fs::write(
+            &output_file,
+            r#"groups:
+- name: absent_metrics
+  rules:
+  - alert: absent_some_metric
+    expr: absent(some_metric)
+  - alert: absent_stale_metric
+    expr: absent(stale_metric)
+"#,
+        )
+        .expect("failed to write existing output");
+        let ignore_file = temp_file().expect("failed to get temp file");
+        fs::write(&ignore_file, "other_metric\n").expect("failed to write ignore file");
+        let plan_json = temp_file().expect("failed to get temp file");
+        process_rules_dir(&Opts {
+            rule_paths: vec![fixture.to_string()],
+            output_file: output_file.clone().into(),
+            ignore_file: ignore_file.into(),
+            plan_json: Some(plan_json.clone().into()),
+            dry_run: true,
+            ..Default::default()
+        })
+        .expect("failed to plan");
+        let plan: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&plan_json).expect("failed to read plan"))
+                .expect("plan isn't JSON");
+        assert_eq!(plan["files_scanned"], serde_json::json!([fixture]));
+        assert_eq!(plan["selectors_found"], 3);
+        assert_eq!(
+            plan["ignored_selectors"],
+            serde_json::json!(["other_metric"])
+        );
+        let output = &plan["outputs"][0];
+        assert_eq!(output["file"], serde_json::json!(output_file));
+        assert_eq!(
+            output["rules_to_create"],
+            serde_json::json!(["absent_maintenance_mode"])
+        );
+        assert_eq!(
+            output["rules_to_remove"],
+            serde_json::json!(["absent_stale_metric"])
+        );
+        assert_eq!(output["rules"].as_array().map(Vec::len), Some(2));
+        assert!(fs::read_to_string(&output_file)
+            .expect("failed to read output")
+            .contains("absent_stale_metric"));
+    }
+
+    #[test]
+    fn require_metric_fails_on_nameless_selectors() {
+        let fixture = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/test_no_metric.rules.yml"
+        );
+        let selectors = get_selectors_in_file(fixture, &Opts::default())
+            .expect("failed to get selectors from file");
+        let nameless: Vec<String> = find_nameless_selectors(&selectors)
+            .iter()
+            .map(|s| s.selector.to_string())
+            .collect();
+        assert_eq!(nameless, vec![r#"{job="x"}"#]);
+        let output_file = temp_file().expect("failed to get temp file");
+        let opts = Opts {
+            rule_paths: vec![fixture.to_string()],
+            output_file: output_file.clone().into(),
+            dry_run: true,
+            require_metric: true,
+            ..Default::default()
+        };
+        assert!(process_rules_dir(&opts).is_err());
+        assert!(!Path::new(&output_file).exists());
+    }
 
     #[test]
     fn outputs_rules_in_the_same_order() {
         let fixtures_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/alerts");
         let output_file = temp_file().expect("failed to get temp file");
-        process_rules_dir(fixtures_dir, &output_file, "", None, false)
-            .expect("failed to process fixtures");
+        process_rules_dir(&Opts {
+            rules_dirs: vec![fixtures_dir.into()],
+            output_file: output_file.clone().into(),
+            ..Default::default()
+        })
+        .expect("failed to process fixtures");
         let second_output_file = temp_file().expect("failed to get temp file");
-        process_rules_dir(fixtures_dir, &second_output_file, "", None, false)
-            .expect("failed to process fixtures");
+        process_rules_dir(&Opts {
+            rules_dirs: vec![fixtures_dir.into()],
+            output_file: second_output_file.clone().into(),
+            ..Default::default()
+        })
+        .expect("failed to process fixtures");
         let output_file_contents =
             fs::read_to_string(output_file).expect("failed to read output file");
         let second_output_file_contents =